@@ -0,0 +1,30 @@
+create type JobQueue as enum (
+  'PRODUCT_QUERY',
+  'DOMAIN_QUALIFIER',
+  'FOUNDER_QUERY',
+  'EMAIL_VERIFIER',
+  'PERSISTANT_DATA'
+);
+
+create type JobStatus as enum (
+  'PENDING',
+  'RUNNING',
+  'DEAD'
+);
+
+create table job (
+  id bigint primary key generated always as identity,
+  queue JobQueue not null,
+  payload jsonb not null,
+  status JobStatus not null default 'PENDING',
+  attempts int not null default 0,
+  max_attempts int not null,
+  run_after timestamptz not null default now(),
+  locked_until timestamptz,
+  last_error text,
+
+	created_at timestamptz not null default now(),
+	updated_at timestamptz not null default now()
+);
+
+create index idx_job_queue_status_run_after on job (queue, status, run_after);
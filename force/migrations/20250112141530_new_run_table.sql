@@ -0,0 +1,8 @@
+create table run (
+  id uuid primary key,
+  niche text not null,
+
+	created_at timestamptz not null default now()
+);
+
+alter table email add column run_id uuid references run(id);
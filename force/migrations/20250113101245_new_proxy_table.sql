@@ -0,0 +1,13 @@
+create table proxy (
+  id bigint primary key generated always as identity,
+  url text not null unique,
+  successes bigint not null default 0,
+  captchas bigint not null default 0,
+  timeouts bigint not null default 0,
+  consecutive_failures int not null default 0,
+  cooldown_until timestamptz,
+  banned boolean not null default false,
+
+	created_at timestamptz not null default now(),
+	updated_at timestamptz not null default now()
+);
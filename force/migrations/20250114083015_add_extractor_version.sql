@@ -0,0 +1,18 @@
+alter table data_extract add column extractor_version int not null default 1;
+create index idx_data_extract_html_tag_id_extractor_version on data_extract (html_tag_id, extractor_version);
+
+create table reextraction (
+  id bigint primary key generated always as identity,
+  extractor_version int not null,
+  data_extraction_intent DataExtractionIntent not null,
+  regenerate_emails bool not null,
+  tags_processed bigint not null default 0,
+  added bigint not null default 0,
+  removed bigint not null default 0,
+  changed bigint not null default 0,
+  unchanged bigint not null default 0,
+  emails_generated bigint not null default 0,
+  finished_at timestamptz,
+
+	created_at timestamptz not null default now()
+);
@@ -0,0 +1,20 @@
+create type EmailPattern as enum (
+  'FIRST',
+  'LAST',
+  'FIRST_LAST',
+  'FIRST_DOT_LAST',
+  'FIRST_L',
+  'F_LAST'
+);
+
+create table email_pattern (
+  id bigint primary key generated always as identity,
+  domain text not null unique,
+  pattern EmailPattern not null,
+  verified_count int not null default 1,
+
+	created_at timestamptz not null default now(),
+	updated_at timestamptz not null default now()
+);
+
+create index idx_email_domain_founder_name on email (domain, founder_name);
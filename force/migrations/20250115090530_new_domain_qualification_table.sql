@@ -0,0 +1,11 @@
+create table domain_qualification (
+  id bigint primary key generated always as identity,
+  domain text not null unique,
+  mx_hosts text[] not null default '{}',
+  is_catch_all boolean not null,
+  smtp_reachable boolean not null,
+  qualified_at timestamptz not null default now(),
+
+	created_at timestamptz not null default now(),
+	updated_at timestamptz not null default now()
+);
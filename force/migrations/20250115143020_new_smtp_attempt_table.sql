@@ -0,0 +1,22 @@
+create type SmtpVerdict as enum (
+  'DELIVERABLE',
+  'UNDELIVERABLE',
+  'GREYLISTED',
+  'UNKNOWN'
+);
+
+create table smtp_attempt (
+  id bigint primary key generated always as identity,
+  email_address text not null,
+  mx_host text not null,
+  verdict SmtpVerdict not null,
+  connected boolean not null,
+  reply_code int,
+  enhanced_code text,
+  transcript text[] not null,
+
+	created_at timestamptz not null default now(),
+	updated_at timestamptz not null default now()
+);
+
+create index idx_smtp_attempt_email_address on smtp_attempt (email_address);
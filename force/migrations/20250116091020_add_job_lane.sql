@@ -0,0 +1,13 @@
+create type JobLane as enum (
+  'INTERACTIVE',
+  'SCHEDULED',
+  'BACKFILL'
+);
+
+alter table run add column lane JobLane not null default 'INTERACTIVE';
+
+alter table job add column lane JobLane not null default 'SCHEDULED';
+alter table job add column run_id uuid references run(id);
+
+create index idx_job_queue_status_lane on job (queue, status, lane, id);
+create index idx_job_run_id on job (run_id);
@@ -0,0 +1,9 @@
+create table dedupe_key (
+  scope text not null,
+  key text not null,
+  seen_at timestamptz not null default now(),
+
+  primary key (scope, key)
+);
+
+create index idx_dedupe_key_seen_at on dedupe_key (seen_at);
@@ -0,0 +1,5 @@
+alter table email add column updated_at timestamptz not null default now();
+update email set updated_at = created_at;
+
+create index idx_email_created_at on email (created_at);
+create index idx_email_domain on email (domain);
@@ -0,0 +1,18 @@
+create type RunStatus as enum (
+  'RUNNING',
+  'COMPLETED',
+  'FAILED'
+);
+
+alter table run add column status RunStatus not null default 'RUNNING';
+alter table run add column target_count int;
+alter table run add column error text;
+
+alter table run add column products_generated int not null default 0;
+alter table run add column queries_scraped int not null default 0;
+alter table run add column domains_qualified int not null default 0;
+alter table run add column founders_found int not null default 0;
+alter table run add column emails_verified int not null default 0;
+
+alter table run add column updated_at timestamptz not null default now();
+alter table run add column completed_at timestamptz;
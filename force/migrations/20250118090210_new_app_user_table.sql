@@ -0,0 +1,21 @@
+create type UserRole as enum (
+  'ADMIN',
+  'MEMBER'
+);
+
+create table app_user (
+  id uuid primary key,
+  email text not null unique,
+  password_hash text not null,
+  role UserRole not null default 'MEMBER',
+  created_at timestamptz not null default now()
+);
+
+create table user_session (
+  token text primary key,
+  user_id uuid not null references app_user(id) on delete cascade,
+  expires_at timestamptz not null,
+  created_at timestamptz not null default now()
+);
+
+create index user_session_user_id_idx on user_session(user_id);
@@ -0,0 +1,23 @@
+create table api_key (
+  id uuid primary key,
+  name text not null,
+  key_prefix text not null,
+  key_hash text not null unique,
+  monthly_lead_quota int not null,
+  requests_per_minute int not null,
+  created_at timestamptz not null default now(),
+  revoked_at timestamptz
+);
+
+create table api_key_lead (
+  id bigserial primary key,
+  api_key_id uuid not null references api_key(id),
+  email_address text not null,
+  run_id uuid references run(id),
+  created_at timestamptz not null default now()
+);
+
+create index api_key_lead_api_key_id_created_at_idx on api_key_lead(api_key_id, created_at);
+create index api_key_lead_api_key_id_email_address_idx on api_key_lead(api_key_id, email_address);
+
+alter table run add column api_key_id uuid references api_key(id);
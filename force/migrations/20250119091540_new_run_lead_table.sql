@@ -0,0 +1,6 @@
+create table run_lead (
+  run_id uuid not null references run(id),
+  email_address text not null,
+  created_at timestamptz not null default now(),
+  primary key (run_id, email_address)
+);
@@ -0,0 +1,13 @@
+create table html_tag_extraction (
+  html_tag_id bigint not null references html_tag(id),
+  extractor_version int not null,
+  created_at timestamptz not null default now(),
+  primary key (html_tag_id, extractor_version)
+);
+
+insert into html_tag_extraction
+  (html_tag_id, extractor_version)
+select distinct
+  html_tag_id, extractor_version
+from
+  data_extract;
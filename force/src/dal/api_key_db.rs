@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgQueryResult, PgPool};
+use uuid::Uuid;
+
+pub struct ApiKeyRow {
+    pub id: Uuid,
+    pub name: String,
+    pub monthly_lead_quota: i32,
+    pub requests_per_minute: i32,
+    /// Verified leads the key consumed since the start of the month
+    pub leads_used: i64,
+}
+
+/// A key with its usage, for the dashboard
+pub struct ApiKeyUsage {
+    pub id: Uuid,
+    pub name: String,
+    pub key_prefix: String,
+    pub monthly_lead_quota: i32,
+    pub requests_per_minute: i32,
+    pub leads_used: i64,
+    pub runs: i64,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+pub async fn insert_api_key(
+    pool: &PgPool,
+    name: &str,
+    key_prefix: &str,
+    key_hash: &str,
+    monthly_lead_quota: i32,
+    requests_per_minute: i32,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar!(
+        r"
+        insert into api_key
+            (id, name, key_prefix, key_hash, monthly_lead_quota, requests_per_minute)
+        values
+            ($1, $2, $3, $4, $5, $6)
+        returning id
+        ",
+        Uuid::new_v4(),
+        name,
+        key_prefix,
+        key_hash,
+        monthly_lead_quota,
+        requests_per_minute,
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Key with the hash, unless it was revoked
+pub async fn get_active_api_key(
+    pool: &PgPool,
+    key_hash: &str,
+) -> Result<Option<ApiKeyRow>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyRow,
+        r#"
+        select
+            k.id,
+            k.name,
+            k.monthly_lead_quota,
+            k.requests_per_minute,
+            coalesce(
+                (
+                    select count(*)
+                    from api_key_lead l
+                    where l.api_key_id = k.id and l.created_at >= date_trunc('month', now())
+                ),
+                0
+            )::bigint as "leads_used!"
+        from
+            api_key k
+        where
+            k.key_hash = $1 and
+            k.revoked_at is null
+        "#,
+        key_hash,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Charges the key for the leads it is handed and returns the ones it may get, in order.
+/// Leads the key got earlier this month are free, new ones are charged until the month's
+/// quota is used up, a lead delivered in an earlier month is charged again. The key's row stays locked until the charge is done, so concurrent requests of a key
+/// can't both spend its last leads.
+pub async fn charge_leads(
+    pool: &PgPool,
+    api_key_id: Uuid,
+    run_id: Option<Uuid>,
+    emails: &[String],
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let quota = sqlx::query_scalar!(
+        r"
+        select monthly_lead_quota from api_key where id = $1 for update
+        ",
+        api_key_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let leads_used = sqlx::query_scalar!(
+        r#"
+        select
+            count(*) as "count!"
+        from
+            api_key_lead
+        where
+            api_key_id = $1 and
+            created_at >= date_trunc('month', now())
+        "#,
+        api_key_id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let delivered: HashSet<String> = sqlx::query_scalar!(
+        r"
+        select
+            email_address
+        from
+            api_key_lead
+        where
+            api_key_id = $1 and
+            email_address = any($2) and
+            created_at >= date_trunc('month', now())
+        ",
+        api_key_id,
+        emails,
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .collect();
+
+    let mut remaining = (quota as i64 - leads_used).max(0);
+    let mut charged = vec![];
+    let mut allowed = vec![];
+    for email in emails {
+        if delivered.contains(email) {
+            allowed.push(email.clone());
+        } else if remaining > 0 && !charged.contains(email) {
+            remaining -= 1;
+            charged.push(email.clone());
+            allowed.push(email.clone());
+        }
+    }
+
+    sqlx::query!(
+        r"
+        insert into api_key_lead
+            (api_key_id, email_address, run_id)
+        select
+            $1, email_address, $3
+        from
+            unnest($2::text[]) as email_address
+        ",
+        api_key_id,
+        &charged,
+        run_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(allowed)
+}
+
+pub async fn get_api_key_usages(pool: &PgPool) -> Result<Vec<ApiKeyUsage>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyUsage,
+        r#"
+        select
+            k.id,
+            k.name,
+            k.key_prefix,
+            k.monthly_lead_quota,
+            k.requests_per_minute,
+            count(l.id) as "leads_used!",
+            count(distinct l.run_id) as "runs!",
+            k.created_at,
+            k.revoked_at
+        from
+            api_key k
+            left join api_key_lead l on
+                l.api_key_id = k.id and
+                l.created_at >= date_trunc('month', now())
+        group by
+            k.id
+        order by
+            k.created_at desc
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn revoke_api_key(pool: &PgPool, api_key_id: Uuid) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update api_key set
+            revoked_at = now()
+        where
+            id = $1 and
+            revoked_at is null
+        ",
+        api_key_id,
+    )
+    .execute(pool)
+    .await
+}
@@ -105,3 +105,40 @@ pub async fn set_google_search_page_depth(
     .execute(pool)
     .await
 }
+
+pub async fn get_queue_limit(pool: &PgPool, key: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        select
+            value
+        from
+            configuration
+        where
+            key = $1
+        "#,
+        key
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn set_queue_limit(
+    key: &str,
+    limit: usize,
+    pool: &PgPool,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r#"
+        insert into configuration
+            (key, value)
+        values
+            ($1, $2)
+        on conflict(key) do update set
+            value = $2
+        "#,
+        key,
+        limit.to_string()
+    )
+    .execute(pool)
+    .await
+}
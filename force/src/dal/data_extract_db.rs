@@ -1,8 +1,13 @@
 use sqlx::PgConnection;
 
-use crate::domain::data_extract::DataExtract;
+use crate::domain::{
+    data_extract::{DataExtract, EXTRACTOR_VERSION},
+    html_tag::HtmlTag,
+};
 
-#[derive(sqlx::Type)]
+use super::html_tag_db::HtmlTagType;
+
+#[derive(sqlx::Type, Clone, Copy)]
 #[sqlx(type_name = "DataType", rename_all = "SCREAMING_SNAKE_CASE")]
 enum DataType {
     Domain,
@@ -10,29 +15,127 @@ enum DataType {
     CompanyName,
 }
 
+impl From<DataExtract> for (String, DataType) {
+    fn from(data: DataExtract) -> Self {
+        match data {
+            DataExtract::Domain(content) => (content, DataType::Domain),
+            DataExtract::FounderName(content) => (content, DataType::FounderName),
+            DataExtract::CompanyName(content) => (content, DataType::CompanyName),
+        }
+    }
+}
+
 pub async fn insert_data(
     con: &mut PgConnection,
     data: DataExtract,
     tag_id: i64,
 ) -> Result<i64, sqlx::Error> {
-    let (content, data_type) = match data {
-        DataExtract::Domain(content) => (content, DataType::Domain),
-        DataExtract::FounderName(content) => (content, DataType::FounderName),
-        DataExtract::CompanyName(content) => (content, DataType::CompanyName),
-    };
+    let (content, data_type) = data.into();
 
     sqlx::query_scalar!(
         r"
         insert into data_extract
-            (data, data_type, html_tag_id)
+            (data, data_type, html_tag_id, extractor_version)
         values
-            ($1, $2, $3)
+            ($1, $2, $3, $4)
         returning id
         ",
         content,
         data_type as DataType,
         tag_id,
+        EXTRACTOR_VERSION,
     )
     .fetch_one(&mut *con)
     .await
 }
+
+/// Marks the tag as processed by `EXTRACTOR_VERSION`, whether it yielded data or not
+pub async fn insert_extraction_marker(
+    con: &mut PgConnection,
+    tag_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r"
+        insert into html_tag_extraction
+            (html_tag_id, extractor_version)
+        values
+            ($1, $2)
+        on conflict do nothing
+        ",
+        tag_id,
+        EXTRACTOR_VERSION,
+    )
+    .execute(&mut *con)
+    .await?;
+
+    Ok(())
+}
+
+/// Inserts the html tags of a web page with the data extracted from them in one statement.
+/// Tag ids are taken from the sequence up front so that every extract points at its own tag.
+/// Every tag is marked as processed by `EXTRACTOR_VERSION`, including the ones without data.
+pub async fn insert_html_tags_with_data(
+    con: &mut PgConnection,
+    web_page_id: i64,
+    tags: Vec<(HtmlTag, Option<DataExtract>)>,
+) -> Result<(), sqlx::Error> {
+    let mut contents = vec![];
+    let mut tag_types = vec![];
+    let mut datas = vec![];
+    let mut data_types = vec![];
+
+    for (tag, data) in tags {
+        let (content, tag_type) = tag.into();
+        contents.push(content);
+        tag_types.push(tag_type);
+
+        match data.map(<(String, DataType)>::from) {
+            Some((data, data_type)) => {
+                datas.push(Some(data));
+                data_types.push(Some(data_type));
+            }
+            None => {
+                datas.push(None);
+                data_types.push(None);
+            }
+        }
+    }
+
+    sqlx::query!(
+        r#"
+        with tag as (
+            select
+                nextval(pg_get_serial_sequence('html_tag', 'id')) as id,
+                t.*
+            from unnest (
+                $2::text[],
+                $3::HtmlTagType[],
+                $4::text[],
+                $5::DataType[]
+            ) as t(text_content, html_tag_type, data, data_type)
+        ), inserted_tag as (
+            insert into html_tag
+                (id, text_content, html_tag_type, google_webpage_id)
+            overriding system value
+            select id, text_content, html_tag_type, $1 from tag
+        ), inserted_marker as (
+            insert into html_tag_extraction
+                (html_tag_id, extractor_version)
+            select id, $6 from tag
+        )
+        insert into data_extract
+            (data, data_type, html_tag_id, extractor_version)
+        select data, data_type, id, $6 from tag where data is not null
+        "#,
+        web_page_id,
+        &contents,
+        &tag_types as &[HtmlTagType],
+        &datas as &[Option<String>],
+        &data_types as &[Option<DataType>],
+        EXTRACTOR_VERSION,
+    )
+    .execute(&mut *con)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use sqlx::{postgres::PgQueryResult, PgPool};
+
+/// Marks the key as seen now, true when it was not seen within the last `ttl_secs`
+pub async fn mark_key_seen(
+    pool: &PgPool,
+    scope: &str,
+    key: &str,
+    ttl_secs: f64,
+) -> Result<bool, sqlx::Error> {
+    let marked = sqlx::query_scalar!(
+        r"
+        insert into dedupe_key
+            (scope, key)
+        values
+            ($1, $2)
+        on conflict (scope, key) do update set
+            seen_at = now()
+        where
+            dedupe_key.seen_at < now() - make_interval(secs => $3)
+        returning key
+        ",
+        scope,
+        key,
+        ttl_secs,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(marked.is_some())
+}
+
+pub async fn delete_expired_keys(
+    pool: &PgPool,
+    scope: &str,
+    ttl_secs: f64,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        delete from dedupe_key
+        where
+            scope = $1 and
+            seen_at < now() - make_interval(secs => $2)
+        ",
+        scope,
+        ttl_secs,
+    )
+    .execute(pool)
+    .await
+}
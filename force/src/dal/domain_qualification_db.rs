@@ -0,0 +1,54 @@
+use sqlx::{postgres::PgQueryResult, PgConnection, PgPool};
+
+use crate::domain::domain_qualification::DomainQualification;
+
+pub async fn get_domain_qualification(
+    pool: &PgPool,
+    domain: &str,
+) -> Result<Option<DomainQualification>, sqlx::Error> {
+    sqlx::query_as!(
+        DomainQualification,
+        r"
+        select
+            domain,
+            mx_hosts,
+            is_catch_all,
+            smtp_reachable,
+            qualified_at
+        from
+            domain_qualification
+        where
+            domain = $1
+        ",
+        domain,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn upsert_domain_qualification(
+    con: &mut PgConnection,
+    qualification: &DomainQualification,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        insert into domain_qualification
+            (domain, mx_hosts, is_catch_all, smtp_reachable, qualified_at)
+        values
+            ($1, $2, $3, $4, $5)
+        on conflict (domain) do update set
+            mx_hosts = excluded.mx_hosts,
+            is_catch_all = excluded.is_catch_all,
+            smtp_reachable = excluded.smtp_reachable,
+            qualified_at = excluded.qualified_at,
+            updated_at = now()
+        ",
+        qualification.domain,
+        &qualification.mx_hosts,
+        qualification.is_catch_all,
+        qualification.smtp_reachable,
+        qualification.qualified_at,
+    )
+    .execute(con)
+    .await
+}
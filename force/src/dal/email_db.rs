@@ -1,24 +1,53 @@
-use sqlx::{postgres::PgQueryResult, PgConnection, PgPool};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgQueryResult, PgConnection, PgPool, Postgres, QueryBuilder};
+use uuid::Uuid;
 
-use crate::domain::email::Email;
+use crate::domain::email::{Email, Reachability, VerificationStatus};
 
 pub async fn insert_email(con: &mut PgConnection, email: Email) -> Result<i64, sqlx::Error> {
     sqlx::query_scalar!(
         r"
         insert into email
-            (email_address, verification_status, reachability, founder_name, domain)
+            (email_address, verification_status, reachability, founder_name, domain, run_id)
         values
-            ($1, 'PENDING', 'UNKNOWN', $2, $3)
+            ($1, 'PENDING', 'UNKNOWN', $2, $3, $4)
         returning id
         ",
         email.email_address,
         email.founder_name,
         email.domain,
+        email.run_id,
     )
     .fetch_one(&mut *con)
     .await
 }
 
+/// Inserts the email unless it already exists, true when it was inserted
+pub async fn insert_email_if_new(
+    con: &mut PgConnection,
+    email: Email,
+) -> Result<bool, sqlx::Error> {
+    let id = sqlx::query_scalar!(
+        r"
+        insert into email
+            (email_address, verification_status, reachability, founder_name, domain, run_id)
+        values
+            ($1, 'PENDING', 'UNKNOWN', $2, $3, $4)
+        on conflict do nothing
+        returning id
+        ",
+        email.email_address,
+        email.founder_name,
+        email.domain,
+        email.run_id,
+    )
+    .fetch_optional(&mut *con)
+    .await?;
+
+    Ok(id.is_some())
+}
+
 pub async fn update_email_verified(
     con: &mut PgConnection,
     email: String,
@@ -27,7 +56,8 @@ pub async fn update_email_verified(
         r"
         update email set
             reachability = 'SAFE',
-            verification_status = 'VERIFIED' 
+            verification_status = 'VERIFIED',
+            updated_at = now()
         where
             email_address = $1
         ",
@@ -45,7 +75,27 @@ pub async fn update_email_unverified(
         r"
         update email set
             reachability = 'INVALID',
-            verification_status = 'INVALID'
+            verification_status = 'INVALID',
+            updated_at = now()
+        where
+            email_address = $1
+        ",
+        email,
+    )
+    .execute(con)
+    .await
+}
+
+pub async fn update_email_catch_all(
+    con: &mut PgConnection,
+    email: String,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update email set
+            reachability = 'RISKY',
+            verification_status = 'CATCH_ALL',
+            updated_at = now()
         where
             email_address = $1
         ",
@@ -55,6 +105,81 @@ pub async fn update_email_unverified(
     .await
 }
 
+/// Any mailbox on a catch-all domain is accepted, so its pending emails can't be told valid
+/// or invalid. Emails verified before the domain turned catch-all keep their status.
+pub async fn update_domain_emails_catch_all(
+    con: &mut PgConnection,
+    domain: &str,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update email set
+            reachability = 'RISKY',
+            verification_status = 'CATCH_ALL',
+            updated_at = now()
+        where
+            domain = $1 and
+            verification_status = 'PENDING'
+        ",
+        domain,
+    )
+    .execute(con)
+    .await
+}
+
+/// Emails among the given ones that were already verified, with their status
+pub async fn get_settled_emails(
+    pool: &PgPool,
+    emails: &[String],
+) -> Result<Vec<(String, VerificationStatus)>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        select
+            email_address,
+            verification_status as "verification_status: VerificationStatus"
+        from
+            email
+        where
+            email_address = any($1) and
+            verification_status <> 'PENDING'
+        "#,
+        emails,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.email_address, row.verification_status))
+        .collect())
+}
+
+/// Verified emails the run found or was handed from earlier runs, oldest first
+pub async fn get_verified_emails_for_run(
+    pool: &PgPool,
+    run_id: Uuid,
+    count: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r"
+        select
+            l.email_address
+        from
+            run_lead l
+            join email e on e.email_address = l.email_address
+        where
+            l.run_id = $1 and
+            e.verification_status = 'VERIFIED'
+        order by l.created_at, l.email_address
+        limit $2
+        ",
+        run_id,
+        count,
+    )
+    .fetch_all(pool)
+    .await
+}
+
 pub async fn get_verified_emails_for_niche(
     pool: &PgPool,
     niche: &str,
@@ -106,3 +231,166 @@ pub async fn get_verified_emails_for_niche(
     .fetch_all(pool)
     .await
 }
+
+/// An email along with where it came from
+#[derive(Serialize, sqlx::FromRow)]
+pub struct LeadRecord {
+    pub id: i64,
+    pub email: String,
+    pub founder_name: String,
+    pub domain: String,
+    pub product: Option<String>,
+    pub niche: Option<String>,
+    pub verification_status: VerificationStatus,
+    pub reachability: Reachability,
+    /// Founder search that found the founder's name
+    pub source_query: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeadSort {
+    #[default]
+    CreatedAt,
+    UpdatedAt,
+    Email,
+    Domain,
+}
+
+impl LeadSort {
+    fn column(&self) -> &'static str {
+        match self {
+            LeadSort::CreatedAt => "e.created_at",
+            LeadSort::UpdatedAt => "e.updated_at",
+            LeadSort::Email => "e.email_address",
+            LeadSort::Domain => "e.domain",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+pub struct LeadFilter {
+    pub niche: Option<String>,
+    pub status: Option<VerificationStatus>,
+    pub domain: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub sort: LeadSort,
+    pub order: SortOrder,
+    /// Id of the last lead of the previous page
+    pub cursor: Option<i64>,
+    pub limit: i64,
+}
+
+/// Page of leads in the filter's sort order, ties are broken by id so that pages never overlap.
+/// The niche and product are the first ones the email's domain was found for.
+pub async fn get_leads(pool: &PgPool, filter: &LeadFilter) -> Result<Vec<LeadRecord>, sqlx::Error> {
+    let mut query: QueryBuilder<Postgres> = QueryBuilder::new(
+        r#"
+        select
+            e.id,
+            e.email_address as email,
+            e.founder_name,
+            e.domain,
+            src.generated_product as product,
+            src.user_niche as niche,
+            e.verification_status,
+            e.reachability,
+            fq.search_query as source_query,
+            e.created_at,
+            e.updated_at
+        from
+            email e
+            left join lateral (
+                select
+                    n.user_niche,
+                    n.generated_product
+                from
+                    data_extract de
+                    join html_tag ht on ht.id = de.html_tag_id
+                    join google_webpage gw on gw.id = ht.google_webpage_id
+                    join niche n on n.generated_product = gw.search_query
+                where
+                    de.data = e.domain and
+                    de.data_type = 'DOMAIN'
+        "#,
+    );
+    if let Some(niche) = &filter.niche {
+        query.push(" and n.user_niche = ").push_bind(niche);
+    }
+    query.push(
+        r#"
+                order by
+                    n.id
+                limit 1
+            ) src on true
+            left join lateral (
+                select
+                    gw.search_query
+                from
+                    data_extract de
+                    join html_tag ht on ht.id = de.html_tag_id
+                    join google_webpage gw on gw.id = ht.google_webpage_id
+                where
+                    de.data = e.founder_name and
+                    de.data_type = 'FOUNDER_NAME' and
+                    gw.search_query like 'site:linkedin.com "' || lower(e.domain) || '"%'
+                order by
+                    gw.id
+                limit 1
+            ) fq on true
+        where
+            true
+        "#,
+    );
+
+    if filter.niche.is_some() {
+        query.push(" and src.user_niche is not null");
+    }
+    if let Some(status) = filter.status {
+        query
+            .push(" and e.verification_status = ")
+            .push_bind(status);
+    }
+    if let Some(domain) = &filter.domain {
+        query
+            .push(" and e.domain = ")
+            .push_bind(domain.to_lowercase());
+    }
+    if let Some(created_after) = filter.created_after {
+        query.push(" and e.created_at >= ").push_bind(created_after);
+    }
+    if let Some(created_before) = filter.created_before {
+        query.push(" and e.created_at < ").push_bind(created_before);
+    }
+
+    let column = filter.sort.column();
+    let (comparison, direction) = match filter.order {
+        SortOrder::Asc => (">", "asc"),
+        SortOrder::Desc => ("<", "desc"),
+    };
+    if let Some(cursor) = filter.cursor {
+        query
+            .push(format!(
+                " and ({column}, e.id) {comparison} (select {column}, e.id from email e where e.id = "
+            ))
+            .push_bind(cursor)
+            .push(")");
+    }
+    query
+        .push(format!(
+            " order by {column} {direction}, e.id {direction} limit "
+        ))
+        .push_bind(filter.limit);
+
+    query.build_query_as().fetch_all(pool).await
+}
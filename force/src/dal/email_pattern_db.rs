@@ -0,0 +1,113 @@
+use sqlx::{postgres::PgQueryResult, PgConnection, PgPool};
+
+use crate::domain::email_pattern::EmailPattern;
+
+pub struct VerifiedFounderEmail {
+    pub founder_name: String,
+    pub domain: String,
+    pub email_address: String,
+}
+
+pub async fn get_email_pattern(
+    pool: &PgPool,
+    domain: &str,
+) -> Result<Option<EmailPattern>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        select
+            pattern as "pattern: EmailPattern"
+        from
+            email_pattern
+        where
+            domain = $1
+        "#,
+        domain,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// A different pattern replaces the stored one, the same pattern bumps its count
+pub async fn upsert_email_pattern(
+    con: &mut PgConnection,
+    domain: &str,
+    pattern: EmailPattern,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        insert into email_pattern
+            (domain, pattern)
+        values
+            ($1, $2)
+        on conflict (domain) do update set
+            pattern = excluded.pattern,
+            verified_count = case when email_pattern.pattern = excluded.pattern
+                then email_pattern.verified_count + 1
+                else 1
+            end,
+            updated_at = now()
+        ",
+        domain,
+        pattern as EmailPattern,
+    )
+    .execute(con)
+    .await
+}
+
+pub async fn set_email_pattern(
+    con: &mut PgConnection,
+    domain: &str,
+    pattern: EmailPattern,
+    verified_count: i32,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        insert into email_pattern
+            (domain, pattern, verified_count)
+        values
+            ($1, $2, $3)
+        on conflict (domain) do update set
+            pattern = excluded.pattern,
+            verified_count = excluded.verified_count,
+            updated_at = now()
+        ",
+        domain,
+        pattern as EmailPattern,
+        verified_count,
+    )
+    .execute(con)
+    .await
+}
+
+/// Verified emails of founders with exactly one verified permutation, founders with more
+/// are on domains that accept anything and say nothing about the pattern
+pub async fn get_unambiguous_verified_emails(
+    con: &mut PgConnection,
+    email_address: Option<&str>,
+) -> Result<Vec<VerifiedFounderEmail>, sqlx::Error> {
+    sqlx::query_as!(
+        VerifiedFounderEmail,
+        r"
+        select
+            e.founder_name,
+            e.domain,
+            e.email_address
+        from
+            email e
+        where
+            e.verification_status = 'VERIFIED' and
+            ($1::text is null or e.email_address = $1) and
+            (
+                select count(*) from email o
+                where
+                    o.domain = e.domain and
+                    o.founder_name = e.founder_name and
+                    o.verification_status = 'VERIFIED'
+            ) = 1
+        order by e.id
+        ",
+        email_address,
+    )
+    .fetch_all(con)
+    .await
+}
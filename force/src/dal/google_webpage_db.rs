@@ -51,3 +51,53 @@ pub async fn filter_unscraped_product_queries(
         .map(|q| q.to_string())
         .collect())
 }
+
+/// Data extracted from the stored pages of a query by the latest extractor of each tag,
+/// `None` if the query was never scraped
+pub async fn get_stored_query_data(
+    pool: &PgPool,
+    query: &str,
+    intent: DataExtractionIntent,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let scraped = sqlx::query_scalar!(
+        r#"
+        select exists (
+            select 1 from google_webpage
+            where search_query = $1 and data_extraction_intent = $2
+        ) as "scraped!"
+        "#,
+        query,
+        intent as DataExtractionIntent,
+    )
+    .fetch_one(pool)
+    .await?;
+    if !scraped {
+        return Ok(None);
+    }
+
+    let data = sqlx::query_scalar!(
+        r"
+        select distinct on (t.id)
+            d.data
+        from
+            google_webpage w
+            join html_tag t on t.google_webpage_id = w.id
+            join data_extract d on d.html_tag_id = t.id
+        where
+            w.search_query = $1 and
+            w.data_extraction_intent = $2 and
+            -- A newer extractor that found nothing in the tag overrides older data
+            not exists (
+                select 1 from html_tag_extraction x
+                where x.html_tag_id = t.id and x.extractor_version > d.extractor_version
+            )
+        order by t.id, d.extractor_version desc, d.id desc
+        ",
+        query,
+        intent as DataExtractionIntent,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(Some(data))
+}
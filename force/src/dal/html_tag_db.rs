@@ -4,24 +4,41 @@ use crate::domain::html_tag::HtmlTag;
 
 #[derive(sqlx::Type)]
 #[sqlx(type_name = "HtmlTagType", rename_all = "SCREAMING_SNAKE_CASE")]
-enum HtmlTagType {
+pub enum HtmlTagType {
     ATag,
     H3Tag,
     SpanTag,
     NextPageATag,
 }
 
+impl HtmlTagType {
+    pub fn with_content(self, content: String) -> HtmlTag {
+        match self {
+            HtmlTagType::ATag => HtmlTag::ATag(content),
+            HtmlTagType::H3Tag => HtmlTag::H3Tag(content),
+            HtmlTagType::SpanTag => HtmlTag::SpanTag(content),
+            HtmlTagType::NextPageATag => HtmlTag::NextPageATag(content),
+        }
+    }
+}
+
+impl From<HtmlTag> for (String, HtmlTagType) {
+    fn from(html_tag: HtmlTag) -> Self {
+        match html_tag {
+            HtmlTag::ATag(content) => (content, HtmlTagType::ATag),
+            HtmlTag::H3Tag(content) => (content, HtmlTagType::H3Tag),
+            HtmlTag::SpanTag(content) => (content, HtmlTagType::SpanTag),
+            HtmlTag::NextPageATag(content) => (content, HtmlTagType::NextPageATag),
+        }
+    }
+}
+
 pub async fn insert_html_tag(
     con: &mut PgConnection,
     html_tag: HtmlTag,
     web_page_id: i64,
 ) -> Result<i64, sqlx::Error> {
-    let (content, tag_type) = match html_tag {
-        HtmlTag::ATag(content) => (content, HtmlTagType::ATag),
-        HtmlTag::H3Tag(content) => (content, HtmlTagType::H3Tag),
-        HtmlTag::SpanTag(content) => (content, HtmlTagType::SpanTag),
-        HtmlTag::NextPageATag(content) => (content, HtmlTagType::NextPageATag),
-    };
+    let (content, tag_type) = html_tag.into();
 
     sqlx::query_scalar!(
         r"
@@ -0,0 +1,310 @@
+use sqlx::{postgres::PgQueryResult, types::JsonValue, PgPool};
+
+use uuid::Uuid;
+
+use crate::domain::job::{JobLane, JobQueue};
+
+pub struct JobRow {
+    pub id: i64,
+    pub payload: JsonValue,
+    pub attempts: i32,
+}
+
+pub async fn insert_job(
+    pool: &PgPool,
+    queue: JobQueue,
+    payload: JsonValue,
+    max_attempts: i32,
+    run_id: Option<Uuid>,
+    lane: JobLane,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r"
+        insert into job
+            (queue, payload, max_attempts, run_id, lane)
+        values
+            ($1, $2, $3, $4, $5)
+        returning id
+        ",
+        queue as JobQueue,
+        payload,
+        max_attempts,
+        run_id,
+        lane as JobLane,
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Jobs waiting to be claimed in `lane` or a lane before it,
+/// including ones scheduled for a later retry
+pub async fn count_pending_jobs(
+    pool: &PgPool,
+    queue: JobQueue,
+    lane: JobLane,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        select
+            count(*) as "count!"
+        from
+            job
+        where
+            queue = $1 and
+            status = 'PENDING' and
+            lane <= $2
+        "#,
+        queue as JobQueue,
+        lane as JobLane,
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub struct QueueDepthRow {
+    pub queue: JobQueue,
+    pub status: String,
+    pub count: i64,
+}
+
+/// Jobs in every queue by status, dead letters included
+pub async fn count_jobs_by_status(pool: &PgPool) -> Result<Vec<QueueDepthRow>, sqlx::Error> {
+    sqlx::query_as!(
+        QueueDepthRow,
+        r#"
+        select
+            queue as "queue: JobQueue",
+            status::text as "status!",
+            count(*) as "count!"
+        from
+            job
+        group by
+            queue, status
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Jobs of a run that are waiting or being worked on, by queue
+pub async fn count_run_jobs(
+    pool: &PgPool,
+    run_id: Uuid,
+) -> Result<Vec<(JobQueue, i64)>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        select
+            queue as "queue: JobQueue",
+            count(*) as "count!"
+        from
+            job
+        where
+            run_id = $1 and
+            status <> 'DEAD'
+        group by
+            queue
+        "#,
+        run_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|row| (row.queue, row.count)).collect())
+}
+
+/// Moves the jobs of a run that are still waiting to `lane`
+pub async fn set_run_jobs_lane(
+    pool: &PgPool,
+    run_id: Uuid,
+    lane: JobLane,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update job set
+            lane = $2,
+            updated_at = now()
+        where
+            run_id = $1 and
+            status = 'PENDING'
+        ",
+        run_id,
+        lane as JobLane,
+    )
+    .execute(pool)
+    .await
+}
+
+/// Claims up to `n` runnable jobs from the queue and leases them for `lease_secs`.
+/// Jobs whose lease expired (worker crashed or was restarted mid job) are claimed again.
+pub async fn claim_jobs(
+    pool: &PgPool,
+    queue: JobQueue,
+    n: i64,
+    lease_secs: f64,
+) -> Result<Vec<JobRow>, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update job set
+            status = 'DEAD',
+            locked_until = null,
+            last_error = 'Lease expired on final attempt',
+            updated_at = now()
+        where
+            queue = $1 and
+            status = 'RUNNING' and
+            locked_until < now() and
+            attempts >= max_attempts
+        ",
+        queue as JobQueue,
+    )
+    .execute(pool)
+    .await?;
+
+    // Rows of an update come back in no particular order, so they are sorted again
+    sqlx::query_as!(
+        JobRow,
+        r#"
+        with claimed as (
+            update job set
+                status = 'RUNNING',
+                attempts = attempts + 1,
+                locked_until = now() + make_interval(secs => $3),
+                updated_at = now()
+            where id in (
+                select
+                    id
+                from
+                    job
+                where
+                    queue = $1 and (
+                        (status = 'PENDING' and run_after <= now()) or
+                        (status = 'RUNNING' and locked_until < now())
+                    )
+                order by lane, id
+                limit $2
+                for update skip locked
+            )
+            returning id, payload, attempts, lane
+        )
+        select
+            id as "id!",
+            payload as "payload!",
+            attempts as "attempts!"
+        from
+            claimed
+        order by lane, id
+        "#,
+        queue as JobQueue,
+        n,
+        lease_secs,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn complete_job(pool: &PgPool, job_id: i64) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!("delete from job where id = $1", job_id)
+        .execute(pool)
+        .await
+}
+
+/// Puts the job back in its queue with exponential backoff, or in the dead letter state
+/// once it has used up all of its attempts.
+pub async fn retry_job(
+    pool: &PgPool,
+    job_id: i64,
+    error: &str,
+    backoff_secs: f64,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update job set
+            status = case when attempts >= max_attempts
+                then 'DEAD'::JobStatus
+                else 'PENDING'::JobStatus
+            end,
+            run_after = now() + make_interval(secs => $3 * power(2, attempts - 1)),
+            locked_until = null,
+            last_error = $2,
+            updated_at = now()
+        where
+            id = $1
+        ",
+        job_id,
+        error,
+        backoff_secs,
+    )
+    .execute(pool)
+    .await
+}
+
+/// Puts the job back in its queue to run after `delay_secs`, used when the work is expected
+/// to succeed later like a greylisted email. Still counts as an attempt.
+pub async fn retry_job_after(
+    pool: &PgPool,
+    job_id: i64,
+    error: &str,
+    delay_secs: f64,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update job set
+            status = case when attempts >= max_attempts
+                then 'DEAD'::JobStatus
+                else 'PENDING'::JobStatus
+            end,
+            run_after = now() + make_interval(secs => $3),
+            locked_until = null,
+            last_error = $2,
+            updated_at = now()
+        where
+            id = $1
+        ",
+        job_id,
+        error,
+        delay_secs,
+    )
+    .execute(pool)
+    .await
+}
+
+/// Gives claimed jobs back to the queue without counting the claim as an attempt
+pub async fn release_jobs(pool: &PgPool, job_ids: &[i64]) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update job set
+            status = 'PENDING',
+            attempts = greatest(attempts - 1, 0),
+            locked_until = null,
+            updated_at = now()
+        where
+            id = any($1) and
+            status = 'RUNNING'
+        ",
+        job_ids,
+    )
+    .execute(pool)
+    .await
+}
+
+pub async fn dead_letter_job(
+    pool: &PgPool,
+    job_id: i64,
+    error: &str,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update job set
+            status = 'DEAD',
+            locked_until = null,
+            last_error = $2,
+            updated_at = now()
+        where
+            id = $1
+        ",
+        job_id,
+        error,
+    )
+    .execute(pool)
+    .await
+}
@@ -6,7 +6,10 @@ use sqlx::{postgres::PgQueryResult, PgConnection, PgPool};
 use uuid::Uuid;
 
 use crate::{
-    domain::{email::FounderDomainEmail, html_tag::HtmlTag},
+    domain::{
+        email::{FounderDomainEmail, Reachability},
+        html_tag::HtmlTag,
+    },
     routes::lead_route::{FounderDomain, FounderTagCandidate},
     services::FRESH_RESULTS,
 };
@@ -507,6 +510,17 @@ impl Display for EmailReachability {
     }
 }
 
+impl From<Reachability> for EmailReachability {
+    fn from(value: Reachability) -> Self {
+        match value {
+            Reachability::Safe => EmailReachability::Safe,
+            Reachability::Unknown => EmailReachability::Unknown,
+            Reachability::Risky => EmailReachability::Risky,
+            Reachability::Invalid => EmailReachability::Invalid,
+        }
+    }
+}
+
 impl From<Reachable> for EmailReachability {
     fn from(value: Reachable) -> Self {
         match value {
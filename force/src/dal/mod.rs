@@ -1,10 +1,20 @@
+pub mod api_key_db;
 pub mod app_db;
 pub mod config_db;
 pub mod data_extract_db;
+pub mod dedupe_db;
+pub mod domain_qualification_db;
 pub mod email_db;
+pub mod email_pattern_db;
 pub mod google_webpage_db;
 pub mod html_tag_db;
+pub mod job_db;
 pub mod lead_db;
 pub mod niche_db;
+pub mod proxy_db;
+pub mod reextraction_db;
+pub mod run_db;
 pub mod smart_scout_db;
+pub mod smtp_attempt_db;
 pub mod stat_db;
+pub mod user_db;
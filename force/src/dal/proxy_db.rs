@@ -0,0 +1,79 @@
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{postgres::PgQueryResult, PgPool};
+
+#[derive(Clone)]
+pub struct ProxyRow {
+    pub id: i64,
+    pub url: String,
+    pub successes: i64,
+    pub captchas: i64,
+    pub timeouts: i64,
+    pub consecutive_failures: i32,
+    pub cooldown_until: Option<DateTime<Utc>>,
+    pub banned: bool,
+}
+
+pub async fn insert_proxies(pool: &PgPool, urls: &[String]) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        insert into proxy
+            (url)
+        select unnest($1::text[])
+        on conflict (url) do nothing
+        ",
+        urls,
+    )
+    .execute(pool)
+    .await
+}
+
+pub async fn get_proxies(pool: &PgPool) -> Result<Vec<ProxyRow>, sqlx::Error> {
+    sqlx::query_as!(
+        ProxyRow,
+        r"
+        select
+            id,
+            url,
+            successes,
+            captchas,
+            timeouts,
+            consecutive_failures,
+            cooldown_until,
+            banned
+        from
+            proxy
+        order by id
+        "
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn update_proxy_stats(
+    pool: &PgPool,
+    proxy: &ProxyRow,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update proxy set
+            successes = $2,
+            captchas = $3,
+            timeouts = $4,
+            consecutive_failures = $5,
+            cooldown_until = $6,
+            banned = $7,
+            updated_at = now()
+        where
+            id = $1
+        ",
+        proxy.id,
+        proxy.successes,
+        proxy.captchas,
+        proxy.timeouts,
+        proxy.consecutive_failures,
+        proxy.cooldown_until,
+        proxy.banned,
+    )
+    .execute(pool)
+    .await
+}
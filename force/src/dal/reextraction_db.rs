@@ -0,0 +1,194 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{postgres::PgQueryResult, PgPool};
+
+use crate::domain::google_webpage::DataExtractionIntent;
+
+use super::html_tag_db::HtmlTagType;
+
+pub struct ReextractionCandidate {
+    pub html_tag_id: i64,
+    pub text_content: String,
+    pub html_tag_type: HtmlTagType,
+    pub search_query: String,
+    pub previous_data: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ReextractionCounts {
+    pub tags_processed: i64,
+    pub added: i64,
+    pub removed: i64,
+    pub changed: i64,
+    pub unchanged: i64,
+    pub emails_generated: i64,
+}
+
+impl ReextractionCounts {
+    pub fn add(&mut self, other: &ReextractionCounts) {
+        self.tags_processed += other.tags_processed;
+        self.added += other.added;
+        self.removed += other.removed;
+        self.changed += other.changed;
+        self.unchanged += other.unchanged;
+        self.emails_generated += other.emails_generated;
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReextractionRow {
+    pub id: i64,
+    pub extractor_version: i32,
+    pub regenerate_emails: bool,
+    pub tags_processed: i64,
+    pub added: i64,
+    pub removed: i64,
+    pub changed: i64,
+    pub unchanged: i64,
+    pub emails_generated: i64,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn insert_reextraction(
+    pool: &PgPool,
+    extractor_version: i32,
+    intent: DataExtractionIntent,
+    regenerate_emails: bool,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r"
+        insert into reextraction
+            (extractor_version, data_extraction_intent, regenerate_emails)
+        values
+            ($1, $2, $3)
+        returning id
+        ",
+        extractor_version,
+        intent as DataExtractionIntent,
+        regenerate_emails,
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Html tags of pages with the given intent that were not processed by `extractor_version` yet,
+/// along with the data extracted by the latest older version to diff against.
+pub async fn get_reextraction_candidates(
+    pool: &PgPool,
+    intent: DataExtractionIntent,
+    extractor_version: i32,
+    after_html_tag_id: i64,
+    limit: i64,
+) -> Result<Vec<ReextractionCandidate>, sqlx::Error> {
+    sqlx::query_as!(
+        ReextractionCandidate,
+        r#"
+        select
+            t.id as html_tag_id,
+            t.text_content,
+            t.html_tag_type as "html_tag_type: HtmlTagType",
+            w.search_query,
+            (
+                select
+                    d.data
+                from
+                    data_extract d
+                where
+                    d.html_tag_id = t.id and
+                    d.extractor_version < $2
+                order by d.extractor_version desc, d.id desc
+                limit 1
+            ) as previous_data
+        from
+            html_tag t
+            join google_webpage w on w.id = t.google_webpage_id
+        where
+            w.data_extraction_intent = $1 and
+            t.id > $3 and
+            not exists (
+                select 1 from html_tag_extraction x
+                where x.html_tag_id = t.id and x.extractor_version = $2
+            )
+        order by t.id
+        limit $4
+        "#,
+        intent as DataExtractionIntent,
+        extractor_version,
+        after_html_tag_id,
+        limit,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn add_reextraction_counts(
+    pool: &PgPool,
+    reextraction_id: i64,
+    counts: &ReextractionCounts,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update reextraction set
+            tags_processed = tags_processed + $2,
+            added = added + $3,
+            removed = removed + $4,
+            changed = changed + $5,
+            unchanged = unchanged + $6,
+            emails_generated = emails_generated + $7
+        where
+            id = $1
+        ",
+        reextraction_id,
+        counts.tags_processed,
+        counts.added,
+        counts.removed,
+        counts.changed,
+        counts.unchanged,
+        counts.emails_generated,
+    )
+    .execute(pool)
+    .await
+}
+
+pub async fn finish_reextraction(
+    pool: &PgPool,
+    reextraction_id: i64,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        "update reextraction set finished_at = now() where id = $1",
+        reextraction_id,
+    )
+    .execute(pool)
+    .await
+}
+
+pub async fn get_reextraction(
+    pool: &PgPool,
+    reextraction_id: i64,
+) -> Result<Option<ReextractionRow>, sqlx::Error> {
+    sqlx::query_as!(
+        ReextractionRow,
+        r"
+        select
+            id,
+            extractor_version,
+            regenerate_emails,
+            tags_processed,
+            added,
+            removed,
+            changed,
+            unchanged,
+            emails_generated,
+            finished_at,
+            created_at
+        from
+            reextraction
+        where
+            id = $1
+        ",
+        reextraction_id,
+    )
+    .fetch_optional(pool)
+    .await
+}
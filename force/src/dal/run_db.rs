@@ -0,0 +1,237 @@
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgQueryResult, PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::domain::{
+    job::JobLane,
+    run::{RunStage, RunStatus},
+};
+
+pub struct RunRow {
+    pub id: Uuid,
+    pub niche: String,
+    pub status: RunStatus,
+    pub target_count: Option<i32>,
+    /// Key the run was started with, its verified leads count against the key's quota
+    pub api_key_id: Option<Uuid>,
+    pub error: Option<String>,
+    pub products_generated: i32,
+    pub queries_scraped: i32,
+    pub domains_qualified: i32,
+    pub founders_found: i32,
+    pub emails_verified: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+pub async fn insert_run(
+    pool: &PgPool,
+    niche: &str,
+    target_count: Option<i32>,
+    api_key_id: Option<Uuid>,
+) -> Result<Uuid, sqlx::Error> {
+    sqlx::query_scalar!(
+        r"
+        insert into run
+            (id, niche, target_count, api_key_id)
+        values
+            ($1, $2, $3, $4)
+        returning id
+        ",
+        Uuid::new_v4(),
+        niche,
+        target_count,
+        api_key_id,
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn get_run(pool: &PgPool, run_id: Uuid) -> Result<Option<RunRow>, sqlx::Error> {
+    sqlx::query_as!(
+        RunRow,
+        r#"
+        select
+            id,
+            niche,
+            status as "status: RunStatus",
+            target_count,
+            api_key_id,
+            error,
+            products_generated,
+            queries_scraped,
+            domains_qualified,
+            founders_found,
+            emails_verified,
+            created_at,
+            updated_at,
+            completed_at
+        from
+            run
+        where
+            id = $1
+        "#,
+        run_id,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Running runs whose products were generated, the ones that can be done
+pub async fn get_running_runs(pool: &PgPool) -> Result<Vec<RunRow>, sqlx::Error> {
+    sqlx::query_as!(
+        RunRow,
+        r#"
+        select
+            id,
+            niche,
+            status as "status: RunStatus",
+            target_count,
+            api_key_id,
+            error,
+            products_generated,
+            queries_scraped,
+            domains_qualified,
+            founders_found,
+            emails_verified,
+            created_at,
+            updated_at,
+            completed_at
+        from
+            run
+        where
+            status = 'RUNNING' and
+            products_generated > 0
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn set_products_generated(
+    pool: &PgPool,
+    run_id: Uuid,
+    count: i32,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update run set
+            products_generated = $2,
+            updated_at = now()
+        where
+            id = $1
+        ",
+        run_id,
+        count,
+    )
+    .execute(pool)
+    .await
+}
+
+pub async fn add_run_progress(
+    con: &mut PgConnection,
+    run_id: Uuid,
+    stage: RunStage,
+    count: i32,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update run set
+            queries_scraped = queries_scraped + case when $2 = 'queries_scraped' then $3 else 0 end,
+            domains_qualified = domains_qualified + case when $2 = 'domains_qualified' then $3 else 0 end,
+            founders_found = founders_found + case when $2 = 'founders_found' then $3 else 0 end,
+            emails_verified = emails_verified + case when $2 = 'emails_verified' then $3 else 0 end,
+            updated_at = now()
+        where
+            id = $1
+        ",
+        run_id,
+        stage.column(),
+        count,
+    )
+    .execute(con)
+    .await
+}
+
+/// Links a verified email to a run, the same email can be a lead of several runs
+pub async fn insert_run_lead(
+    con: &mut PgConnection,
+    run_id: Uuid,
+    email: &str,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        insert into run_lead
+            (run_id, email_address)
+        values
+            ($1, $2)
+        on conflict do nothing
+        ",
+        run_id,
+        email,
+    )
+    .execute(con)
+    .await
+}
+
+/// Only moves runs that are still running, a finished run keeps its status
+pub async fn finish_run(
+    pool: &PgPool,
+    run_id: Uuid,
+    status: RunStatus,
+    error: Option<&str>,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update run set
+            status = $2,
+            error = $3,
+            completed_at = now(),
+            updated_at = now()
+        where
+            id = $1 and
+            status = 'RUNNING'
+        ",
+        run_id,
+        status as RunStatus,
+        error,
+    )
+    .execute(pool)
+    .await
+}
+
+pub async fn get_run_lane(pool: &PgPool, run_id: Uuid) -> Result<Option<JobLane>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        select
+            lane as "lane: JobLane"
+        from
+            run
+        where
+            id = $1
+        "#,
+        run_id,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn set_run_lane(
+    pool: &PgPool,
+    run_id: Uuid,
+    lane: JobLane,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        update run set
+            lane = $2
+        where
+            id = $1
+        ",
+        run_id,
+        lane as JobLane,
+    )
+    .execute(pool)
+    .await
+}
@@ -0,0 +1,70 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+
+use crate::services::{SmtpAttempt, SmtpVerdict};
+
+#[derive(Serialize)]
+pub struct SmtpAttemptRow {
+    pub id: i64,
+    pub mx_host: String,
+    pub verdict: SmtpVerdict,
+    pub connected: bool,
+    pub reply_code: Option<i32>,
+    pub enhanced_code: Option<String>,
+    pub transcript: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn insert_smtp_attempt(
+    con: &mut PgConnection,
+    email_address: &str,
+    attempt: &SmtpAttempt,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r"
+        insert into smtp_attempt
+            (email_address, mx_host, verdict, connected, reply_code, enhanced_code, transcript)
+        values
+            ($1, $2, $3, $4, $5, $6, $7)
+        returning id
+        ",
+        email_address,
+        attempt.mx_host,
+        attempt.verdict as SmtpVerdict,
+        attempt.connected,
+        attempt.reply_code.map(i32::from),
+        attempt.enhanced_code,
+        &attempt.transcript,
+    )
+    .fetch_one(&mut *con)
+    .await
+}
+
+pub async fn get_smtp_attempts(
+    pool: &PgPool,
+    email_address: &str,
+) -> Result<Vec<SmtpAttemptRow>, sqlx::Error> {
+    sqlx::query_as!(
+        SmtpAttemptRow,
+        r#"
+        select
+            id,
+            mx_host,
+            verdict as "verdict: SmtpVerdict",
+            connected,
+            reply_code,
+            enhanced_code,
+            transcript,
+            created_at
+        from
+            smtp_attempt
+        where
+            email_address = $1
+        order by id
+        "#,
+        email_address,
+    )
+    .fetch_all(pool)
+    .await
+}
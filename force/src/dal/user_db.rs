@@ -0,0 +1,122 @@
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgQueryResult, PgPool};
+use uuid::Uuid;
+
+use crate::domain::user::UserRole;
+
+pub struct UserRow {
+    pub id: Uuid,
+    pub email: String,
+    pub password_hash: String,
+    pub role: UserRole,
+}
+
+/// Adds the user, or does nothing and returns `None` if the email is taken
+pub async fn insert_user(
+    pool: &PgPool,
+    email: &str,
+    password_hash: &str,
+    role: UserRole,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r"
+        insert into app_user
+            (id, email, password_hash, role)
+        values
+            ($1, $2, $3, $4)
+        on conflict do nothing
+        returning id
+        ",
+        Uuid::new_v4(),
+        email.to_lowercase(),
+        password_hash,
+        role as UserRole,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn get_user_by_email(pool: &PgPool, email: &str) -> Result<Option<UserRow>, sqlx::Error> {
+    sqlx::query_as!(
+        UserRow,
+        r#"
+        select
+            id,
+            email,
+            password_hash,
+            role as "role: UserRole"
+        from
+            app_user
+        where
+            email = $1
+        "#,
+        email.to_lowercase(),
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn insert_session(
+    pool: &PgPool,
+    token: &str,
+    user_id: Uuid,
+    expires_at: DateTime<Utc>,
+) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        insert into user_session
+            (token, user_id, expires_at)
+        values
+            ($1, $2, $3)
+        ",
+        token,
+        user_id,
+        expires_at,
+    )
+    .execute(pool)
+    .await
+}
+
+/// User of a session that has not expired yet
+pub async fn get_session_user(pool: &PgPool, token: &str) -> Result<Option<UserRow>, sqlx::Error> {
+    sqlx::query_as!(
+        UserRow,
+        r#"
+        select
+            u.id,
+            u.email,
+            u.password_hash,
+            u.role as "role: UserRole"
+        from
+            user_session s
+            join app_user u on u.id = s.user_id
+        where
+            s.token = $1 and
+            s.expires_at > now()
+        "#,
+        token,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn delete_session(pool: &PgPool, token: &str) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        delete from user_session where token = $1
+        ",
+        token,
+    )
+    .execute(pool)
+    .await
+}
+
+pub async fn delete_expired_sessions(pool: &PgPool) -> Result<PgQueryResult, sqlx::Error> {
+    sqlx::query!(
+        r"
+        delete from user_session where expires_at <= now()
+        ",
+    )
+    .execute(pool)
+    .await
+}
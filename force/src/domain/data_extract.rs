@@ -1,3 +1,7 @@
+/// Version of `extract_domain` and `extract_founder_name`, bump it whenever they change so
+/// that stored html tags can be re-extracted. Rows saved before versioning are version 1.
+pub const EXTRACTOR_VERSION: i32 = 2;
+
 pub enum DataExtract {
     Domain(String),
     FounderName(String),
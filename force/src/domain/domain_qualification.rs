@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Result of probing a domain's mail servers with a made up mailbox
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DomainQualification {
+    pub domain: String,
+    pub mx_hosts: Vec<String>,
+    /// The made up mailbox was accepted, so every email on the domain looks valid
+    pub is_catch_all: bool,
+    pub smtp_reachable: bool,
+    pub qualified_at: DateTime<Utc>,
+}
+
+impl DomainQualification {
+    pub fn is_fresh(&self, ttl: Duration, now: DateTime<Utc>) -> bool {
+        self.qualified_at + ttl > now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use super::DomainQualification;
+
+    #[test]
+    fn domain_qualification_freshness() {
+        let now = Utc::now();
+        let ttl = Duration::from_secs(60 * 60);
+        let qualification = |age: Duration| DomainQualification {
+            domain: "verywellfit.com".to_string(),
+            mx_hosts: vec!["mx.verywellfit.com".to_string()],
+            is_catch_all: true,
+            smtp_reachable: true,
+            qualified_at: now - age,
+        };
+
+        assert!(qualification(Duration::from_secs(0)).is_fresh(ttl, now));
+        assert!(qualification(Duration::from_secs(59 * 60)).is_fresh(ttl, now));
+        assert!(!qualification(Duration::from_secs(60 * 60)).is_fresh(ttl, now));
+        assert!(!qualification(Duration::from_secs(3 * 60 * 60)).is_fresh(ttl, now));
+    }
+}
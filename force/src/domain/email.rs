@@ -1,17 +1,21 @@
 use check_if_email_exists::Reachable;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::dal::lead_db::{EmailReachability, EmailVerifiedStatus};
 
+use super::{email_pattern::EmailPattern, person_name::parse_person_name};
+
 pub struct Email {
     pub email_address: String,
     pub founder_name: String,
     pub domain: String,
     pub verification_status: VerificationStatus,
     pub reachability: Reachability,
+    pub run_id: Option<Uuid>,
 }
 
-#[derive(Debug, PartialEq, Deserialize, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "VerificationStatus", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum VerificationStatus {
     Pending,
@@ -30,7 +34,7 @@ impl From<EmailVerifiedStatus> for VerificationStatus {
     }
 }
 
-#[derive(Debug, PartialEq, Deserialize, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "Reachability", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Reachability {
     Safe,
@@ -61,63 +65,63 @@ impl From<EmailReachability> for Reachability {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FounderDomainEmail {
     pub founder_name: String,
     pub domain: String,
     pub email: String,
+    pub run_id: Option<Uuid>,
+    /// Other permutations to verify only if this email turns out invalid
+    #[serde(default)]
+    pub fallback_emails: Vec<String>,
 }
 
 pub fn construct_email_permutations(name: &str, domain: &str) -> Vec<FounderDomainEmail> {
-    let mut emails_db: Vec<FounderDomainEmail> = vec![];
+    match parse_person_name(name) {
+        Some(person) => EmailPattern::ALL
+            .iter()
+            .map(|pattern| FounderDomainEmail {
+                email: format!(
+                    "{}@{}",
+                    pattern.local_part(&person.first, &person.last),
+                    domain
+                ),
+                founder_name: name.to_string(),
+                domain: domain.to_string(),
+                run_id: None,
+                fallback_emails: vec![],
+            })
+            .collect(),
+        None => vec![],
+    }
+}
 
-    let name_pieces: Vec<&str> = name.split(" ").collect();
-    if name_pieces.len() == 2 {
-        let name_vec: Vec<&str> = name.split(" ").collect();
-        let first_name = name_vec.first().unwrap().to_lowercase();
-        let last_name = name_vec.get(1).unwrap().to_lowercase();
+/// With a pattern learned for the domain only its email is verified right away,
+/// the other permutations are its fallbacks
+pub fn construct_emails_with_pattern(
+    name: &str,
+    domain: &str,
+    pattern: Option<EmailPattern>,
+) -> Vec<FounderDomainEmail> {
+    let emails = construct_email_permutations(name, domain);
 
-        emails_db.push(FounderDomainEmail {
-            email: format!("{}@{}", first_name, domain),
-            founder_name: name.to_string(),
-            domain: domain.to_string(),
-        });
-        emails_db.push(FounderDomainEmail {
-            email: format!("{}@{}", last_name, domain),
-            founder_name: name.to_string(),
-            domain: domain.to_string(),
-        });
-        emails_db.push(FounderDomainEmail {
-            email: format!("{}{}@{}", first_name, last_name, domain),
-            founder_name: name.to_string(),
-            domain: domain.to_string(),
-        });
-        emails_db.push(FounderDomainEmail {
-            email: format!("{}.{}@{}", first_name, last_name, domain),
-            founder_name: name.to_string(),
-            domain: domain.to_string(),
-        });
-        emails_db.push(FounderDomainEmail {
-            email: format!(
-                "{}{}@{}",
-                first_name,
-                last_name.chars().next().unwrap(),
-                domain
-            ),
-            founder_name: name.to_string(),
-            domain: domain.to_string(),
-        });
-        emails_db.push(FounderDomainEmail {
-            email: format!(
-                "{}{}@{}",
-                first_name.chars().next().unwrap(),
-                last_name,
-                domain
-            ),
-            founder_name: name.to_string(),
-            domain: domain.to_string(),
-        });
-    }
+    let (Some(pattern), Some(person)) = (pattern, parse_person_name(name)) else {
+        return emails;
+    };
+    let learned_email = format!(
+        "{}@{}",
+        pattern.local_part(&person.first, &person.last),
+        domain
+    );
+
+    let (learned, fallbacks): (Vec<FounderDomainEmail>, Vec<FounderDomainEmail>) =
+        emails.into_iter().partition(|em| em.email == learned_email);
 
-    emails_db
+    learned
+        .into_iter()
+        .map(|em| FounderDomainEmail {
+            fallback_emails: fallbacks.iter().map(|f| f.email.clone()).collect(),
+            ..em
+        })
+        .collect()
 }
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+use super::person_name::parse_person_name;
+
+/// Format of the local part of an email address built from a founder's name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "EmailPattern", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum EmailPattern {
+    First,
+    Last,
+    FirstLast,
+    FirstDotLast,
+    FirstL,
+    FLast,
+}
+
+impl EmailPattern {
+    pub const ALL: [EmailPattern; 6] = [
+        EmailPattern::First,
+        EmailPattern::Last,
+        EmailPattern::FirstLast,
+        EmailPattern::FirstDotLast,
+        EmailPattern::FirstL,
+        EmailPattern::FLast,
+    ];
+
+    /// Expects lowercase, non empty name pieces
+    pub fn local_part(&self, first_name: &str, last_name: &str) -> String {
+        let first_initial: String = first_name.chars().take(1).collect();
+        let last_initial: String = last_name.chars().take(1).collect();
+
+        match self {
+            EmailPattern::First => first_name.to_string(),
+            EmailPattern::Last => last_name.to_string(),
+            EmailPattern::FirstLast => format!("{}{}", first_name, last_name),
+            EmailPattern::FirstDotLast => format!("{}.{}", first_name, last_name),
+            EmailPattern::FirstL => format!("{}{}", first_name, last_initial),
+            EmailPattern::FLast => format!("{}{}", first_initial, last_name),
+        }
+    }
+
+    /// Pattern that builds `email` out of `founder_name`, None when the name can not be
+    /// parsed into a person's name or the email does not follow any known pattern
+    pub fn infer(founder_name: &str, email: &str) -> Option<EmailPattern> {
+        let person = parse_person_name(founder_name)?;
+        let (local_part, _) = email.split_once('@')?;
+        let local_part = local_part.to_lowercase();
+
+        EmailPattern::ALL
+            .into_iter()
+            .find(|pattern| pattern.local_part(&person.first, &person.last) == local_part)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::email::construct_emails_with_pattern;
+
+    use super::EmailPattern;
+
+    #[test]
+    fn infer_email_pattern_valid() {
+        let cases = [
+            ("Dan Go", "dan@verywellfit.com", Some(EmailPattern::First)),
+            (
+                "Dan Go",
+                "dan.go@verywellfit.com",
+                Some(EmailPattern::FirstDotLast),
+            ),
+            (
+                "Samina Qureshi",
+                "SQureshi@verywellfit.com",
+                Some(EmailPattern::FLast),
+            ),
+            (
+                "Samina Qureshi",
+                "saminaq@verywellfit.com",
+                Some(EmailPattern::FirstL),
+            ),
+            ("Samina Qureshi", "hello@verywellfit.com", None),
+            (
+                "Veer Pushpak Gupta",
+                "veer.gupta@verywellfit.com",
+                Some(EmailPattern::FirstDotLast),
+            ),
+            (
+                "Wondercise Technology Corp.",
+                "wondercise@verywellfit.com",
+                None,
+            ),
+        ];
+
+        for (name, email, expected) in cases {
+            assert_eq!(EmailPattern::infer(name, email), expected);
+        }
+    }
+
+    #[test]
+    fn construct_emails_with_learned_pattern() {
+        let emails =
+            construct_emails_with_pattern("Dan Go", "verywellfit.com", Some(EmailPattern::FLast));
+
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].email, "dgo@verywellfit.com");
+        assert_eq!(
+            emails[0].fallback_emails,
+            vec![
+                "dan@verywellfit.com",
+                "go@verywellfit.com",
+                "dango@verywellfit.com",
+                "dan.go@verywellfit.com",
+                "dang@verywellfit.com",
+            ]
+        );
+
+        let emails = construct_emails_with_pattern("Dan Go", "verywellfit.com", None);
+        assert_eq!(emails.len(), 6);
+        assert!(emails.iter().all(|em| em.fallback_emails.is_empty()));
+    }
+}
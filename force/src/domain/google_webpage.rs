@@ -8,7 +8,7 @@ pub struct GoogleWebPage {
     pub any_result: bool,
 }
 
-#[derive(Debug, PartialEq, Deserialize, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, sqlx::Type)]
 #[sqlx(
     type_name = "DataExtractionIntent",
     rename_all = "SCREAMING_SNAKE_CASE"
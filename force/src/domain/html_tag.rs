@@ -1,7 +1,8 @@
+use serde::{Deserialize, Serialize};
 use strsim::jaro_winkler;
 use url::Url;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum HtmlTag {
     ATag(String),
     H3Tag(String),
@@ -70,10 +71,11 @@ pub fn extract_founder_name(tag: HtmlTag) -> Option<String> {
     }
 }
 
+/// Accepts google's relative `/url?q=` links as well as absolute urls from the other search engines
 pub fn extract_domain(tag: HtmlTag) -> Option<String> {
     match tag {
-        HtmlTag::ATag(content) => match content.strip_prefix("/url?q=") {
-            Some(url) => match Url::parse(url) {
+        HtmlTag::ATag(content) => {
+            match Url::parse(content.strip_prefix("/url?q=").unwrap_or(&content)) {
                 Ok(parsed_url) => match parsed_url.host_str() {
                     Some("support.google.com") => None,
                     Some("www.google.com") => None,
@@ -94,9 +96,8 @@ pub fn extract_domain(tag: HtmlTag) -> Option<String> {
                     }
                 },
                 Err(_) => None,
-            },
-            None => None,
-        },
+            }
+        }
         _ => None,
     }
 }
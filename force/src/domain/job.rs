@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "JobQueue", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum JobQueue {
+    ProductQuery,
+    DomainQualifier,
+    FounderQuery,
+    EmailVerifier,
+    PersistantData,
+}
+
+/// Jobs are claimed lane by lane, work someone is waiting on goes first
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[sqlx(type_name = "JobLane", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum JobLane {
+    /// Runs of a route that is waiting on the results
+    Interactive,
+    Scheduled,
+    /// Smart Scout, re-extractions and runs that already have their results
+    Backfill,
+}
+
+impl JobQueue {
+    /// Name used in the `queue-capacity-*` and `worker-concurrency-*` configuration keys
+    pub fn config_name(&self) -> &'static str {
+        match self {
+            JobQueue::ProductQuery => "product-query",
+            JobQueue::DomainQualifier => "domain-qualifier",
+            JobQueue::FounderQuery => "founder-query",
+            JobQueue::EmailVerifier => "email-verifier",
+            JobQueue::PersistantData => "persistant-data",
+        }
+    }
+
+    /// Pending jobs allowed before senders have to wait, unless set in the configuration table
+    pub fn default_capacity(&self) -> usize {
+        match self {
+            JobQueue::ProductQuery => 1_000,
+            JobQueue::DomainQualifier => 5_000,
+            JobQueue::FounderQuery => 5_000,
+            JobQueue::EmailVerifier => 20_000,
+            JobQueue::PersistantData => 50_000,
+        }
+    }
+
+    /// Jobs worked on at the same time, unless set in the configuration table
+    pub fn default_concurrency(&self) -> usize {
+        match self {
+            JobQueue::ProductQuery => 10,
+            JobQueue::DomainQualifier => 20,
+            JobQueue::FounderQuery => 10,
+            JobQueue::EmailVerifier => 20,
+            // Persistance writes inline, this is only how many jobs it claims at once
+            JobQueue::PersistantData => 100,
+        }
+    }
+}
@@ -1,7 +1,13 @@
 pub mod config;
 pub mod data_extract;
+pub mod domain_qualification;
 pub mod email;
+pub mod email_pattern;
 pub mod google_webpage;
 pub mod html_tag;
+pub mod job;
 pub mod niche;
+pub mod person_name;
+pub mod run;
 pub mod smart_scout;
+pub mod user;
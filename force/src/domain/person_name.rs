@@ -0,0 +1,146 @@
+use deunicode::deunicode;
+
+const HONORIFICS: [&str; 9] = ["dr", "mr", "mrs", "ms", "miss", "mx", "prof", "sir", "dame"];
+const SUFFIXES: [&str; 22] = [
+    "jr", "sr", "ii", "iii", "iv", "v", "md", "mba", "phd", "dds", "dmd", "do", "rn", "np", "cpa",
+    "cfa", "esq", "pe", "pharmd", "msc", "bsc", "mph",
+];
+const PARTICLES: [&str; 19] = [
+    "de", "da", "di", "do", "du", "del", "della", "der", "den", "dos", "das", "van", "von", "ter",
+    "la", "le", "al", "el", "bin",
+];
+const COMPANY_WORDS: [&str; 12] = [
+    "inc",
+    "llc",
+    "ltd",
+    "corp",
+    "co",
+    "company",
+    "gmbh",
+    "group",
+    "technology",
+    "technologies",
+    "solutions",
+    "labs",
+];
+
+/// Founder name split into ascii lowercase pieces ready to be used in email local parts
+#[derive(Debug, PartialEq)]
+pub struct PersonName {
+    pub first: String,
+    pub middle: Vec<String>,
+    /// Particles are kept with the surname, "de Troostembergh" becomes "detroostembergh"
+    pub last: String,
+}
+
+pub fn parse_person_name(name: &str) -> Option<PersonName> {
+    let name = deunicode(&fix_mojibake(name));
+
+    // Credentials usually come after a comma like "Deepak L. Bhatt, MD"
+    let name = name.split(',').next()?;
+
+    let mut pieces: Vec<String> = name
+        .split_whitespace()
+        .map(|piece| {
+            piece
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+                .collect::<String>()
+                .trim_matches('-')
+                .to_lowercase()
+        })
+        .filter(|piece| !piece.is_empty())
+        .collect();
+
+    if pieces.iter().any(|p| COMPANY_WORDS.contains(&p.as_str())) {
+        return None;
+    }
+
+    while pieces
+        .first()
+        .is_some_and(|p| HONORIFICS.contains(&p.as_str()))
+    {
+        pieces.remove(0);
+    }
+    while pieces.len() > 2 && SUFFIXES.contains(&pieces[pieces.len() - 1].as_str()) {
+        pieces.pop();
+    }
+
+    if pieces.len() < 2 || pieces.iter().any(|p| p.chars().any(|c| c.is_ascii_digit())) {
+        return None;
+    }
+
+    let first = pieces.remove(0);
+    let mut last = vec![pieces.pop()?];
+    while pieces
+        .last()
+        .is_some_and(|p| PARTICLES.contains(&p.as_str()))
+    {
+        last.insert(0, pieces.pop()?);
+    }
+
+    Some(PersonName {
+        first,
+        middle: pieces,
+        last: last.concat(),
+    })
+}
+
+/// Utf-8 text that was decoded as latin-1 somewhere, "HÃ©lÃ¨ne" is turned back into "Hélène"
+fn fix_mojibake(name: &str) -> String {
+    if !name.contains(['Ã', 'Â']) {
+        return name.to_string();
+    }
+
+    let bytes: Option<Vec<u8>> = name.chars().map(|c| u8::try_from(c).ok()).collect();
+    bytes
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .unwrap_or(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_person_name, PersonName};
+
+    fn person(first: &str, middle: &[&str], last: &str) -> Option<PersonName> {
+        Some(PersonName {
+            first: first.to_string(),
+            middle: middle.iter().map(|m| m.to_string()).collect(),
+            last: last.to_string(),
+        })
+    }
+
+    #[test]
+    fn parse_person_name_valid() {
+        let cases = [
+            ("Dan Go", person("dan", &[], "go")),
+            ("Veer Pushpak Gupta", person("veer", &["pushpak"], "gupta")),
+            ("Deepak L. Bhatt", person("deepak", &["l"], "bhatt")),
+            (
+                "Deepak L. Bhatt, MD, MPH",
+                person("deepak", &["l"], "bhatt"),
+            ),
+            ("Dr. Jane Smith PhD", person("jane", &[], "smith")),
+            ("West Shell III", person("west", &[], "shell")),
+            (
+                "HÃ©lÃ¨ne de Troostembergh",
+                person("helene", &[], "detroostembergh"),
+            ),
+            (
+                "Hélène de Troostembergh",
+                person("helene", &[], "detroostembergh"),
+            ),
+            ("Ludwig van der Berg", person("ludwig", &[], "vanderberg")),
+            ("Mary Smith-Jones", person("mary", &[], "smith-jones")),
+            ("Conan O'Brien", person("conan", &[], "obrien")),
+            ("Wondercise Technology Corp.", None),
+            ("WellTheory", None),
+            ("Dr. Smith", None),
+            ("Jane 2 Smith", None),
+        ];
+
+        for (name, expected) in cases {
+            assert_eq!(parse_person_name(name), expected, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, sqlx::Type)]
+#[sqlx(type_name = "RunStatus", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RunStatus {
+    Running,
+    /// The run found its target count of emails or has no work left
+    Completed,
+    Failed,
+}
+
+/// Pipeline stages whose progress is counted per run
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RunStage {
+    QueriesScraped,
+    DomainsQualified,
+    FoundersFound,
+    EmailsVerified,
+}
+
+impl RunStage {
+    /// Counter column of the stage in the run table
+    pub fn column(&self) -> &'static str {
+        match self {
+            RunStage::QueriesScraped => "queries_scraped",
+            RunStage::DomainsQualified => "domains_qualified",
+            RunStage::FoundersFound => "founders_found",
+            RunStage::EmailsVerified => "emails_verified",
+        }
+    }
+}
@@ -0,0 +1,10 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "UserRole", rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(rename_all = "snake_case")]
+pub enum UserRole {
+    /// Can also change configuration and add users
+    Admin,
+    Member,
+}
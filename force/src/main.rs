@@ -1,20 +1,29 @@
-use std::{net::TcpListener, time::Duration};
+use std::{
+    net::TcpListener,
+    time::{Duration, Instant},
+};
 
 use actix_web::web;
 use env_logger::Env;
 use force::{
     configuration::get_configuration,
-    domain::email::FounderDomainEmail,
+    domain::{
+        email::FounderDomainEmail,
+        job::{JobLane, JobQueue},
+    },
     services::{
         data_persistance_handler, domain_qualifier_handler, domain_scraper_handler,
-        email_verified_handler, founder_scraper_handler, smart_scout_scraper_handler,
-        EmailVerifierSender, FounderQueryChannelData, OpenaiClient, PersistantData,
-        ProductQuerySender, Sentinel, VerifiedEmailReceiver,
+        email_verified_handler, ensure_admin, founder_scraper_handler, job_channel,
+        learn_email_patterns_from_verified, run_completion_handler, smart_scout_scraper_handler,
+        Dedupe, DomainQualifierChannelData, EmailVerifierSender, FounderQueryChannelData,
+        OpenaiClient, PersistantData, ProductQueryChannelData, ProductQuerySender, ProxyPool,
+        SearchEngines, Sentinel, SerpFixtures, Shutdown, SmtpVerifier, Supervisor, VerifiedEmail,
+        VerifiedEmailReceiver,
     },
-    startup::run,
+    startup::{run, AppState},
 };
 use sqlx::postgres::PgPoolOptions;
-use tokio::sync::{self, mpsc};
+use tokio::sync;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
@@ -36,18 +45,54 @@ async fn main() -> std::io::Result<()> {
     );
     let listener = TcpListener::bind(address)?;
     let openai_client = OpenaiClient::new(configuration.api_keys.openai);
-    let sentinel = Sentinel::new(configuration.api_keys.bulk_email_checker);
+    let sentinel = Sentinel::new(
+        configuration.email_verification,
+        configuration.api_keys.bulk_email_checker,
+        SmtpVerifier::new(configuration.smtp_verifier),
+    );
     let sentinel = web::Data::new(sentinel);
+    let proxy_pool = ProxyPool::load(connection_pool.clone(), configuration.proxies)
+        .await
+        .expect("Failed to load proxies.");
+    let proxy_pool = web::Data::new(proxy_pool);
+    let search_engines = web::Data::new(SearchEngines::new(
+        configuration.search_engines,
+        proxy_pool.clone(),
+        SerpFixtures::new(configuration.serp_fixtures),
+    ));
 
-    let (product_query_sender, product_query_receiver) = mpsc::unbounded_channel::<String>();
+    // Stages stop first, persistence drains after them to flush what they sent on their way out
+    let stage_shutdown = Shutdown::default();
+    let persistance_shutdown = Shutdown::default();
+
+    let (product_query_sender, product_query_receiver) =
+        job_channel::<ProductQueryChannelData>(connection_pool.clone(), JobQueue::ProductQuery);
     let (founder_query_sender, founder_query_receiver) =
-        mpsc::unbounded_channel::<FounderQueryChannelData>();
-    let (domain_qualifier_sender, doomain_qualifier_receiver) = mpsc::unbounded_channel::<String>();
-    let (email_sender, email_receiver) = mpsc::unbounded_channel::<FounderDomainEmail>();
+        job_channel::<FounderQueryChannelData>(connection_pool.clone(), JobQueue::FounderQuery);
+    let (domain_qualifier_sender, doomain_qualifier_receiver) =
+        job_channel::<DomainQualifierChannelData>(
+            connection_pool.clone(),
+            JobQueue::DomainQualifier,
+        );
+    let (email_sender, email_receiver) =
+        job_channel::<FounderDomainEmail>(connection_pool.clone(), JobQueue::EmailVerifier);
     let (persistant_data_sender, persistant_data_receiver) =
-        mpsc::unbounded_channel::<PersistantData>();
+        job_channel::<PersistantData>(connection_pool.clone(), JobQueue::PersistantData);
+    let product_query_receiver = product_query_receiver.stop_on(stage_shutdown.signal());
+    let doomain_qualifier_receiver = doomain_qualifier_receiver.stop_on(stage_shutdown.signal());
+    let founder_query_receiver = founder_query_receiver.stop_on(stage_shutdown.signal());
+    let email_receiver = email_receiver.stop_on(stage_shutdown.signal());
+    let persistant_data_receiver = persistant_data_receiver.drain_on(persistance_shutdown.signal());
+    let stage_monitors = [
+        product_query_receiver.monitor(),
+        doomain_qualifier_receiver.monitor(),
+        founder_query_receiver.monitor(),
+        email_receiver.monitor(),
+    ];
+    let persistance_monitor = persistant_data_receiver.monitor();
+
     let (verified_email_sender, verified_email_receiver) =
-        sync::broadcast::channel::<String>(10_000);
+        sync::broadcast::channel::<VerifiedEmail>(10_000);
     drop(verified_email_receiver); // TODO: Remove this?
 
     let product_query_sender = ProductQuerySender {
@@ -60,66 +105,153 @@ async fn main() -> std::io::Result<()> {
         sender: email_sender.clone(),
     };
 
+    let dedupe = |queue| Dedupe::new(queue, &configuration.dedupe, connection_pool.clone());
+    let product_query_dedupe = dedupe(JobQueue::ProductQuery);
+    let domain_qualifier_dedupe = dedupe(JobQueue::DomainQualifier);
+    let founder_query_dedupe = dedupe(JobQueue::FounderQuery);
+    let email_dedupe = dedupe(JobQueue::EmailVerifier);
+
     // Spawn backgound tasks
+    tokio::spawn(learn_email_patterns_from_verified(connection_pool.clone()));
+
+    // Pipeline stages run until shutdown, the supervisor starts them again when they crash
+    let supervisor = Supervisor::new(&configuration.supervisor);
+
+    let search_engines_clone = search_engines.clone();
     let pers_data_clone = persistant_data_sender.clone();
-    tokio::spawn(async move {
+    let pool_clone = connection_pool.clone();
+    supervisor.spawn("domain-scraper", move || {
         domain_scraper_handler(
-            product_query_receiver,
-            domain_qualifier_sender,
-            pers_data_clone,
+            product_query_receiver.resubscribe(),
+            product_query_dedupe.clone(),
+            domain_qualifier_sender.clone(),
+            pers_data_clone.clone(),
+            search_engines_clone.clone(),
+            pool_clone.clone(),
         )
-        .await
     });
 
     let sent_clone = sentinel.clone();
     let pers_data_clone = persistant_data_sender.clone();
     let fou_q_clone = founder_query_sender.clone();
-    tokio::spawn(async move {
+    let pool_clone = connection_pool.clone();
+    let qualification_ttl =
+        Duration::from_secs(configuration.domain_qualification.ttl_hours * 60 * 60);
+    supervisor.spawn("domain-qualifier", move || {
         domain_qualifier_handler(
-            sent_clone,
-            doomain_qualifier_receiver,
-            fou_q_clone,
-            pers_data_clone,
+            sent_clone.clone(),
+            doomain_qualifier_receiver.resubscribe(),
+            domain_qualifier_dedupe.clone(),
+            fou_q_clone.clone(),
+            pers_data_clone.clone(),
+            pool_clone.clone(),
+            qualification_ttl,
         )
-        .await
     });
 
     let pers_data_clone = persistant_data_sender.clone();
-    tokio::spawn(async move {
-        founder_scraper_handler(founder_query_receiver, email_sender, pers_data_clone).await
+    let search_engines_clone = search_engines.clone();
+    let email_sender_clone = email_sender.clone();
+    let pool_clone = connection_pool.clone();
+    supervisor.spawn("founder-scraper", move || {
+        founder_scraper_handler(
+            founder_query_receiver.resubscribe(),
+            founder_query_dedupe.clone(),
+            email_sender_clone.clone(),
+            pers_data_clone.clone(),
+            search_engines_clone.clone(),
+            pool_clone.clone(),
+        )
     });
 
     let sent_clone = sentinel.clone();
     let pers_data_clone = persistant_data_sender.clone();
-    tokio::spawn(async move {
+    let pool_clone = connection_pool.clone();
+    supervisor.spawn("email-verifier", move || {
         email_verified_handler(
-            sent_clone,
-            email_receiver,
-            pers_data_clone,
-            verified_email_sender,
+            sent_clone.clone(),
+            email_receiver.resubscribe(),
+            email_dedupe.clone(),
+            pers_data_clone.clone(),
+            verified_email_sender.clone(),
+            email_sender.clone(),
+            pool_clone.clone(),
         )
-        .await
     });
 
     let pool_clone = connection_pool.clone();
-    let pers_data_clone = persistant_data_sender.clone();
-    tokio::spawn(async move {
-        data_persistance_handler(persistant_data_receiver, pers_data_clone, pool_clone).await
+    let persistance_settings = configuration.data_persistance.clone();
+    let persistance_handler = supervisor.spawn("data-persistance", move || {
+        data_persistance_handler(
+            persistant_data_receiver.resubscribe(),
+            pool_clone.clone(),
+            persistance_settings.clone(),
+        )
     });
 
     let pool_clone = connection_pool.clone();
-    tokio::spawn(async move {
-        smart_scout_scraper_handler(pool_clone, founder_query_sender, persistant_data_sender).await
+    supervisor.spawn("run-completion", move || {
+        run_completion_handler(pool_clone.clone())
     });
 
+    if configuration.smart_scout.enabled {
+        let pool_clone = connection_pool.clone();
+        let search_engines_clone = search_engines.clone();
+        let founder_query_sender = founder_query_sender.with_lane(JobLane::Backfill);
+        let smart_scout_settings = configuration.smart_scout.clone();
+        supervisor.spawn("smart-scout-scraper", move || {
+            smart_scout_scraper_handler(
+                pool_clone.clone(),
+                founder_query_sender.clone(),
+                persistant_data_sender.clone(),
+                search_engines_clone.clone(),
+                smart_scout_settings.clone(),
+            )
+        });
+    }
+
+    ensure_admin(&connection_pool, &configuration.auth)
+        .await
+        .expect("Failed to add admin.");
+
+    let shutdown_timeout = Duration::from_secs(configuration.application.shutdown_timeout_secs);
+
+    // Returns once the server stopped on SIGINT or SIGTERM, routes take no new work from here
     run(
         listener,
-        connection_pool,
-        openai_client,
-        sentinel,
-        product_query_sender,
-        verified_email_receiver,
-        email_verifier_sender,
+        AppState {
+            db_pool: connection_pool,
+            openai_client,
+            sentinel,
+            product_query_sender,
+            verified_email_receiver,
+            email_verifier_sender,
+            search_engines,
+            proxy_pool,
+            supervisor: supervisor.clone(),
+            auth_settings: configuration.auth,
+        },
     )?
-    .await
+    .await?;
+
+    log::info!(
+        "Server stopped, giving the pipeline {:?} to finish",
+        shutdown_timeout
+    );
+    let deadline = Instant::now() + shutdown_timeout;
+
+    supervisor.stop();
+    stage_shutdown.trigger();
+    for monitor in stage_monitors.iter() {
+        monitor.drain(deadline).await;
+    }
+
+    persistance_shutdown.trigger();
+    match tokio::time::timeout_at(deadline.into(), persistance_handler).await {
+        Ok(_) => log::info!("Flushed persistant data"),
+        Err(_) => log::warn!("Persistant data was not flushed before the deadline"),
+    }
+    persistance_monitor.drain(deadline).await;
+
+    Ok(())
 }
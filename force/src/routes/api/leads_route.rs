@@ -0,0 +1,289 @@
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+
+use crate::{
+    dal::email_db::{self, LeadFilter, LeadRecord, LeadSort, SortOrder},
+    domain::email::VerificationStatus,
+    services::{charge_lead_usage, ApiKey},
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(Deserialize)]
+struct GetLeadsQuery {
+    niche: Option<String>,
+    status: Option<VerificationStatus>,
+    domain: Option<String>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    sort: LeadSort,
+    #[serde(default)]
+    order: SortOrder,
+    cursor: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct LeadsPage {
+    data: Vec<LeadRecord>,
+    /// Pass as `cursor` to get the next page, none on the last page
+    next_cursor: Option<i64>,
+}
+
+#[get("/leads")]
+async fn get_leads(
+    pool: web::Data<PgPool>,
+    query: web::Query<GetLeadsQuery>,
+    api_key: ApiKey,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE)
+        .min(api_key.remaining_leads());
+
+    let filter = LeadFilter {
+        niche: query.niche.map(|n| n.trim().to_lowercase()),
+        status: query.status,
+        domain: query.domain,
+        created_after: query.created_after,
+        created_before: query.created_before,
+        sort: query.sort,
+        order: query.order,
+        cursor: query.cursor,
+        // One more than asked to know if there is a next page
+        limit: limit + 1,
+    };
+
+    match email_db::get_leads(&pool, &filter).await {
+        Ok(mut leads) => {
+            let next_cursor = match leads.len() as i64 > limit {
+                true => {
+                    leads.truncate(limit as usize);
+                    leads.last().map(|lead| lead.id)
+                }
+                false => None,
+            };
+
+            // Paging over leads the key already got again doesn't charge for them twice
+            let verified_leads: Vec<String> = leads
+                .iter()
+                .filter(|lead| lead.verification_status == VerificationStatus::Verified)
+                .map(|lead| lead.email.clone())
+                .collect();
+            let charged = match charge_lead_usage(&pool, &api_key, None, verified_leads).await {
+                Ok(charged) => charged,
+                Err(e) => {
+                    log::error!("Error while charging leads: {:?}", e);
+                    return HttpResponse::InternalServerError()
+                        .json(json!({"error": "Could not charge leads"}));
+                }
+            };
+            // Verified leads past the quota, like when requests of the key raced, are left out
+            leads.retain(|lead| {
+                lead.verification_status != VerificationStatus::Verified
+                    || charged.contains(&lead.email)
+            });
+
+            HttpResponse::Ok().json(LeadsPage {
+                data: leads,
+                next_cursor,
+            })
+        }
+        Err(e) => {
+            log::error!("Error while getting leads: {:?}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "Could not get leads"}))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, middleware::from_fn, test, web, App};
+    use serde_json::Value;
+    use sqlx::PgPool;
+
+    use crate::{
+        dal::{data_extract_db, email_db, google_webpage_db, niche_db},
+        domain::{
+            data_extract::DataExtract,
+            email::{Email, Reachability, VerificationStatus},
+            google_webpage::{DataExtractionIntent, GoogleWebPage},
+            html_tag::HtmlTag,
+        },
+        services::{issue_api_key, require_api_key, ApiKeyLimiter, API_KEY_HEADER},
+    };
+
+    use super::get_leads;
+
+    async fn seed(pool: &PgPool) {
+        niche_db::insert_niche(pool, "bottles", "prompt", vec!["water bottles".to_string()])
+            .await
+            .unwrap();
+
+        let mut con = pool.acquire().await.unwrap();
+        let web_page_id = google_webpage_db::insert_web_page(
+            &mut con,
+            GoogleWebPage {
+                search_query: "water bottles".to_string(),
+                page_source: "".to_string(),
+                page_number: 1,
+                data_extraction_intent: DataExtractionIntent::Domain,
+                any_result: true,
+            },
+        )
+        .await
+        .unwrap();
+        data_extract_db::insert_html_tags_with_data(
+            &mut con,
+            web_page_id,
+            vec![(
+                HtmlTag::ATag("https://verywellfit.com".to_string()),
+                Some(DataExtract::Domain("verywellfit.com".to_string())),
+            )],
+        )
+        .await
+        .unwrap();
+
+        for (address, domain) in [
+            ("dan@verywellfit.com", "verywellfit.com"),
+            ("dan.go@verywellfit.com", "verywellfit.com"),
+            ("d.go@verywellfit.com", "verywellfit.com"),
+            ("dan@cups.com", "cups.com"),
+        ] {
+            email_db::insert_email(
+                &mut con,
+                Email {
+                    email_address: address.to_string(),
+                    founder_name: "Dan Go".to_string(),
+                    domain: domain.to_string(),
+                    verification_status: VerificationStatus::Pending,
+                    reachability: Reachability::Unknown,
+                    run_id: None,
+                },
+            )
+            .await
+            .unwrap();
+        }
+        email_db::update_email_verified(&mut con, "dan.go@verywellfit.com".to_string())
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn leads_are_paged_with_a_cursor(pool: PgPool) {
+        seed(&pool).await;
+        let key = issue_api_key(&pool, "tests", 1000, 60).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(ApiKeyLimiter::default()))
+                .service(
+                    web::scope("/api/v1")
+                        .wrap(from_fn(require_api_key))
+                        .service(get_leads),
+                ),
+        )
+        .await;
+
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header((API_KEY_HEADER, key.as_str()))
+                .to_request()
+        };
+        let emails = |page: &Value| -> Vec<String> {
+            page["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|lead| lead["email"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let first: Value = test::call_and_read_body_json(
+            &app,
+            get("/api/v1/leads?niche=Bottles&sort=email&order=asc&limit=2"),
+        )
+        .await;
+        assert_eq!(
+            emails(&first),
+            vec!["d.go@verywellfit.com", "dan.go@verywellfit.com"]
+        );
+        assert_eq!(first["data"][1]["product"], "water bottles");
+        assert_eq!(first["data"][1]["verification_status"], "Verified");
+
+        let cursor = first["next_cursor"].as_i64().unwrap();
+        let second: Value = test::call_and_read_body_json(
+            &app,
+            get(&format!(
+                "/api/v1/leads?niche=bottles&sort=email&order=asc&limit=2&cursor={}",
+                cursor
+            )),
+        )
+        .await;
+        assert_eq!(emails(&second), vec!["dan@verywellfit.com"]);
+        assert!(second["next_cursor"].is_null());
+
+        let verified: Value =
+            test::call_and_read_body_json(&app, get("/api/v1/leads?status=Verified")).await;
+        assert_eq!(emails(&verified), vec!["dan.go@verywellfit.com"]);
+
+        let other_domain: Value =
+            test::call_and_read_body_json(&app, get("/api/v1/leads?domain=cups.com")).await;
+        assert_eq!(emails(&other_domain), vec!["dan@cups.com"]);
+        assert!(other_domain["data"][0]["niche"].is_null());
+    }
+
+    #[sqlx::test]
+    async fn verified_leads_count_against_the_keys_quota(pool: PgPool) {
+        seed(&pool).await;
+        let key = issue_api_key(&pool, "tests", 2, 60).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(ApiKeyLimiter::default()))
+                .service(
+                    web::scope("/api/v1")
+                        .wrap(from_fn(require_api_key))
+                        .service(get_leads),
+                ),
+        )
+        .await;
+
+        let get = |key: &str| {
+            test::TestRequest::get()
+                .uri("/api/v1/leads?status=Verified")
+                .insert_header(("Authorization", format!("Bearer {}", key)))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, get("smmac_unknown")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Fetching the same verified lead again doesn't charge it twice
+        for _ in 0..2 {
+            let page: Value = test::call_and_read_body_json(&app, get(&key)).await;
+            assert_eq!(page["data"].as_array().unwrap().len(), 1);
+        }
+        let leads_used: i64 = sqlx::query_scalar("select count(*) from api_key_lead")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(leads_used, 1);
+
+        // Used up once the quota is reached
+        sqlx::query("update api_key set monthly_lead_quota = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let response = test::call_service(&app, get(&key)).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod leads_route;
+pub mod runs_route;
@@ -0,0 +1,493 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use actix_web::{get, http::header, post, web, web::Bytes, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use tokio::{sync::broadcast, time::Interval};
+use uuid::Uuid;
+
+use crate::{
+    dal::{
+        email_db, job_db,
+        run_db::{self, RunRow},
+    },
+    domain::run::RunStatus,
+    services::{
+        charge_lead_usage, start_run, ApiKey, OpenaiClient, ProductQuerySender, VerifiedEmail,
+        VerifiedEmailReceiver,
+    },
+};
+
+/// Results returned for runs without a target count
+const DEFAULT_RESULT_COUNT: i64 = 100;
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+struct CreateRunBody {
+    niche: String,
+    count: Option<i32>,
+}
+
+#[post("/runs")]
+async fn create_run(
+    pool: web::Data<PgPool>,
+    openai_client: web::Data<OpenaiClient>,
+    product_query_sender: web::Data<ProductQuerySender>,
+    body: web::Json<CreateRunBody>,
+    api_key: ApiKey,
+) -> HttpResponse {
+    let niche = body.niche.trim().to_lowercase();
+    if niche.is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "Niche can not be empty"}));
+    }
+    if body.count.is_some_and(|count| count < 1) {
+        return HttpResponse::BadRequest().json(json!({"error": "Count should be > 0"}));
+    }
+
+    // A run looks for no more leads than the key has left, without a count it uses them all
+    let remaining_leads = api_key.remaining_leads();
+    let target_count = body
+        .count
+        .map(i64::from)
+        .unwrap_or(remaining_leads)
+        .min(remaining_leads) as i32;
+
+    let run_id = match run_db::insert_run(&pool, &niche, Some(target_count), Some(api_key.id)).await
+    {
+        Ok(run_id) => run_id,
+        Err(e) => {
+            log::error!("Error while creating run for niche {}: {:?}", niche, e);
+            return HttpResponse::InternalServerError()
+                .json(json!({"error": "Could not create run"}));
+        }
+    };
+
+    tokio::spawn(start_run(
+        pool.get_ref().clone(),
+        openai_client,
+        product_query_sender.sender.clone(),
+        run_id,
+        niche,
+    ));
+
+    HttpResponse::Accepted().json(json!({
+        "run_id": run_id,
+        "status": RunStatus::Running,
+    }))
+}
+
+#[derive(Clone, PartialEq, Serialize)]
+struct StageProgress {
+    products_generated: i32,
+    queries_scraped: i32,
+    domains_qualified: i32,
+    founders_found: i32,
+    emails_verified: i32,
+}
+
+impl From<&RunRow> for StageProgress {
+    fn from(run: &RunRow) -> Self {
+        StageProgress {
+            products_generated: run.products_generated,
+            queries_scraped: run.queries_scraped,
+            domains_qualified: run.domains_qualified,
+            founders_found: run.founders_found,
+            emails_verified: run.emails_verified,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RunResponse {
+    id: Uuid,
+    niche: String,
+    status: RunStatus,
+    target_count: Option<i32>,
+    error: Option<String>,
+    progress: StageProgress,
+    /// Jobs of the run still waiting or being worked on, by queue
+    pending_jobs: HashMap<&'static str, i64>,
+    /// Verified emails of the run, including ones earlier runs verified that it came across
+    emails: Vec<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    completed_at: Option<DateTime<Utc>>,
+}
+
+/// Run of the key, other keys' runs are not found
+async fn get_key_run(
+    pool: &PgPool,
+    run_id: Uuid,
+    api_key: &ApiKey,
+) -> Result<Option<RunRow>, sqlx::Error> {
+    let run = run_db::get_run(pool, run_id).await?;
+
+    Ok(run.filter(|run| run.api_key_id == Some(api_key.id)))
+}
+
+/// The run with its pending jobs, runs are completed by the pipeline and not here
+async fn load_run(
+    pool: &PgPool,
+    run_id: Uuid,
+    api_key: &ApiKey,
+) -> Result<Option<(RunRow, HashMap<&'static str, i64>)>, sqlx::Error> {
+    let Some(run) = get_key_run(pool, run_id, api_key).await? else {
+        return Ok(None);
+    };
+
+    let pending_jobs: HashMap<&'static str, i64> = job_db::count_run_jobs(pool, run_id)
+        .await?
+        .into_iter()
+        .map(|(queue, count)| (queue.config_name(), count))
+        .collect();
+
+    Ok(Some((run, pending_jobs)))
+}
+
+#[get("/runs/{run_id}")]
+async fn get_run(pool: web::Data<PgPool>, path: web::Path<Uuid>, api_key: ApiKey) -> HttpResponse {
+    let run_id = path.into_inner();
+
+    let result = async {
+        let Some((run, pending_jobs)) = load_run(&pool, run_id, &api_key).await? else {
+            return Ok(None);
+        };
+
+        let result_count = run
+            .target_count
+            .map(i64::from)
+            .unwrap_or(DEFAULT_RESULT_COUNT);
+        let emails = email_db::get_verified_emails_for_run(&pool, run_id, result_count).await?;
+        let emails = charge_lead_usage(&pool, &api_key, Some(run_id), emails).await?;
+
+        Ok::<_, sqlx::Error>(Some(RunResponse {
+            id: run.id,
+            progress: StageProgress::from(&run),
+            niche: run.niche,
+            status: run.status,
+            target_count: run.target_count,
+            error: run.error,
+            pending_jobs,
+            emails,
+            created_at: run.created_at,
+            updated_at: run.updated_at,
+            completed_at: run.completed_at,
+        }))
+    }
+    .await;
+
+    match result {
+        Ok(Some(run)) => HttpResponse::Ok().json(run),
+        Ok(None) => HttpResponse::NotFound().json(json!({"error": "Run not found"})),
+        Err(e) => {
+            log::error!("Error while getting run {}: {:?}", run_id, e);
+            HttpResponse::InternalServerError().json(json!({"error": "Could not get run"}))
+        }
+    }
+}
+
+/// Server-sent events of a run: a `lead` for every email verified for it, `progress` when
+/// a stage did more work and `done` once the run finished, after which the stream ends.
+/// An `error` ends the stream early, like when the key's lead quota is used up.
+#[get("/runs/{run_id}/events")]
+async fn stream_run_events(
+    pool: web::Data<PgPool>,
+    verified_email_receiver: web::Data<VerifiedEmailReceiver>,
+    path: web::Path<Uuid>,
+    api_key: ApiKey,
+) -> HttpResponse {
+    let run_id = path.into_inner();
+
+    // Subscribed before looking up the run so that no lead slips through in between
+    let verified_emails = verified_email_receiver.sender.subscribe();
+    match get_key_run(&pool, run_id, &api_key).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().json(json!({"error": "Run not found"})),
+        Err(e) => {
+            log::error!("Error while getting run {}: {:?}", run_id, e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Could not get run"}));
+        }
+    }
+
+    let events = RunEvents {
+        pool: pool.get_ref().clone(),
+        run_id,
+        api_key,
+        verified_emails,
+        poll: tokio::time::interval(PROGRESS_POLL_INTERVAL),
+        progress: None,
+        last_sent: Instant::now(),
+        finished: false,
+    };
+
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .content_type("text/event-stream")
+        .streaming(stream::unfold(events, next_run_event))
+}
+
+struct RunEvents {
+    pool: PgPool,
+    run_id: Uuid,
+    api_key: ApiKey,
+    verified_emails: broadcast::Receiver<VerifiedEmail>,
+    poll: Interval,
+    progress: Option<StageProgress>,
+    last_sent: Instant,
+    finished: bool,
+}
+
+async fn next_run_event(
+    mut events: RunEvents,
+) -> Option<(Result<Bytes, actix_web::Error>, RunEvents)> {
+    if events.finished {
+        return None;
+    }
+
+    let event = loop {
+        // Leads already sent go out before a poll can finish the stream
+        tokio::select! {
+            biased;
+            received = VerifiedEmailReceiver::next_for_run(&mut events.verified_emails, events.run_id) => match received {
+                Some(email) => {
+                    let charged = charge_lead_usage(
+                        &events.pool,
+                        &events.api_key,
+                        Some(events.run_id),
+                        vec![email],
+                    )
+                    .await;
+                    match charged {
+                        Ok(emails) if !emails.is_empty() => {
+                            break sse_event("lead", &json!({"email": emails[0]}));
+                        }
+                        Ok(_) => {
+                            events.finished = true;
+                            break sse_event("error", &json!({"error": "Monthly lead quota used up"}));
+                        }
+                        Err(e) => {
+                            log::error!("Error while charging leads of run {}: {:?}", events.run_id, e);
+                            events.finished = true;
+                            break sse_event("error", &json!({"error": "Could not charge leads"}));
+                        }
+                    }
+                }
+                // The app is shutting down
+                None => return None,
+            },
+            _ = events.poll.tick() => {
+                match load_run(&events.pool, events.run_id, &events.api_key).await {
+                    Ok(Some((run, _))) => {
+                        let progress = StageProgress::from(&run);
+
+                        if run.status != RunStatus::Running {
+                            events.finished = true;
+                            break sse_event(
+                                "done",
+                                &json!({"status": run.status, "error": run.error, "progress": progress}),
+                            );
+                        }
+                        if events.progress.as_ref() != Some(&progress) {
+                            events.progress = Some(progress.clone());
+                            break sse_event("progress", &progress);
+                        }
+                    }
+                    Ok(None) => return None,
+                    Err(e) => log::error!("Error while polling run {}: {:?}", events.run_id, e),
+                }
+
+                // Comments keep proxies from closing a quiet stream
+                if events.last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+                    break Bytes::from_static(b": keep-alive\n\n");
+                }
+            }
+        }
+    };
+
+    events.last_sent = Instant::now();
+    Some((Ok(event), events))
+}
+
+fn sse_event(name: &str, data: &impl Serialize) -> Bytes {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, middleware::from_fn, test, web, App};
+    use sqlx::PgPool;
+    use tokio::sync::broadcast;
+    use uuid::Uuid;
+
+    use crate::{
+        dal::{email_db, run_db},
+        domain::{
+            email::{Email, Reachability, VerificationStatus},
+            run::RunStatus,
+        },
+        services::{
+            hash_api_key, issue_api_key, require_api_key, ApiKeyLimiter, VerifiedEmail,
+            VerifiedEmailReceiver, API_KEY_HEADER,
+        },
+    };
+
+    use super::{get_run, stream_run_events};
+
+    #[sqlx::test]
+    async fn events_stream_the_runs_leads_until_it_is_done(pool: PgPool) {
+        let key = issue_api_key(&pool, "tests", 100, 60).await.unwrap();
+        let api_key_id: Uuid = sqlx::query_scalar("select id from api_key where key_hash = $1")
+            .bind(hash_api_key(&key))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let run_id = run_db::insert_run(&pool, "bottles", None, Some(api_key_id))
+            .await
+            .unwrap();
+        let (sender, _) = broadcast::channel(10);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(ApiKeyLimiter::default()))
+                .app_data(web::Data::new(VerifiedEmailReceiver {
+                    sender: sender.clone(),
+                }))
+                .service(
+                    web::scope("/api/v1")
+                        .wrap(from_fn(require_api_key))
+                        .service(stream_run_events),
+                ),
+        )
+        .await;
+
+        // Runs of other keys are not found
+        let other_key = issue_api_key(&pool, "other", 100, 60).await.unwrap();
+        let events = |key: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/api/v1/runs/{}/events", run_id))
+                .insert_header((API_KEY_HEADER, key))
+                .to_request()
+        };
+        let response = test::call_service(&app, events(&other_key)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = test::call_service(&app, events(&key)).await;
+        assert!(response.status().is_success());
+
+        for (email, run_id) in [
+            ("dan@cups.com", None),
+            ("dan.go@verywellfit.com", Some(run_id)),
+        ] {
+            sender
+                .send(VerifiedEmail {
+                    email: email.to_string(),
+                    run_id,
+                })
+                .unwrap();
+        }
+        run_db::finish_run(&pool, run_id, RunStatus::Completed, None)
+            .await
+            .unwrap();
+
+        let body = test::read_body(response).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("event: lead\ndata: {\"email\":\"dan.go@verywellfit.com\"}\n\n"));
+        assert!(!body.contains("dan@cups.com"));
+        assert!(body.contains("event: done\ndata: {"));
+        assert!(body.contains("\"status\":\"Completed\""));
+
+        // The streamed lead is on the key's usage, with the run it came from
+        let usage: Vec<(Uuid, String)> =
+            sqlx::query_as("select run_id, email_address from api_key_lead where api_key_id = $1")
+                .bind(api_key_id)
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(usage, vec![(run_id, "dan.go@verywellfit.com".to_string())]);
+    }
+
+    #[sqlx::test]
+    async fn runs_return_only_their_own_leads(pool: PgPool) {
+        let key = issue_api_key(&pool, "tests", 100, 60).await.unwrap();
+        let api_key_id: Uuid = sqlx::query_scalar("select id from api_key where key_hash = $1")
+            .bind(hash_api_key(&key))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let earlier_run = run_db::insert_run(&pool, "fitness", None, Some(api_key_id))
+            .await
+            .unwrap();
+        let run_id = run_db::insert_run(&pool, "fitness", None, Some(api_key_id))
+            .await
+            .unwrap();
+
+        let mut con = pool.acquire().await.unwrap();
+        for (email, run_ids) in [
+            ("dan.go@verywellfit.com", vec![earlier_run, run_id]),
+            ("jo@verywellfit.com", vec![earlier_run]),
+        ] {
+            email_db::insert_email(
+                &mut con,
+                Email {
+                    email_address: email.to_string(),
+                    founder_name: "Dan Go".to_string(),
+                    domain: "verywellfit.com".to_string(),
+                    verification_status: VerificationStatus::Pending,
+                    reachability: Reachability::Unknown,
+                    run_id: Some(earlier_run),
+                },
+            )
+            .await
+            .unwrap();
+            email_db::update_email_verified(&mut con, email.to_string())
+                .await
+                .unwrap();
+            for run_id in run_ids {
+                run_db::insert_run_lead(&mut con, run_id, email)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(ApiKeyLimiter::default()))
+                .service(
+                    web::scope("/api/v1")
+                        .wrap(from_fn(require_api_key))
+                        .service(get_run),
+                ),
+        )
+        .await;
+        let run: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/api/v1/runs/{}", run_id))
+                .insert_header((API_KEY_HEADER, key.as_str()))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(run["emails"], serde_json::json!(["dan.go@verywellfit.com"]));
+
+        // Only the run's lead is charged, not every verified email of the niche
+        let leads: Vec<String> = sqlx::query_scalar(
+            "select email_address from api_key_lead where api_key_id = $1 and run_id = $2",
+        )
+        .bind(api_key_id)
+        .bind(run_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(leads, vec!["dan.go@verywellfit.com"]);
+    }
+}
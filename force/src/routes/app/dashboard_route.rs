@@ -2,10 +2,19 @@ use actix_web::{get, post, web, HttpResponse};
 use askama::Template;
 use serde::Deserialize;
 use sqlx::PgPool;
+use uuid::Uuid;
 
-use crate::dal::{
-    config_db,
-    stat_db::{self, DomainStat, EmailStat, FounderStat},
+use crate::{
+    dal::{
+        api_key_db::{self, ApiKeyUsage},
+        config_db,
+        stat_db::{self, DomainStat, EmailStat, FounderStat},
+        user_db,
+    },
+    domain::user::UserRole,
+    services::{
+        hash_password, issue_api_key, metrics, AuthUser, MetricSummary, ProxyHealth, ProxyPool,
+    },
 };
 
 #[derive(Template)]
@@ -14,12 +23,20 @@ struct DashboardTemplate {
     domain_stats: Vec<DomainStat>,
     founder_stats: Vec<FounderStat>,
     email_stats: Vec<EmailStat>,
+    proxy_health: Vec<ProxyHealth>,
+    metric_summary: Vec<MetricSummary>,
     gpt_prompt: String,
     page_depth: u8,
+    is_admin: bool,
+    api_key_usages: Vec<ApiKeyUsage>,
 }
 
 #[get("/dashboard")]
-async fn dashboard(pool: web::Data<PgPool>) -> HttpResponse {
+async fn dashboard(
+    pool: web::Data<PgPool>,
+    proxy_pool: web::Data<ProxyPool>,
+    user: AuthUser,
+) -> HttpResponse {
     let (left, right) = config_db::get_gippity_prompt(&pool).await.unwrap();
     let gpt_prompt = format!(
         "{} Million $ startups {}",
@@ -36,14 +53,27 @@ async fn dashboard(pool: web::Data<PgPool>) -> HttpResponse {
     let domain_stats = stat_db::get_domain_stats(&pool).await.unwrap_or(vec![]);
     let founder_stats = stat_db::get_founder_stats(&pool).await.unwrap_or(vec![]);
     let email_stats = stat_db::get_email_stats(&pool).await.unwrap_or(vec![]);
+    let proxy_health = proxy_pool.health();
+    metrics().refresh_queue_jobs(&pool).await;
+    let metric_summary = metrics().summary();
+    let api_key_usages = match user.is_admin() {
+        true => api_key_db::get_api_key_usages(&pool)
+            .await
+            .unwrap_or(vec![]),
+        false => vec![],
+    };
 
     HttpResponse::Ok().body(
         DashboardTemplate {
             domain_stats,
             founder_stats,
             email_stats,
+            proxy_health,
+            metric_summary,
             gpt_prompt,
             page_depth,
+            is_admin: user.is_admin(),
+            api_key_usages,
         }
         .render()
         .unwrap(),
@@ -57,7 +87,15 @@ struct SetConfigBody {
 }
 
 #[post("/set-config")]
-async fn set_config(pool: web::Data<PgPool>, body: web::Form<SetConfigBody>) -> HttpResponse {
+async fn set_config(
+    pool: web::Data<PgPool>,
+    body: web::Form<SetConfigBody>,
+    user: AuthUser,
+) -> HttpResponse {
+    if !user.is_admin() {
+        return HttpResponse::Forbidden().body("Only admins can change configuration");
+    }
+
     match body.key.as_str() {
         "chatgpt-products-for-niche-start" => {
             config_db::set_gippity_prompt(Some(&body.value), None, &pool)
@@ -74,8 +112,101 @@ async fn set_config(pool: web::Data<PgPool>, body: web::Form<SetConfigBody>) ->
                 .await
                 .unwrap();
         }
+        key if key.starts_with("queue-capacity-") || key.starts_with("worker-concurrency-") => {
+            let Ok(limit) = body.value.parse::<usize>() else {
+                return HttpResponse::Ok().body(format!("Limit must be a number: {}", body.value));
+            };
+            config_db::set_queue_limit(key, limit, &pool).await.unwrap();
+        }
         _ => return HttpResponse::Ok().body(format!("Setting wrong configuration: {}", body.key)),
     }
 
     HttpResponse::Ok().body("Done!")
 }
+
+#[derive(Deserialize)]
+struct AddUserBody {
+    email: String,
+    password: String,
+    role: UserRole,
+}
+
+#[post("/add-user")]
+async fn add_user(
+    pool: web::Data<PgPool>,
+    body: web::Form<AddUserBody>,
+    user: AuthUser,
+) -> HttpResponse {
+    if !user.is_admin() {
+        return HttpResponse::Forbidden().body("Only admins can add users");
+    }
+
+    let password_hash = hash_password(body.password.clone()).await;
+    match user_db::insert_user(&pool, &body.email, &password_hash, body.role).await {
+        Ok(Some(_)) => HttpResponse::Ok().body("Done!"),
+        Ok(None) => HttpResponse::Ok().body(format!("User already exists: {}", body.email)),
+        Err(e) => {
+            log::error!("Error while adding user {}: {:?}", body.email, e);
+            HttpResponse::InternalServerError().body("Could not add user")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AddApiKeyBody {
+    name: String,
+    monthly_lead_quota: i32,
+    requests_per_minute: i32,
+}
+
+#[post("/add-api-key")]
+async fn add_api_key(
+    pool: web::Data<PgPool>,
+    body: web::Form<AddApiKeyBody>,
+    user: AuthUser,
+) -> HttpResponse {
+    if !user.is_admin() {
+        return HttpResponse::Forbidden().body("Only admins can issue api keys");
+    }
+
+    match issue_api_key(
+        &pool,
+        &body.name,
+        body.monthly_lead_quota,
+        body.requests_per_minute,
+    )
+    .await
+    {
+        Ok(key) => {
+            HttpResponse::Ok().body(format!("Copy the key now, it is not shown again: {}", key))
+        }
+        Err(e) => {
+            log::error!("Error while issuing api key {}: {:?}", body.name, e);
+            HttpResponse::InternalServerError().body("Could not issue api key")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RevokeApiKeyBody {
+    id: Uuid,
+}
+
+#[post("/revoke-api-key")]
+async fn revoke_api_key(
+    pool: web::Data<PgPool>,
+    body: web::Form<RevokeApiKeyBody>,
+    user: AuthUser,
+) -> HttpResponse {
+    if !user.is_admin() {
+        return HttpResponse::Forbidden().body("Only admins can revoke api keys");
+    }
+
+    match api_key_db::revoke_api_key(&pool, body.id).await {
+        Ok(_) => HttpResponse::Ok().body("Revoked"),
+        Err(e) => {
+            log::error!("Error while revoking api key {}: {:?}", body.id, e);
+            HttpResponse::InternalServerError().body("Could not revoke api key")
+        }
+    }
+}
@@ -1,11 +1,193 @@
-use actix_web::{get, HttpResponse};
+use actix_web::{cookie::Cookie, get, post, web, HttpRequest, HttpResponse};
 use askama::Template;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::{
+    configuration::AuthSettings,
+    dal::user_db,
+    services::{authenticate, create_session, SESSION_COOKIE},
+};
 
 #[derive(Template)]
 #[template(path = "login.html")]
-struct LoginTemplate {}
+struct LoginTemplate {
+    error: Option<String>,
+}
 
 #[get("/login")]
 async fn login() -> HttpResponse {
-    HttpResponse::Ok().body(LoginTemplate {}.render().unwrap())
+    HttpResponse::Ok().body(LoginTemplate { error: None }.render().unwrap())
+}
+
+#[derive(Deserialize)]
+struct LoginBody {
+    email: String,
+    password: String,
+}
+
+#[post("/login")]
+async fn authenticate_user(
+    pool: web::Data<PgPool>,
+    auth_settings: web::Data<AuthSettings>,
+    body: web::Form<LoginBody>,
+) -> HttpResponse {
+    let user = match authenticate(&pool, &body.email, &body.password).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            let error = Some("Wrong email or password".to_string());
+            return HttpResponse::Ok().body(LoginTemplate { error }.render().unwrap());
+        }
+        Err(e) => {
+            log::error!("Error while logging in {}: {:?}", body.email, e);
+            let error = Some("Could not log in, try again".to_string());
+            return HttpResponse::Ok().body(LoginTemplate { error }.render().unwrap());
+        }
+    };
+
+    match create_session(&pool, user.id, &auth_settings).await {
+        Ok(cookie) => HttpResponse::Ok()
+            .cookie(cookie)
+            .insert_header(("HX-Redirect", "/app/dashboard"))
+            .finish(),
+        Err(e) => {
+            log::error!("Error while creating session of {}: {:?}", user.email, e);
+            let error = Some("Could not log in, try again".to_string());
+            HttpResponse::Ok().body(LoginTemplate { error }.render().unwrap())
+        }
+    }
+}
+
+#[post("/logout")]
+async fn logout(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
+    let mut response = HttpResponse::Ok()
+        .insert_header(("HX-Redirect", "/app/login"))
+        .finish();
+
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        if let Err(e) = user_db::delete_session(&pool, cookie.value()).await {
+            log::error!("Error while deleting session: {:?}", e);
+        }
+        // Removed on the path it was set on, the request's copy has none
+        let cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+        if let Err(e) = response.add_removal_cookie(&cookie) {
+            log::error!("Error while removing session cookie: {:?}", e);
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        middleware::from_fn,
+        test, web, App,
+    };
+    use sqlx::PgPool;
+
+    use crate::{
+        configuration::AuthSettings,
+        dal::user_db,
+        domain::user::UserRole,
+        routes::dashboard_route::set_config,
+        services::{hash_password, require_login, SESSION_COOKIE},
+    };
+
+    use super::{authenticate_user, logout};
+
+    #[sqlx::test]
+    async fn config_needs_an_admin_session(pool: PgPool) {
+        for (email, role) in [
+            ("admin@smmac.com", UserRole::Admin),
+            ("member@smmac.com", UserRole::Member),
+        ] {
+            let password_hash = hash_password("hunter22".to_string()).await;
+            user_db::insert_user(&pool, email, &password_hash, role)
+                .await
+                .unwrap();
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(AuthSettings {
+                    session_ttl_hours: 1,
+                    secure_cookie: false,
+                    admin_email: None,
+                    admin_password: None,
+                }))
+                .service(
+                    web::scope("/app")
+                        .service(authenticate_user)
+                        .service(logout)
+                        .service(
+                            web::scope("")
+                                .wrap(from_fn(require_login))
+                                .service(set_config),
+                        ),
+                ),
+        )
+        .await;
+
+        let set_page_depth = || {
+            test::TestRequest::post()
+                .uri("/app/set-config")
+                .set_form([("key", "google-search-domain-page-depth"), ("value", "2")])
+        };
+        let login = |email: &'static str, password: &'static str| {
+            test::TestRequest::post()
+                .uri("/app/login")
+                .set_form([("email", email), ("password", password)])
+                .to_request()
+        };
+
+        // Pages send the browser to log in, other requests are refused
+        let response = test::call_service(
+            &app,
+            set_page_depth()
+                .insert_header((header::ACCEPT, "text/html"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers().get(header::LOCATION).unwrap(),
+            "/app/login"
+        );
+        let response = test::call_service(&app, set_page_depth().to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = test::call_service(&app, login("admin@smmac.com", "wrong")).await;
+        assert!(response.response().cookies().next().is_none());
+
+        let response = test::call_service(&app, login("member@smmac.com", "hunter22")).await;
+        let member_cookie = response.response().cookies().next().unwrap().into_owned();
+        let response =
+            test::call_service(&app, set_page_depth().cookie(member_cookie).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = test::call_service(&app, login("Admin@smmac.com", "hunter22")).await;
+        let admin_cookie = response.response().cookies().next().unwrap().into_owned();
+        assert_eq!(admin_cookie.name(), SESSION_COOKIE);
+        let body = test::call_and_read_body(
+            &app,
+            set_page_depth().cookie(admin_cookie.clone()).to_request(),
+        )
+        .await;
+        assert_eq!(body, "Done!");
+
+        // The session is gone after logging out, even if the cookie is sent again
+        test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/app/logout")
+                .cookie(admin_cookie.clone())
+                .to_request(),
+        )
+        .await;
+        let response =
+            test::call_service(&app, set_page_depth().cookie(admin_cookie).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
 }
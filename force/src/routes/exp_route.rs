@@ -1,4 +1,4 @@
-use actix_web::{get, web, HttpResponse};
+use actix_web::{get, post, web, HttpResponse};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -6,24 +6,39 @@ use sqlx::PgPool;
 use uuid::Uuid;
 
 use crate::{
-    dal::lead_db::{EmailReachability, EmailVerifiedStatus},
-    domain::email::{FounderDomainEmail, Reachability, VerificationStatus},
+    dal::{
+        lead_db::{EmailReachability, EmailVerifiedStatus},
+        reextraction_db, smtp_attempt_db,
+    },
+    domain::{
+        data_extract::EXTRACTOR_VERSION,
+        email::{FounderDomainEmail, Reachability, VerificationStatus},
+        google_webpage::DataExtractionIntent,
+        job::JobLane,
+    },
     routes::lead_route::build_company_name_search_query,
     services::{
-        extract_data_from_google_search_with_reqwest, EmailVerifierSender, GoogleSearchResult,
-        GoogleSearchType, ProductQuerySender, Sentinel,
+        extract_data_from_google_search_with_reqwest, reextract, EmailVerifierSender,
+        GoogleSearchResult, GoogleSearchType, ProductQueryChannelData, ProductQuerySender,
+        SearchEngines, Sentinel,
     },
 };
 
 #[get("/check-channel-works")]
 async fn check_channel_works(domain_scraper_sender: web::Data<ProductQuerySender>) -> HttpResponse {
     let domain_scraper_sender = domain_scraper_sender.sender.clone();
-    ["pro 1", "pro 2", "pro 999"].iter().for_each(|q| {
-        match domain_scraper_sender.send(q.to_string()) {
+    for q in ["pro 1", "pro 2", "pro 999"] {
+        match domain_scraper_sender
+            .send(ProductQueryChannelData {
+                query: q.to_string(),
+                run_id: None,
+            })
+            .await
+        {
             Ok(_) => {}
             Err(e) => log::error!("Found error while sending: {:?}", e),
         }
-    });
+    }
 
     HttpResponse::Ok().body("Done")
 }
@@ -454,7 +469,10 @@ async fn verify_emails(
                 founder_name: em.founder_name,
                 domain: em.domain,
                 email: em.email_address,
+                run_id: None,
+                fallback_emails: vec![],
             })
+            .await
             .unwrap();
     }
 
@@ -490,7 +508,10 @@ async fn verify_emails_custom(
                 founder_name: em.founder_name,
                 domain: em.domain,
                 email: em.email_address,
+                run_id: None,
+                fallback_emails: vec![],
             })
+            .await
             .unwrap();
     }
 
@@ -523,7 +544,7 @@ async fn verify_emails_hardcoded(sentinel: web::Data<Sentinel>) -> HttpResponse
     ];
 
     for em in emails {
-        sentinel.verify_email_manual(&em).await;
+        sentinel.verify(&em).await;
     }
 
     HttpResponse::Ok().body("Done!")
@@ -555,7 +576,10 @@ async fn verify_emails_smart_scout(
                 founder_name: em.founder_name,
                 domain: em.domain,
                 email: em.email_address,
+                run_id: None,
+                fallback_emails: vec![],
             })
+            .await
             .unwrap();
     }
 
@@ -563,12 +587,15 @@ async fn verify_emails_smart_scout(
 }
 
 #[get("/check-proxy-works")]
-async fn check_proxy_works() -> HttpResponse {
+async fn check_proxy_works(search_engines: web::Data<SearchEngines>) -> HttpResponse {
     let query = build_company_name_search_query("AnkerDirect");
 
-    let google_search_result =
-        extract_data_from_google_search_with_reqwest(query.clone(), GoogleSearchType::CompanyName)
-            .await;
+    let google_search_result = extract_data_from_google_search_with_reqwest(
+        &search_engines,
+        query.clone(),
+        GoogleSearchType::CompanyName,
+    )
+    .await;
 
     match google_search_result {
         GoogleSearchResult::CompanyNames {
@@ -581,3 +608,81 @@ async fn check_proxy_works() -> HttpResponse {
         _ => HttpResponse::Ok().body("Not suitable search result"),
     }
 }
+
+#[derive(Deserialize)]
+struct ReextractQuery {
+    intent: DataExtractionIntent,
+    regenerate_emails: Option<bool>,
+}
+
+#[post("/re-extract")]
+async fn start_reextraction(
+    pool: web::Data<PgPool>,
+    email_verifier_sender: web::Data<EmailVerifierSender>,
+    query: web::Query<ReextractQuery>,
+) -> HttpResponse {
+    if query.intent == DataExtractionIntent::CompanyName {
+        return HttpResponse::BadRequest().body("Company names can not be re-extracted");
+    }
+    let regenerate_emails = query.regenerate_emails.unwrap_or(false);
+
+    let reextraction_id = match reextraction_db::insert_reextraction(
+        &pool,
+        EXTRACTOR_VERSION,
+        query.intent,
+        regenerate_emails,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => {
+            log::error!("Error while creating re-extraction: {:?}", e);
+            return HttpResponse::InternalServerError().body("Could not create re-extraction");
+        }
+    };
+
+    tokio::spawn(reextract(
+        pool.get_ref().clone(),
+        reextraction_id,
+        query.intent,
+        regenerate_emails,
+        email_verifier_sender
+            .sender
+            .clone()
+            .with_lane(JobLane::Backfill),
+    ));
+
+    HttpResponse::Ok().json(json!({"reextraction_id": reextraction_id}))
+}
+
+#[get("/re-extract/{reextraction_id}")]
+async fn get_reextraction(pool: web::Data<PgPool>, path: web::Path<i64>) -> HttpResponse {
+    match reextraction_db::get_reextraction(&pool, path.into_inner()).await {
+        Ok(Some(reextraction)) => HttpResponse::Ok().json(reextraction),
+        Ok(None) => HttpResponse::NotFound().body("Re-extraction not found"),
+        Err(e) => {
+            log::error!("Error while fetching re-extraction: {:?}", e);
+            HttpResponse::InternalServerError().body("Could not fetch re-extraction")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SmtpAttemptQuery {
+    email: String,
+}
+
+/// Smtp transcripts of every verification attempt of an email, oldest first
+#[get("/smtp-attempts")]
+async fn get_smtp_attempts(
+    pool: web::Data<PgPool>,
+    query: web::Query<SmtpAttemptQuery>,
+) -> HttpResponse {
+    match smtp_attempt_db::get_smtp_attempts(&pool, &query.email).await {
+        Ok(attempts) => HttpResponse::Ok().json(attempts),
+        Err(e) => {
+            log::error!("Error while fetching smtp attempts: {:?}", e);
+            HttpResponse::InternalServerError().body("Could not fetch smtp attempts")
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use actix_web::{get, web::Data, HttpResponse, Responder};
+use serde_json::json;
+
+use crate::services::{StageStatus, Supervisor};
+
+/// Liveness of the pipeline stages, unavailable while any of them is restarting
+#[get("/health")]
+async fn health(supervisor: Data<Supervisor>) -> impl Responder {
+    let stages = supervisor.health();
+    let healthy = stages
+        .iter()
+        .all(|stage| stage.status == StageStatus::Running);
+
+    let body = json!({
+        "status": match healthy {
+            true => "ok",
+            false => "degraded",
+        },
+        "stages": stages,
+    });
+    match healthy {
+        true => HttpResponse::Ok().json(body),
+        false => HttpResponse::ServiceUnavailable().json(body),
+    }
+}
@@ -1,5 +1,4 @@
 use actix_web::{get, web, HttpResponse};
-use check_if_email_exists::Reachable;
 use serde::Deserialize;
 use sqlx::{Acquire, PgPool};
 use tokio::task::JoinSet;
@@ -8,16 +7,17 @@ use crate::{
     dal::{
         config_db, google_webpage_db, html_tag_db,
         lead_db::{self, EmailReachability, EmailVerifiedStatus},
-        niche_db,
+        niche_db, run_db,
     },
     domain::{
-        email::construct_email_permutations,
+        email::{construct_email_permutations, VerificationStatus},
         google_webpage::{DataExtractionIntent, GoogleWebPage},
         html_tag::{extract_domain, extract_founder_name, HtmlTag},
     },
     services::{
-        extract_data_from_google_search_with_reqwest, save_product_search_queries,
-        GoogleSearchResult, GoogleSearchType, OpenaiClient, ProductQuerySender, Sentinel,
+        charge_lead_usage, extract_data_from_google_search_with_reqwest,
+        save_product_search_queries, ApiKey, GoogleSearchResult, GoogleSearchType, OpenaiClient,
+        ProductQueryChannelData, ProductQuerySender, SearchEngines, Sentinel,
     },
 };
 
@@ -34,7 +34,6 @@ pub const BLACK_LIST_DOMAINS: [&str; 7] = [
 #[derive(Deserialize)]
 struct GetLeadsFromNicheQuery {
     niche: String,
-    // requester_email: String,
 }
 
 #[get("")]
@@ -44,9 +43,11 @@ async fn get_leads_from_niche(
     pool: web::Data<PgPool>,
     sentinel: web::Data<Sentinel>,
     product_query_sender: web::Data<ProductQuerySender>,
+    search_engines: web::Data<SearchEngines>,
+    api_key: Option<ApiKey>,
 ) -> HttpResponse {
     /*
-    1. (v2) User verification and free tier count
+    1. Api key and its monthly lead quota, or the session, are checked by `require_api_key_or_login`
     2. Get boolean search list from openai using the niche prompt
     3. Perform web scraping on each boolean search page, store results in db
         3.1 (v2) Rotate ips if getting blocked from google
@@ -57,6 +58,15 @@ async fn get_leads_from_niche(
 
     let niche = body.niche.trim().to_lowercase();
 
+    let api_key_id = api_key.as_ref().map(|api_key| api_key.id);
+    let run_id = match run_db::insert_run(&pool, &niche, None, api_key_id).await {
+        Ok(run_id) => run_id,
+        Err(e) => {
+            log::error!("Error while creating run for niche {}: {:?}", niche, e);
+            return HttpResponse::InternalServerError().body("Could not create run");
+        }
+    };
+
     save_product_search_queries(&pool, &openai_client, &niche).await;
 
     let niche_obj = niche_db::get_niche(&pool, &niche).await.unwrap();
@@ -70,9 +80,17 @@ async fn get_leads_from_niche(
         .unwrap();
 
     let product_query_sender = product_query_sender.sender.clone();
-    product_queries
-        .iter()
-        .for_each(|q| product_query_sender.send(q.to_string()).unwrap());
+    for q in product_queries.iter() {
+        if let Err(e) = product_query_sender
+            .send(ProductQueryChannelData {
+                query: q.to_string(),
+                run_id: Some(run_id),
+            })
+            .await
+        {
+            log::error!("Error while queueing product query {}: {:?}", q, e);
+        }
+    }
 
     let page_depth = config_db::get_google_search_page_depth(&pool)
         .await
@@ -81,7 +99,7 @@ async fn get_leads_from_niche(
         .parse()
         .unwrap_or(1);
 
-    save_urls_from_google_searche_batch(&pool, product_queries, page_depth).await;
+    save_urls_from_google_searche_batch(&pool, &search_engines, product_queries, page_depth).await;
 
     let domains_result = lead_db::get_domains_for_niche(&niche, &pool).await;
     if let Err(error) = domains_result {
@@ -109,7 +127,7 @@ async fn get_leads_from_niche(
         &niche
     );
 
-    save_founders_from_google_searches_batch(&pool, domains.clone()).await;
+    save_founders_from_google_searches_batch(&pool, &search_engines, domains.clone()).await;
 
     construct_emails(&pool, domains).await;
 
@@ -142,9 +160,24 @@ async fn get_leads_from_niche(
                 let valid_emails: Vec<String> = verified_emails
                     .into_iter()
                     .filter(|e| !catch_all_emails.contains(e))
+                    .take(match api_key {
+                        Some(ref api_key) => api_key.remaining_leads() as usize,
+                        None => usize::MAX,
+                    })
                     .collect();
-
-                HttpResponse::Ok().json(valid_emails)
+                let charged = match api_key {
+                    Some(ref api_key) => {
+                        charge_lead_usage(&pool, api_key, Some(run_id), valid_emails).await
+                    }
+                    None => Ok(valid_emails),
+                };
+                match charged {
+                    Ok(valid_emails) => HttpResponse::Ok().json(valid_emails),
+                    Err(e) => {
+                        log::error!("Error while charging leads of run {}: {:?}", run_id, e);
+                        HttpResponse::InternalServerError().body("Could not charge leads")
+                    }
+                }
             }
         },
         Err(e) => {
@@ -156,6 +189,7 @@ async fn get_leads_from_niche(
 
 async fn save_urls_from_google_searche_batch(
     pool: &PgPool,
+    search_engines: &web::Data<SearchEngines>,
     search_queries: Vec<String>,
     page_depth: u8,
 ) {
@@ -166,6 +200,7 @@ async fn save_urls_from_google_searche_batch(
 
         for query in batch {
             let query = query.clone();
+            let search_engines = search_engines.clone();
 
             set.spawn(async move {
                 // Fetch domain urls for url, if exist don't search
@@ -177,6 +212,7 @@ async fn save_urls_from_google_searche_batch(
 
                 for current_page_index in 0..page_depth {
                     let google_search_result = extract_data_from_google_search_with_reqwest(
+                        &search_engines,
                         query.clone(),
                         GoogleSearchType::Domain(current_url.clone()),
                     )
@@ -277,7 +313,11 @@ pub enum FounderThreadResult {
     Ignore,
 }
 
-async fn save_founders_from_google_searches_batch(pool: &PgPool, domains: Vec<String>) {
+async fn save_founders_from_google_searches_batch(
+    pool: &PgPool,
+    search_engines: &web::Data<SearchEngines>,
+    domains: Vec<String>,
+) {
     const BATCH_SIZE: usize = 1000;
 
     let mut domain_queries = Vec::new();
@@ -294,9 +334,11 @@ async fn save_founders_from_google_searches_batch(pool: &PgPool, domains: Vec<St
         for (domain, query) in batch {
             let domain = domain.clone();
             let query = query.clone();
+            let search_engines = search_engines.clone();
 
             set.spawn(async move {
                 let google_search_result = extract_data_from_google_search_with_reqwest(
+                    &search_engines,
                     query.to_string(),
                     GoogleSearchType::Founder(domain.to_string()),
                 )
@@ -375,6 +417,14 @@ pub fn build_founder_seach_queries(domain: &str) -> Vec<String> {
         .collect()
 }
 
+/// Inverse of `build_founder_seach_queries`, gets the domain back from a founder query
+pub fn parse_founder_search_query(query: &str) -> Option<String> {
+    query
+        .strip_prefix(r#"site:linkedin.com ""#)
+        .and_then(|rest| rest.split_once(r#"" AND ""#))
+        .map(|(domain, _)| domain.to_string())
+}
+
 pub fn build_company_name_search_query(name: &str) -> String {
     name.to_lowercase()
 }
@@ -423,12 +473,12 @@ async fn verify_emails(pool: &PgPool, sentinel: web::Data<Sentinel>, emails: Vec
             let em = em.clone();
 
             set.spawn(async move {
-                let reachable = sentinel.get_email_verification_status(&em).await;
-                let status = match reachable {
-                    Reachable::Safe => EmailVerifiedStatus::Verified,
+                let outcome = sentinel.verify(&em).await;
+                let status = match outcome.status {
+                    VerificationStatus::Verified => EmailVerifiedStatus::Verified,
                     _ => EmailVerifiedStatus::Invalid,
                 };
-                let reachable: EmailReachability = reachable.into();
+                let reachable: EmailReachability = outcome.reachability.into();
 
                 (em, status, reachable)
             });
@@ -455,9 +505,23 @@ mod tests {
             email::construct_email_permutations,
             html_tag::{extract_domain, HtmlTag},
         },
-        routes::lead_route::{extract_founder_name, FounderTagCandidate},
+        routes::lead_route::{
+            build_founder_seach_queries, extract_founder_name, parse_founder_search_query,
+            FounderTagCandidate,
+        },
     };
 
+    #[test]
+    fn parse_founder_search_query_valid() {
+        for query in build_founder_seach_queries("ZNaturalFoods.com") {
+            assert_eq!(
+                parse_founder_search_query(&query),
+                Some("znaturalfoods.com".to_string())
+            );
+        }
+        assert_eq!(parse_founder_search_query("organic green tea"), None);
+    }
+
     #[test]
     fn get_domain_from_url_valid() {
         let raw_urls = [
@@ -692,12 +756,36 @@ mod tests {
             "dan.go@verywellfit.com".to_string(),
             "dang@verywellfit.com".to_string(),
             "dgo@verywellfit.com".to_string(),
+            "helene@verywellfit.com".to_string(),
+            "detroostembergh@verywellfit.com".to_string(),
+            "helenedetroostembergh@verywellfit.com".to_string(),
+            "helene.detroostembergh@verywellfit.com".to_string(),
+            "helened@verywellfit.com".to_string(),
+            "hdetroostembergh@verywellfit.com".to_string(),
             "samina@verywellfit.com".to_string(),
             "qureshi@verywellfit.com".to_string(),
             "saminaqureshi@verywellfit.com".to_string(),
             "samina.qureshi@verywellfit.com".to_string(),
             "saminaq@verywellfit.com".to_string(),
             "squreshi@verywellfit.com".to_string(),
+            "veer@verywellfit.com".to_string(),
+            "gupta@verywellfit.com".to_string(),
+            "veergupta@verywellfit.com".to_string(),
+            "veer.gupta@verywellfit.com".to_string(),
+            "veerg@verywellfit.com".to_string(),
+            "vgupta@verywellfit.com".to_string(),
+            "deepak@verywellfit.com".to_string(),
+            "bhatt@verywellfit.com".to_string(),
+            "deepakbhatt@verywellfit.com".to_string(),
+            "deepak.bhatt@verywellfit.com".to_string(),
+            "deepakb@verywellfit.com".to_string(),
+            "dbhatt@verywellfit.com".to_string(),
+            "west@verywellfit.com".to_string(),
+            "shell@verywellfit.com".to_string(),
+            "westshell@verywellfit.com".to_string(),
+            "west.shell@verywellfit.com".to_string(),
+            "wests@verywellfit.com".to_string(),
+            "wshell@verywellfit.com".to_string(),
         ];
 
         let mut results: Vec<String> = vec![];
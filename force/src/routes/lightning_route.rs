@@ -1,12 +1,21 @@
+use std::time::Duration;
+
 use actix_web::{get, web, HttpResponse};
 use serde::Deserialize;
 use sqlx::PgPool;
+use tokio::time::Instant;
 
-use crate::dal::google_webpage_db;
+use crate::dal::run_db;
 use crate::routes::lead_route;
-use crate::services::{save_product_search_queries, ProductQuerySender};
+use crate::services::{
+    charge_lead_usage, demote_run, save_product_search_queries, ApiKey, ProductQueryChannelData,
+    ProductQuerySender,
+};
 use crate::services::{OpenaiClient, VerifiedEmailReceiver};
 
+/// Longest a request waits on its run, it gets the leads verified by then
+const MAX_WAIT: Duration = Duration::from_secs(5 * 60);
+
 #[derive(Deserialize)]
 struct GetLightningLeadsQuery {
     niche: String,
@@ -20,41 +29,92 @@ async fn get_lightning_leads(
     pool: web::Data<PgPool>,
     product_query_sender: web::Data<ProductQuerySender>,
     verified_email_receiver: web::Data<VerifiedEmailReceiver>,
+    api_key: Option<ApiKey>,
 ) -> HttpResponse {
     let niche = query.niche.trim().to_lowercase();
     if query.count < 1 {
         return HttpResponse::Ok().body("Count should be > 0");
     }
+    // Leads past the key's quota are not looked for, logged in users have no quota
+    let count = match api_key {
+        Some(ref api_key) => query.count.min(api_key.remaining_leads()),
+        None => query.count,
+    };
+    let api_key_id = api_key.as_ref().map(|api_key| api_key.id);
+
+    let run_id = match run_db::insert_run(&pool, &niche, Some(count as i32), api_key_id).await {
+        Ok(run_id) => run_id,
+        Err(e) => {
+            log::error!("Error while creating run for niche {}: {:?}", niche, e);
+            return HttpResponse::InternalServerError().body("Could not create run");
+        }
+    };
 
     // INFO: This channel will now start receiving emails
     let mut verified_email_receiver = verified_email_receiver.sender.subscribe();
 
     let products = save_product_search_queries(&pool, &openai_client, &niche).await;
 
-    let queries: Vec<String> = products
+    // Queries scraped before are queued too, the stages reuse what they stored for them
+    let product_queries: Vec<String> = products
         .into_iter()
         .map(|p| lead_route::build_seach_query(&p))
         .collect();
-    let product_queries = google_webpage_db::filter_unscraped_product_queries(&pool, queries)
-        .await
-        .unwrap();
 
     let product_query_sender = product_query_sender.sender.clone();
-    product_queries
-        .iter()
-        .for_each(|q| product_query_sender.send(q.to_string()).unwrap());
+    for q in product_queries.iter() {
+        if let Err(e) = product_query_sender
+            .send(ProductQueryChannelData {
+                query: q.to_string(),
+                run_id: Some(run_id),
+            })
+            .await
+        {
+            log::error!("Error while queueing product query {}: {:?}", q, e);
+        }
+    }
 
     let mut emails = Vec::new();
+    let deadline = Instant::now() + MAX_WAIT;
 
-    // TODO: Receive only values for your niche input
-    while let Ok(em) = verified_email_receiver.recv().await {
-        emails.push(em);
+    loop {
+        let received = tokio::time::timeout_at(
+            deadline,
+            VerifiedEmailReceiver::next_for_run(&mut verified_email_receiver, run_id),
+        )
+        .await;
+        let Ok(received) = received else {
+            log::warn!(
+                "Run {} verified {} of {} emails in time",
+                run_id,
+                emails.len(),
+                count
+            );
+            break;
+        };
+        let Some(email) = received else {
+            break;
+        };
+        emails.push(email);
 
-        if emails.len() == query.count as usize {
-            // TODO: Inform worker threads to de prioritize the work initiated by this route
+        if emails.len() == count as usize {
+            // The rest of the run still finishes, just behind other interactive runs
+            if let Err(e) = demote_run(&pool, run_id).await {
+                log::error!("Error while demoting run {}: {:?}", run_id, e);
+            }
             break;
         }
     }
 
-    HttpResponse::Ok().json(emails)
+    let charged = match api_key {
+        Some(ref api_key) => charge_lead_usage(&pool, api_key, Some(run_id), emails).await,
+        None => Ok(emails),
+    };
+    match charged {
+        Ok(emails) => HttpResponse::Ok().json(emails),
+        Err(e) => {
+            log::error!("Error while charging leads of run {}: {:?}", run_id, e);
+            HttpResponse::InternalServerError().body("Could not charge leads")
+        }
+    }
 }
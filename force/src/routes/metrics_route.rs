@@ -0,0 +1,13 @@
+use actix_web::{get, web, HttpResponse, Responder};
+use sqlx::PgPool;
+
+use crate::services::metrics;
+
+#[get("/metrics")]
+async fn prometheus_metrics(pool: web::Data<PgPool>) -> impl Responder {
+    metrics().refresh_queue_jobs(&pool).await;
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics().render())
+}
@@ -1,8 +1,12 @@
+pub mod api;
 pub mod app;
 pub mod default_route;
 // pub mod experiment_route;
 pub mod exp_route;
+pub mod health_route;
 pub mod lead_route;
 pub mod lightning_route;
+pub mod metrics_route;
 
+pub use api::*;
 pub use app::*;
@@ -0,0 +1,397 @@
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::ErrorUnauthorized,
+    http::header,
+    middleware::Next,
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::dal::api_key_db;
+
+use super::require_login;
+
+pub const API_KEY_PREFIX: &str = "smmac_";
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Key of the request, only available behind `require_api_key` or `require_api_key_or_login`
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub monthly_lead_quota: i64,
+    pub leads_used: i64,
+}
+
+impl ApiKey {
+    /// Verified leads the key can still get this month
+    pub fn remaining_leads(&self) -> i64 {
+        (self.monthly_lead_quota - self.leads_used).max(0)
+    }
+}
+
+impl FromRequest for ApiKey {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<ApiKey>()
+                .cloned()
+                .ok_or_else(|| ErrorUnauthorized("Missing api key")),
+        )
+    }
+}
+
+/// A new key, only its hash is stored so it is shown once
+pub fn generate_api_key() -> String {
+    format!(
+        "{}{}{}",
+        API_KEY_PREFIX,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    )
+}
+
+/// Keys are random enough that a fast hash can't be brute forced, unlike passwords
+pub fn hash_api_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Issues a key, returns it in plain text
+pub async fn issue_api_key(
+    pool: &PgPool,
+    name: &str,
+    monthly_lead_quota: i32,
+    requests_per_minute: i32,
+) -> Result<String, sqlx::Error> {
+    let key = generate_api_key();
+    let key_prefix = &key[..API_KEY_PREFIX.len() + 6];
+
+    api_key_db::insert_api_key(
+        pool,
+        name,
+        key_prefix,
+        &hash_api_key(&key),
+        monthly_lead_quota,
+        requests_per_minute,
+    )
+    .await?;
+
+    Ok(key)
+}
+
+/// Charges the key for the verified leads of a response, the response only hands out the
+/// returned leads
+pub async fn charge_lead_usage(
+    pool: &PgPool,
+    api_key: &ApiKey,
+    run_id: Option<Uuid>,
+    emails: Vec<String>,
+) -> Result<Vec<String>, sqlx::Error> {
+    if emails.is_empty() {
+        return Ok(emails);
+    }
+
+    api_key_db::charge_leads(pool, api_key.id, run_id, &emails).await
+}
+
+/// Requests each key made in the current minute
+#[derive(Default)]
+pub struct ApiKeyLimiter {
+    windows: Mutex<HashMap<Uuid, (Instant, u32)>>,
+}
+
+impl ApiKeyLimiter {
+    /// Counts a request of the key, false once the key used up the requests of this minute
+    pub fn try_request(&self, api_key_id: Uuid, requests_per_minute: u32) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        let (started_at, requests) = windows.entry(api_key_id).or_insert((Instant::now(), 0));
+
+        if started_at.elapsed() >= Duration::from_secs(60) {
+            *started_at = Instant::now();
+            *requests = 0;
+        }
+        if *requests >= requests_per_minute {
+            return false;
+        }
+
+        *requests += 1;
+        true
+    }
+}
+
+/// Middleware that lets requests through with a valid key in `Authorization: Bearer` or
+/// `X-Api-Key`, as long as the key is under its rate limit and has leads left this month
+pub async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(key) = api_key_from_headers(req.request()) else {
+        return Ok(reject(req, HttpResponse::Unauthorized(), "Missing api key"));
+    };
+    let (Some(pool), Some(limiter)) = (
+        req.app_data::<web::Data<PgPool>>().cloned(),
+        req.app_data::<web::Data<ApiKeyLimiter>>().cloned(),
+    ) else {
+        log::error!("Api key middleware is missing the pool or the limiter");
+        return Ok(reject(
+            req,
+            HttpResponse::InternalServerError(),
+            "Could not check api key",
+        ));
+    };
+
+    let api_key = match api_key_db::get_active_api_key(&pool, &hash_api_key(&key)).await {
+        Ok(Some(api_key)) => api_key,
+        Ok(None) => return Ok(reject(req, HttpResponse::Unauthorized(), "Invalid api key")),
+        Err(e) => {
+            log::error!("Error while getting api key: {:?}", e);
+            return Ok(reject(
+                req,
+                HttpResponse::InternalServerError(),
+                "Could not check api key",
+            ));
+        }
+    };
+
+    if !limiter.try_request(api_key.id, api_key.requests_per_minute.max(0) as u32) {
+        return Ok(reject(
+            req,
+            HttpResponse::TooManyRequests(),
+            "Rate limit reached, try again in a minute",
+        ));
+    }
+    if api_key.leads_used >= api_key.monthly_lead_quota as i64 {
+        return Ok(reject(
+            req,
+            HttpResponse::TooManyRequests(),
+            "Monthly lead quota used up",
+        ));
+    }
+
+    req.extensions_mut().insert(ApiKey {
+        id: api_key.id,
+        name: api_key.name,
+        monthly_lead_quota: api_key.monthly_lead_quota as i64,
+        leads_used: api_key.leads_used,
+    });
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
+
+/// Middleware for routes the dashboard's users call as well. Requests with a key are checked
+/// like `require_api_key` does, requests without one need a session like `require_login`.
+/// Leads handed to a session aren't charged to any key.
+pub async fn require_api_key_or_login(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    match api_key_from_headers(req.request()) {
+        Some(_) => require_api_key(req, next).await,
+        None => require_login(req, next).await,
+    }
+}
+
+fn api_key_from_headers(req: &HttpRequest) -> Option<String> {
+    let headers = req.headers();
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let header = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok());
+
+    bearer.or(header).map(|key| key.trim().to_string())
+}
+
+fn reject(
+    req: ServiceRequest,
+    mut response: HttpResponseBuilder,
+    error: &str,
+) -> ServiceResponse<BoxBody> {
+    req.into_response(response.json(json!({ "error": error })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        get,
+        http::StatusCode,
+        middleware::from_fn,
+        test::{call_and_read_body, call_service, init_service, read_body, TestRequest},
+        web, App, HttpResponse,
+    };
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use crate::{
+        configuration::AuthSettings,
+        dal::{api_key_db, user_db},
+        domain::user::UserRole,
+        services::{create_session, hash_password},
+    };
+
+    use super::{
+        charge_lead_usage, hash_api_key, issue_api_key, require_api_key_or_login, ApiKey,
+        ApiKeyLimiter, API_KEY_HEADER,
+    };
+
+    #[test]
+    fn keys_are_limited_separately() {
+        let limiter = ApiKeyLimiter::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert!(limiter.try_request(first, 2));
+        assert!(limiter.try_request(first, 2));
+        assert!(!limiter.try_request(first, 2));
+        assert!(limiter.try_request(second, 2));
+    }
+
+    #[sqlx::test]
+    async fn racing_requests_dont_spend_more_than_the_quota(pool: PgPool) {
+        let key = issue_api_key(&pool, "tests", 3, 60).await.unwrap();
+        let row = api_key_db::get_active_api_key(&pool, &hash_api_key(&key))
+            .await
+            .unwrap()
+            .unwrap();
+        let api_key = ApiKey {
+            id: row.id,
+            name: row.name,
+            monthly_lead_quota: row.monthly_lead_quota as i64,
+            leads_used: row.leads_used,
+        };
+
+        let emails = |prefix: &str| {
+            (0..2)
+                .map(|i| format!("{}{}@verywellfit.com", prefix, i))
+                .collect::<Vec<String>>()
+        };
+        let (first, second) = tokio::join!(
+            charge_lead_usage(&pool, &api_key, None, emails("dan")),
+            charge_lead_usage(&pool, &api_key, None, emails("jo")),
+        );
+        assert_eq!(first.unwrap().len() + second.unwrap().len(), 3);
+
+        // Leads the key got before are handed out again for free
+        let again = charge_lead_usage(&pool, &api_key, None, emails("dan"))
+            .await
+            .unwrap();
+        let charged: Vec<String> = sqlx::query_scalar(
+            "select email_address from api_key_lead where email_address like 'dan%'",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(again.len(), charged.len());
+    }
+
+    #[sqlx::test]
+    async fn leads_of_earlier_months_are_charged_again(pool: PgPool) {
+        let key = issue_api_key(&pool, "tests", 3, 60).await.unwrap();
+        let row = api_key_db::get_active_api_key(&pool, &hash_api_key(&key))
+            .await
+            .unwrap()
+            .unwrap();
+        let api_key = ApiKey {
+            id: row.id,
+            name: row.name,
+            monthly_lead_quota: row.monthly_lead_quota as i64,
+            leads_used: row.leads_used,
+        };
+        let emails = vec!["dan.go@verywellfit.com".to_string()];
+
+        charge_lead_usage(&pool, &api_key, None, emails.clone())
+            .await
+            .unwrap();
+        sqlx::query("update api_key_lead set created_at = now() - interval '2 months'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        charge_lead_usage(&pool, &api_key, None, emails)
+            .await
+            .unwrap();
+
+        let leads_used: i64 = sqlx::query_scalar(
+            "select count(*) from api_key_lead where created_at >= date_trunc('month', now())",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(leads_used, 1);
+    }
+
+    #[get("")]
+    async fn key_name(api_key: Option<ApiKey>) -> HttpResponse {
+        HttpResponse::Ok().body(api_key.map(|api_key| api_key.name).unwrap_or_default())
+    }
+
+    #[sqlx::test]
+    async fn lead_routes_take_a_key_or_a_session(pool: PgPool) {
+        let settings = AuthSettings {
+            session_ttl_hours: 1,
+            secure_cookie: false,
+            admin_email: None,
+            admin_password: None,
+        };
+        let password_hash = hash_password("hunter22".to_string()).await;
+        let user_id =
+            user_db::insert_user(&pool, "member@smmac.com", &password_hash, UserRole::Member)
+                .await
+                .unwrap()
+                .unwrap();
+        let cookie = create_session(&pool, user_id, &settings).await.unwrap();
+        let key = issue_api_key(&pool, "tests", 3, 60).await.unwrap();
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(ApiKeyLimiter::default()))
+                .service(
+                    web::scope("/lightning")
+                        .wrap(from_fn(require_api_key_or_login))
+                        .service(key_name),
+                ),
+        )
+        .await;
+        let request = || TestRequest::get().uri("/lightning");
+
+        let response = call_service(&app, request().to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let body = call_and_read_body(
+            &app,
+            request().insert_header((API_KEY_HEADER, key)).to_request(),
+        )
+        .await;
+        assert_eq!(body, "tests");
+
+        // Leads of logged in users aren't charged to a key
+        let response = call_service(&app, request().cookie(cookie).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_body(response).await, "");
+
+        let response = call_service(
+            &app,
+            request()
+                .insert_header((API_KEY_HEADER, "smmac_wrong"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,204 @@
+use std::future::{ready, Ready};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    cookie::{time, Cookie, SameSite},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::ErrorUnauthorized,
+    http::header,
+    middleware::Next,
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::Utc;
+use rand::rngs::OsRng;
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    configuration::AuthSettings,
+    dal::user_db::{self, UserRow},
+    domain::user::UserRole,
+};
+
+pub const SESSION_COOKIE: &str = "smmac_session";
+pub const LOGIN_PATH: &str = "/app/login";
+
+/// User of the request's session, only available behind `require_login`
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: Uuid,
+    pub email: String,
+    pub role: UserRole,
+}
+
+impl AuthUser {
+    pub fn is_admin(&self) -> bool {
+        self.role == UserRole::Admin
+    }
+}
+
+impl From<UserRow> for AuthUser {
+    fn from(user: UserRow) -> Self {
+        AuthUser {
+            id: user.id,
+            email: user.email,
+            role: user.role,
+        }
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthUser>()
+                .cloned()
+                .ok_or_else(|| ErrorUnauthorized("Not logged in")),
+        )
+    }
+}
+
+/// Hashing is slow on purpose, so it runs off the async workers
+pub async fn hash_password(password: String) -> String {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .expect("Default argon2 params hash any password")
+            .to_string()
+    })
+    .await
+    .expect("Password hashing panicked")
+}
+
+pub async fn verify_password(password: String, password_hash: String) -> bool {
+    tokio::task::spawn_blocking(move || match PasswordHash::new(&password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            log::error!("Stored password hash is invalid: {:?}", e);
+            false
+        }
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// The user if the email and password match one
+pub async fn authenticate(
+    pool: &PgPool,
+    email: &str,
+    password: &str,
+) -> Result<Option<AuthUser>, sqlx::Error> {
+    let Some(user) = user_db::get_user_by_email(pool, email).await? else {
+        return Ok(None);
+    };
+
+    match verify_password(password.to_string(), user.password_hash.clone()).await {
+        true => Ok(Some(AuthUser::from(user))),
+        false => Ok(None),
+    }
+}
+
+/// Starts a session for the user, the returned cookie carries its token
+pub async fn create_session(
+    pool: &PgPool,
+    user_id: Uuid,
+    settings: &AuthSettings,
+) -> Result<Cookie<'static>, sqlx::Error> {
+    // Expired sessions are only ever read to be turned away, logins clear them out
+    user_db::delete_expired_sessions(pool).await?;
+
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let ttl = chrono::Duration::hours(settings.session_ttl_hours as i64);
+    user_db::insert_session(pool, &token, user_id, Utc::now() + ttl).await?;
+
+    Ok(Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(settings.secure_cookie)
+        .max_age(time::Duration::hours(settings.session_ttl_hours as i64))
+        .finish())
+}
+
+/// Adds the admin from the settings if there is no user with its email yet
+pub async fn ensure_admin(pool: &PgPool, settings: &AuthSettings) -> Result<(), sqlx::Error> {
+    let (Some(email), Some(password)) = (&settings.admin_email, &settings.admin_password) else {
+        return Ok(());
+    };
+
+    if user_db::get_user_by_email(pool, email).await?.is_none() {
+        let password_hash = hash_password(password.clone()).await;
+        user_db::insert_user(pool, email, &password_hash, UserRole::Admin).await?;
+        log::info!("Added admin {}", email);
+    }
+
+    Ok(())
+}
+
+/// Middleware that turns away requests without a live session. Pages send the browser to
+/// the login page, htmx requests get told to go there and anything else gets a 401.
+pub async fn require_login(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let token = req.cookie(SESSION_COOKIE).map(|c| c.value().to_string());
+    let pool = req.app_data::<web::Data<PgPool>>().cloned();
+
+    let user = match (token, pool) {
+        (Some(token), Some(pool)) => match user_db::get_session_user(&pool, &token).await {
+            Ok(user) => user,
+            Err(e) => {
+                log::error!("Error while getting session user: {:?}", e);
+                return Ok(req.into_response(
+                    HttpResponse::InternalServerError()
+                        .json(json!({"error": "Could not check session"})),
+                ));
+            }
+        },
+        _ => None,
+    };
+
+    match user {
+        Some(user) => {
+            req.extensions_mut().insert(AuthUser::from(user));
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_boxed_body)
+        }
+        None => {
+            let response = unauthorized(req.request());
+            Ok(req.into_response(response))
+        }
+    }
+}
+
+fn unauthorized(req: &HttpRequest) -> HttpResponse {
+    if req.headers().contains_key("HX-Request") {
+        return HttpResponse::Unauthorized()
+            .insert_header(("HX-Redirect", LOGIN_PATH))
+            .finish();
+    }
+
+    let wants_page = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    match wants_page {
+        true => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, LOGIN_PATH))
+            .finish(),
+        false => HttpResponse::Unauthorized().json(json!({"error": "Not logged in"})),
+    }
+}
@@ -1,21 +1,34 @@
-use std::{error::Error, time::Duration};
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
 
-use sqlx::{Acquire, PgPool};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, PgConnection, PgPool};
 
 use crate::{
+    configuration::DataPersistanceSettings,
     dal::{
-        data_extract_db, email_db, google_webpage_db, html_tag_db,
+        data_extract_db, domain_qualification_db, email_db, google_webpage_db, run_db,
         smart_scout_db::{self, SmartScoutJobStatus},
+        smtp_attempt_db,
     },
     domain::{
         data_extract::DataExtract,
+        domain_qualification::DomainQualification,
         email::{Email, FounderDomainEmail, Reachability, VerificationStatus},
         google_webpage::{DataExtractionIntent, GoogleWebPage},
         html_tag::HtmlTag,
     },
 };
 
+use super::{
+    learn_email_pattern, metrics, Job, QueueReceiver, RunLead, RunProgress, SmtpVerification,
+};
+
+const BATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Serialize, Deserialize)]
 pub enum PersistantData {
     Domain(DomainData),
     Founder(FounderData),
@@ -23,9 +36,35 @@ pub enum PersistantData {
     Email(FounderDomainEmail),
     UpdateEmailVerified(String),
     UpdateEmailUnverified(String),
+    UpdateEmailCatchAll(String),
     CompleteSmartScoutJob(i64),
+    DomainQualification(DomainQualification),
+    SmtpVerification(SmtpVerification),
+    RunProgress(RunProgress),
+    RunLead(RunLead),
+}
+
+impl PersistantData {
+    /// Label of the message in the db write metrics
+    fn kind(&self) -> &'static str {
+        match self {
+            PersistantData::Domain(_) => "domain",
+            PersistantData::Founder(_) => "founder",
+            PersistantData::CompanyName(_) => "company_name",
+            PersistantData::Email(_) => "email",
+            PersistantData::UpdateEmailVerified(_) => "update_email_verified",
+            PersistantData::UpdateEmailUnverified(_) => "update_email_unverified",
+            PersistantData::UpdateEmailCatchAll(_) => "update_email_catch_all",
+            PersistantData::CompleteSmartScoutJob(_) => "complete_smart_scout_job",
+            PersistantData::DomainQualification(_) => "domain_qualification",
+            PersistantData::SmtpVerification(_) => "smtp_verification",
+            PersistantData::RunProgress(_) => "run_progress",
+            PersistantData::RunLead(_) => "run_lead",
+        }
+    }
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum DomainData {
     Result {
         query: String,
@@ -37,6 +76,7 @@ pub enum DomainData {
 }
 
 // TODO: Combine page data for domain and founder
+#[derive(Serialize, Deserialize)]
 pub struct DomainPageData {
     pub page_source: String,
     pub page_number: u8,
@@ -44,6 +84,7 @@ pub struct DomainPageData {
     pub domains: Vec<Option<String>>,
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum FounderData {
     Result {
         query: String,
@@ -54,6 +95,7 @@ pub enum FounderData {
     },
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum CompanyNameData {
     Result {
         query: String,
@@ -67,6 +109,7 @@ pub enum CompanyNameData {
     },
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct FounderPageData {
     pub page_source: String,
     pub page_number: u8,
@@ -75,217 +118,405 @@ pub struct FounderPageData {
 }
 
 pub async fn data_persistance_handler(
-    mut data_receiver: UnboundedReceiver<PersistantData>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
+    mut data_receiver: QueueReceiver<PersistantData>,
     pool: PgPool,
+    settings: DataPersistanceSettings,
 ) {
     log::info!("Started data persistance handler");
+    let flush_interval = Duration::from_millis(settings.flush_interval_ms);
+
+    while let Some(job) = data_receiver.recv().await {
+        // Messages that arrive until the flush are written in one transaction
+        let flush_at = Instant::now() + flush_interval;
+        let mut batch = vec![job];
+
+        while batch.len() < settings.max_batch_size {
+            match data_receiver.try_recv().await {
+                Some(job) => batch.push(job),
+                None => match flush_at.checked_duration_since(Instant::now()) {
+                    Some(left) => tokio::time::sleep(left.min(BATCH_POLL_INTERVAL)).await,
+                    None => break,
+                },
+            }
+        }
 
-    while let Some(data) = data_receiver.recv().await {
         log::info!(
-            "Data persistance handler has {} elements",
-            data_receiver.len()
+            "Data persistance handler is flushing {} elements",
+            batch.len()
         );
+        flush(&pool, batch).await;
+    }
+}
 
-        // TODO: Make sure that it can live long enough
-        let pool_con_result = pool.acquire().await;
-        if let Err(e) = pool_con_result {
+/// Every message gets a savepoint so that a failing one is rolled back alone and retried,
+/// a page is never persisted without its tags
+async fn flush(pool: &PgPool, batch: Vec<Job<PersistantData>>) {
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
             log::error!("Pool timed out: {:?}", e);
-            tokio::time::sleep(Duration::from_secs(10)).await;
+            for job in batch {
+                job.handle.retry(&e.to_string()).await;
+            }
+            return;
+        }
+    };
+
+    let mut persisted = vec![];
+    let mut failed = vec![];
+    for job in batch {
+        let (data, handle) = job.into_parts();
+        let timer = metrics()
+            .db_write_seconds
+            .with_label_values(&[data.kind()])
+            .start_timer();
+
+        let result = async {
+            let mut savepoint = tx.begin().await?;
+            persist(&mut savepoint, data).await?;
+            savepoint.commit().await
+        }
+        .await;
+        timer.observe_duration();
 
-            if let Err(e) = persistant_data_sender.send(data) {
+        match result {
+            Ok(()) => persisted.push(handle),
+            Err(e) => {
                 log::error!(
-                    "Persistant data sender channel got an Error: {:?} | Source: {:?}",
+                    "Error while persisting data got an Error: {:?} | Source: {:?}",
                     e,
                     e.source(),
                 );
+                failed.push((handle, e.to_string()));
             }
-            continue;
         }
-        let mut pool_con = pool_con_result.unwrap();
-        let con = pool_con.acquire().await.unwrap();
+    }
 
-        match data {
-            PersistantData::Domain(data) => match data {
-                DomainData::NoResult { query } => {
-                    let webpage = GoogleWebPage {
-                        search_query: query.clone(),
-                        page_source: "".to_string(),
-                        page_number: 0,
-                        data_extraction_intent: DataExtractionIntent::Domain,
-                        any_result: false,
-                    };
+    match tx.commit().await {
+        Ok(()) => {
+            for handle in persisted {
+                handle.complete().await;
+            }
+        }
+        Err(e) => {
+            log::error!("Error while committing persistant data: {:?}", e);
+            for handle in persisted {
+                handle.retry(&e.to_string()).await;
+            }
+        }
+    }
+    for (handle, error) in failed {
+        handle.retry(&error).await;
+    }
+}
 
-                    google_webpage_db::insert_web_page(con, webpage)
-                        .await
-                        .unwrap();
-                }
-                DomainData::Result { query, pages_data } => {
-                    for page_data in pages_data {
-                        let webpage = GoogleWebPage {
-                            search_query: query.clone(),
-                            page_source: page_data.page_source,
-                            page_number: page_data.page_number,
-                            data_extraction_intent: DataExtractionIntent::Domain,
-                            any_result: true,
-                        };
-
-                        let web_page_id = google_webpage_db::insert_web_page(con, webpage)
-                            .await
-                            .unwrap();
-
-                        for (i, tag) in page_data.html_tags.into_iter().enumerate() {
-                            let tag_id = html_tag_db::insert_html_tag(con, tag, web_page_id)
-                                .await
-                                .unwrap();
-
-                            if let Some(Some(domain)) = page_data.domains.get(i) {
-                                data_extract_db::insert_data(
-                                    con,
-                                    DataExtract::Domain(domain.to_string()),
-                                    tag_id,
-                                )
-                                .await
-                                .unwrap();
-                            }
-                        }
-                    }
-                }
-            },
-            PersistantData::Founder(data) => match data {
-                FounderData::NoResult { query } => {
-                    let webpage = GoogleWebPage {
-                        search_query: query.clone(),
-                        page_source: "".to_string(),
-                        page_number: 0,
-                        data_extraction_intent: DataExtractionIntent::FounderName,
-                        any_result: false,
-                    };
+async fn persist(con: &mut PgConnection, data: PersistantData) -> Result<(), sqlx::Error> {
+    match data {
+        PersistantData::Domain(data) => match data {
+            DomainData::NoResult { query } => {
+                let webpage = GoogleWebPage {
+                    search_query: query.clone(),
+                    page_source: "".to_string(),
+                    page_number: 0,
+                    data_extraction_intent: DataExtractionIntent::Domain,
+                    any_result: false,
+                };
 
-                    google_webpage_db::insert_web_page(con, webpage)
-                        .await
-                        .unwrap();
-                }
-                FounderData::Result { query, page_data } => {
+                google_webpage_db::insert_web_page(con, webpage).await?;
+            }
+            DomainData::Result { query, pages_data } => {
+                for page_data in pages_data {
                     let webpage = GoogleWebPage {
                         search_query: query.clone(),
                         page_source: page_data.page_source,
                         page_number: page_data.page_number,
-                        data_extraction_intent: DataExtractionIntent::FounderName,
+                        data_extraction_intent: DataExtractionIntent::Domain,
                         any_result: true,
                     };
 
-                    let web_page_id = google_webpage_db::insert_web_page(con, webpage)
-                        .await
-                        .unwrap();
-
-                    for (i, tag) in page_data.html_tags.into_iter().enumerate() {
-                        let tag_id = html_tag_db::insert_html_tag(con, tag, web_page_id)
-                            .await
-                            .unwrap();
-
-                        if let Some(Some(domain)) = page_data.founder_names.get(i) {
-                            data_extract_db::insert_data(
-                                con,
-                                DataExtract::FounderName(domain.to_string()),
-                                tag_id,
-                            )
-                            .await
-                            .unwrap();
-                        }
-                    }
+                    let web_page_id = google_webpage_db::insert_web_page(con, webpage).await?;
+
+                    let tags = page_data
+                        .html_tags
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, tag)| {
+                            let domain = page_data.domains.get(i).cloned().flatten();
+                            (tag, domain.map(DataExtract::Domain))
+                        })
+                        .collect();
+                    data_extract_db::insert_html_tags_with_data(con, web_page_id, tags).await?;
                 }
-            },
-            PersistantData::Email(data) => {
-                let email = Email {
-                    email_address: data.email,
-                    founder_name: data.founder_name,
-                    domain: data.domain,
-                    verification_status: VerificationStatus::Pending,
-                    reachability: Reachability::Unknown,
+            }
+        },
+        PersistantData::Founder(data) => match data {
+            FounderData::NoResult { query } => {
+                let webpage = GoogleWebPage {
+                    search_query: query.clone(),
+                    page_source: "".to_string(),
+                    page_number: 0,
+                    data_extraction_intent: DataExtractionIntent::FounderName,
+                    any_result: false,
                 };
-                if let Err(e) = email_db::insert_email(con, email).await {
-                    match e {
-                        sqlx::Error::Database(ref data) => {
-                            if data.constraint() != Some("idx_unique_email_email_address") {
-                                log::error!("Error inserting email in db: {:?}", e);
-                            }
-                        }
-                        _ => {}
-                    }
-                }
+
+                google_webpage_db::insert_web_page(con, webpage).await?;
             }
-            PersistantData::UpdateEmailVerified(email) => {
-                if let Err(e) = email_db::update_email_verified(con, email).await {
-                    log::error!("Error while persisting email verified status: {:?}", e);
-                }
+            FounderData::Result { query, page_data } => {
+                let webpage = GoogleWebPage {
+                    search_query: query.clone(),
+                    page_source: page_data.page_source,
+                    page_number: page_data.page_number,
+                    data_extraction_intent: DataExtractionIntent::FounderName,
+                    any_result: true,
+                };
+
+                let web_page_id = google_webpage_db::insert_web_page(con, webpage).await?;
+
+                let tags = page_data
+                    .html_tags
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, tag)| {
+                        let founder_name = page_data.founder_names.get(i).cloned().flatten();
+                        (tag, founder_name.map(DataExtract::FounderName))
+                    })
+                    .collect();
+                data_extract_db::insert_html_tags_with_data(con, web_page_id, tags).await?;
             }
-            PersistantData::UpdateEmailUnverified(email) => {
-                if let Err(e) = email_db::update_email_unverified(con, email).await {
-                    log::error!("Error while persisting email unverified status: {:?}", e);
-                }
+        },
+        PersistantData::Email(data) => {
+            let email = Email {
+                email_address: data.email,
+                founder_name: data.founder_name,
+                domain: data.domain,
+                verification_status: VerificationStatus::Pending,
+                reachability: Reachability::Unknown,
+                run_id: data.run_id,
+            };
+            email_db::insert_email_if_new(con, email).await?;
+        }
+        PersistantData::UpdateEmailVerified(email) => {
+            email_db::update_email_verified(con, email.clone()).await?;
+            learn_email_pattern(con, &email).await?;
+        }
+        PersistantData::UpdateEmailUnverified(email) => {
+            email_db::update_email_unverified(con, email).await?;
+        }
+        PersistantData::UpdateEmailCatchAll(email) => {
+            email_db::update_email_catch_all(con, email).await?;
+        }
+        PersistantData::CompleteSmartScoutJob(smart_scout_id) => {
+            smart_scout_db::finish_job(con, smart_scout_id, SmartScoutJobStatus::Completed).await?;
+        }
+        PersistantData::DomainQualification(qualification) => {
+            domain_qualification_db::upsert_domain_qualification(con, &qualification).await?;
+            if qualification.is_catch_all {
+                email_db::update_domain_emails_catch_all(con, &qualification.domain).await?;
             }
-            PersistantData::CompleteSmartScoutJob(smart_scout_id) => {
-                if let Err(e) =
-                    smart_scout_db::finish_job(con, smart_scout_id, SmartScoutJobStatus::Completed)
-                        .await
-                {
-                    log::error!(
-                        "Error while persisting smart scout job completion status: {:?}",
-                        e
-                    );
-                }
+        }
+        PersistantData::SmtpVerification(verification) => {
+            for attempt in verification.attempts.iter() {
+                smtp_attempt_db::insert_smtp_attempt(con, &verification.email, attempt).await?;
             }
-            PersistantData::CompanyName(data) => match data {
-                CompanyNameData::NoResult { query } => {
-                    let webpage = GoogleWebPage {
-                        search_query: query.clone(),
-                        page_source: "".to_string(),
-                        page_number: 0,
-                        data_extraction_intent: DataExtractionIntent::CompanyName,
-                        any_result: false,
-                    };
+        }
+        PersistantData::RunProgress(progress) => {
+            run_db::add_run_progress(con, progress.run_id, progress.stage, progress.count).await?;
+        }
+        PersistantData::RunLead(lead) => {
+            run_db::insert_run_lead(con, lead.run_id, &lead.email).await?;
+        }
+        PersistantData::CompanyName(data) => match data {
+            CompanyNameData::NoResult { query } => {
+                let webpage = GoogleWebPage {
+                    search_query: query.clone(),
+                    page_source: "".to_string(),
+                    page_number: 0,
+                    data_extraction_intent: DataExtractionIntent::CompanyName,
+                    any_result: false,
+                };
 
-                    google_webpage_db::insert_web_page(con, webpage)
-                        .await
-                        .unwrap();
-                }
-                CompanyNameData::Result {
-                    query,
+                google_webpage_db::insert_web_page(con, webpage).await?;
+            }
+            CompanyNameData::Result {
+                query,
+                page_source,
+                page_number,
+                company_name,
+                html_tags,
+            } => {
+                let webpage = GoogleWebPage {
+                    search_query: query.clone(),
                     page_source,
                     page_number,
-                    company_name,
-                    html_tags,
-                } => {
-                    let webpage = GoogleWebPage {
-                        search_query: query.clone(),
-                        page_source,
-                        page_number,
-                        data_extraction_intent: DataExtractionIntent::CompanyName,
-                        any_result: true,
-                    };
+                    data_extraction_intent: DataExtractionIntent::CompanyName,
+                    any_result: true,
+                };
 
-                    let web_page_id = google_webpage_db::insert_web_page(con, webpage)
-                        .await
-                        .unwrap();
-
-                    for (i, tag) in html_tags.into_iter().enumerate() {
-                        let tag_id = html_tag_db::insert_html_tag(con, tag, web_page_id)
-                            .await
-                            .unwrap();
-
-                        if i == 0 {
-                            data_extract_db::insert_data(
-                                con,
-                                DataExtract::CompanyName(company_name.clone()),
-                                tag_id,
-                            )
-                            .await
-                            .unwrap();
-                        }
-                    }
-                }
+                let web_page_id = google_webpage_db::insert_web_page(con, webpage).await?;
+
+                // The company name is extracted from the first tag
+                let tags = html_tags
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, tag)| match i {
+                        0 => (tag, Some(DataExtract::CompanyName(company_name.clone()))),
+                        _ => (tag, None),
+                    })
+                    .collect();
+                data_extract_db::insert_html_tags_with_data(con, web_page_id, tags).await?;
+            }
+        },
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use sqlx::PgPool;
+
+    use crate::{
+        configuration::DataPersistanceSettings,
+        dal::email_db,
+        domain::{
+            domain_qualification::DomainQualification,
+            email::{Email, Reachability, VerificationStatus},
+            html_tag::HtmlTag,
+            job::JobQueue,
+        },
+        services::job_channel,
+    };
+
+    use super::{data_persistance_handler, persist, DomainData, DomainPageData, PersistantData};
+
+    fn page(query: &str, tag: &str) -> PersistantData {
+        PersistantData::Domain(DomainData::Result {
+            query: query.to_string(),
+            pages_data: vec![DomainPageData {
+                page_source: "<html></html>".to_string(),
+                page_number: 1,
+                html_tags: vec![
+                    HtmlTag::ATag(tag.to_string()),
+                    HtmlTag::H3Tag("Contact us".to_string()),
+                ],
+                domains: vec![Some("verywellfit.com".to_string()), None],
+            }],
+        })
+    }
+
+    #[sqlx::test]
+    async fn failing_message_is_retried_without_a_partial_page(pool: PgPool) {
+        // Tags of the second page fail after its web page row is written
+        sqlx::query(
+            "alter table html_tag add constraint no_broken_tags check (text_content <> 'broken')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let (sender, receiver) = job_channel(pool.clone(), JobQueue::PersistantData);
+        sender
+            .send(page("bottles", "https://verywellfit.com"))
+            .await
+            .unwrap();
+        sender.send(page("cups", "broken")).await.unwrap();
+
+        let handler = tokio::spawn(data_persistance_handler(
+            receiver,
+            pool.clone(),
+            DataPersistanceSettings {
+                flush_interval_ms: 200,
+                max_batch_size: 10,
             },
+        ));
+
+        let mut last_error = None;
+        for _ in 0..50 {
+            last_error = sqlx::query_scalar::<_, Option<String>>(
+                "select last_error from job where status = 'PENDING'",
+            )
+            .fetch_optional(&pool)
+            .await
+            .unwrap()
+            .flatten();
+            if last_error.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        handler.abort();
+        assert!(last_error.is_some());
+
+        let queries: Vec<String> =
+            sqlx::query_scalar("select search_query from google_webpage order by id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(queries, vec!["bottles"]);
+
+        let tags: i64 = sqlx::query_scalar("select count(*) from html_tag")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(tags, 2);
+        let domains: Vec<String> = sqlx::query_scalar("select data from data_extract")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(domains, vec!["verywellfit.com"]);
+    }
+
+    #[sqlx::test]
+    async fn catch_all_domains_keep_their_verified_emails(pool: PgPool) {
+        let mut con = pool.acquire().await.unwrap();
+        for email_address in ["dan.go@verywellfit.com", "dan@verywellfit.com"] {
+            let email = Email {
+                email_address: email_address.to_string(),
+                founder_name: "Dan Go".to_string(),
+                domain: "verywellfit.com".to_string(),
+                verification_status: VerificationStatus::Pending,
+                reachability: Reachability::Unknown,
+                run_id: None,
+            };
+            email_db::insert_email(&mut con, email).await.unwrap();
         }
+        email_db::update_email_verified(&mut con, "dan.go@verywellfit.com".to_string())
+            .await
+            .unwrap();
+
+        persist(
+            &mut con,
+            PersistantData::DomainQualification(DomainQualification {
+                domain: "verywellfit.com".to_string(),
+                mx_hosts: vec![],
+                is_catch_all: true,
+                smtp_reachable: true,
+                qualified_at: Utc::now(),
+            }),
+        )
+        .await
+        .unwrap();
+
+        let statuses = sqlx::query_as::<_, (String, VerificationStatus)>(
+            "select email_address, verification_status from email order by email_address",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            statuses,
+            vec![
+                (
+                    "dan.go@verywellfit.com".to_string(),
+                    VerificationStatus::Verified
+                ),
+                (
+                    "dan@verywellfit.com".to_string(),
+                    VerificationStatus::CatchAll
+                ),
+            ]
+        );
     }
 }
@@ -0,0 +1,222 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{configuration::DedupeSettings, dal::dedupe_db, domain::job::JobQueue};
+
+/// How often expired keys are cleared from postgres
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+struct SeenKeys {
+    entries: HashMap<String, (Instant, u64)>,
+    /// Keys by when they were last seen, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// Skips work a stage already handled within the ttl. Keys live in an in-memory lru
+/// and, when persisted, in postgres so that a restart doesn't redo recent work.
+/// Clones share their keys.
+#[derive(Clone)]
+pub struct Dedupe {
+    scope: &'static str,
+    ttl: Duration,
+    max_entries: usize,
+    pool: Option<PgPool>,
+    seen: Arc<Mutex<SeenKeys>>,
+    pruned_at: Arc<Mutex<Instant>>,
+}
+
+impl Dedupe {
+    pub fn new(queue: JobQueue, settings: &DedupeSettings, pool: PgPool) -> Self {
+        Dedupe {
+            scope: queue.config_name(),
+            ttl: Duration::from_secs(settings.ttl_minutes * 60),
+            max_entries: settings.max_entries.max(1),
+            pool: match settings.persist {
+                true => Some(pool),
+                false => None,
+            },
+            seen: Arc::new(Mutex::new(SeenKeys {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            })),
+            pruned_at: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// True when the key was not seen within the ttl, the key counts as seen from now on
+    pub async fn first_seen(&self, key: &str) -> bool {
+        if self.seen_in_memory(key) {
+            return false;
+        }
+
+        if let Some(pool) = &self.pool {
+            match dedupe_db::mark_key_seen(pool, self.scope, key, self.ttl.as_secs_f64()).await {
+                Ok(true) => {}
+                Ok(false) => return false,
+                // Doing work twice is better than dropping it
+                Err(e) => log::error!("Error while marking {} as seen: {:?}", key, e),
+            }
+        }
+
+        self.remember(key);
+        self.prune().await;
+
+        true
+    }
+
+    /// Like `first_seen` but keys of a run are only shared within it, so a run gets its own
+    /// results for work another run already did
+    pub async fn first_seen_in_run(&self, run_id: Option<Uuid>, key: &str) -> bool {
+        match run_id {
+            Some(run_id) => self.first_seen(&format!("{}:{}", run_id, key)).await,
+            None => self.first_seen(key).await,
+        }
+    }
+
+    async fn prune(&self) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        {
+            let mut pruned_at = self.pruned_at.lock().unwrap();
+            if pruned_at.elapsed() < PRUNE_INTERVAL {
+                return;
+            }
+            *pruned_at = Instant::now();
+        }
+
+        if let Err(e) =
+            dedupe_db::delete_expired_keys(pool, self.scope, self.ttl.as_secs_f64()).await
+        {
+            log::error!("Error while deleting expired {} keys: {:?}", self.scope, e);
+        }
+    }
+
+    fn seen_in_memory(&self, key: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        seen.tick += 1;
+        let tick = seen.tick;
+
+        let Some((seen_at, last_tick)) = seen.entries.get(key).copied() else {
+            return false;
+        };
+        seen.order.remove(&last_tick);
+
+        match seen_at.elapsed() < self.ttl {
+            true => {
+                seen.entries.insert(key.to_string(), (seen_at, tick));
+                seen.order.insert(tick, key.to_string());
+                true
+            }
+            false => {
+                seen.entries.remove(key);
+                false
+            }
+        }
+    }
+
+    fn remember(&self, key: &str) {
+        let mut seen = self.seen.lock().unwrap();
+        seen.tick += 1;
+        let tick = seen.tick;
+
+        if let Some((_, last_tick)) = seen.entries.insert(key.to_string(), (Instant::now(), tick)) {
+            seen.order.remove(&last_tick);
+        }
+        seen.order.insert(tick, key.to_string());
+
+        while seen.entries.len() > self.max_entries {
+            let Some((_, oldest)) = seen.order.pop_first() else {
+                break;
+            };
+            seen.entries.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use crate::{configuration::DedupeSettings, domain::job::JobQueue};
+
+    use super::Dedupe;
+
+    fn settings(max_entries: usize, persist: bool) -> DedupeSettings {
+        DedupeSettings {
+            ttl_minutes: 60,
+            max_entries,
+            persist,
+        }
+    }
+
+    #[sqlx::test]
+    async fn keys_expire_after_ttl(pool: PgPool) {
+        let mut dedupe = Dedupe::new(JobQueue::FounderQuery, &settings(10, false), pool);
+        dedupe.ttl = Duration::from_millis(100);
+
+        assert!(dedupe.first_seen("dan go verywellfit.com").await);
+        assert!(!dedupe.first_seen("dan go verywellfit.com").await);
+        assert!(dedupe.first_seen("ceo verywellfit.com").await);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(dedupe.first_seen("dan go verywellfit.com").await);
+    }
+
+    #[sqlx::test]
+    async fn least_recently_seen_keys_are_dropped(pool: PgPool) {
+        let dedupe = Dedupe::new(JobQueue::ProductQuery, &settings(2, false), pool);
+
+        assert!(dedupe.first_seen("bottles").await);
+        assert!(dedupe.first_seen("mugs").await);
+        // Seeing bottles again makes mugs the oldest
+        assert!(!dedupe.first_seen("bottles").await);
+        assert!(dedupe.first_seen("cups").await);
+
+        assert!(!dedupe.first_seen("bottles").await);
+        assert!(dedupe.first_seen("mugs").await);
+    }
+
+    #[sqlx::test]
+    async fn runs_dont_share_keys(pool: PgPool) {
+        let dedupe = Dedupe::new(JobQueue::ProductQuery, &settings(10, false), pool);
+        let (first, second) = (Some(Uuid::new_v4()), Some(Uuid::new_v4()));
+
+        assert!(dedupe.first_seen_in_run(first, "bottles").await);
+        assert!(!dedupe.first_seen_in_run(first, "bottles").await);
+        assert!(dedupe.first_seen_in_run(second, "bottles").await);
+        assert!(dedupe.first_seen_in_run(None, "bottles").await);
+        assert!(!dedupe.first_seen("bottles").await);
+    }
+
+    #[sqlx::test]
+    async fn persisted_keys_survive_restarts(pool: PgPool) {
+        let dedupe = Dedupe::new(JobQueue::EmailVerifier, &settings(10, true), pool.clone());
+        assert!(dedupe.first_seen("dan@verywellfit.com").await);
+
+        let restarted = Dedupe::new(JobQueue::EmailVerifier, &settings(10, true), pool.clone());
+        assert!(!restarted.first_seen("dan@verywellfit.com").await);
+
+        // Scopes don't share keys
+        let other = Dedupe::new(JobQueue::FounderQuery, &settings(10, true), pool.clone());
+        assert!(other.first_seen("dan@verywellfit.com").await);
+
+        sqlx::query("update dedupe_key set seen_at = now() - interval '2 hours'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let restarted = Dedupe::new(JobQueue::EmailVerifier, &settings(10, true), pool);
+        assert!(restarted.first_seen("dan@verywellfit.com").await);
+    }
+}
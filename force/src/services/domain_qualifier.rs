@@ -1,44 +1,62 @@
-use std::collections::HashSet;
+use std::{error::Error, time::Duration};
 
 use actix_web::web::Data;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
 
-use crate::routes::lead_route::build_founder_seach_queries;
+use crate::{
+    dal::domain_qualification_db,
+    domain::{domain_qualification::DomainQualification, run::RunStage},
+    routes::lead_route::build_founder_seach_queries,
+};
 
-use super::{FounderQueryChannelData, PersistantData, Sentinel};
+use super::{
+    record_run_progress, Dedupe, FounderQueryChannelData, JobHandle, PersistantData, QueueReceiver,
+    QueueSender, Sentinel, SmtpVerdict,
+};
 
-const SET_RESET_LEN: usize = 10_000;
+#[derive(Serialize, Deserialize)]
+pub struct DomainQualifierChannelData {
+    pub domain: String,
+    pub run_id: Option<Uuid>,
+}
 
 pub async fn domain_qualifier_handler(
     sentinel: Data<Sentinel>,
-    mut product_query_receiver: UnboundedReceiver<String>,
-    founder_query_sender: UnboundedSender<FounderQueryChannelData>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
+    mut product_query_receiver: QueueReceiver<DomainQualifierChannelData>,
+    dedupe: Dedupe,
+    founder_query_sender: QueueSender<FounderQueryChannelData>,
+    persistant_data_sender: QueueSender<PersistantData>,
+    pool: PgPool,
+    qualification_ttl: Duration,
 ) {
     log::info!("Started domain qualifier");
-    let mut seen_queries = HashSet::new();
 
-    // TODO: Use tokio::select! to check for a signal that asks to move certain tasks from priority queue to backgound
-    while let Some(domain) = product_query_receiver.recv().await {
+    while let Some(job) = product_query_receiver.recv().await {
         log::info!(
             "Domain qualifier handler has {} elements",
             product_query_receiver.len()
         );
+        let (data, handle) = job.into_parts();
 
-        match seen_queries.contains(&domain) {
-            true => {}
+        // Retried jobs were seen before and are back on purpose
+        let is_duplicate =
+            !dedupe.first_seen_in_run(data.run_id, &data.domain).await && handle.attempt() == 1;
+        match is_duplicate {
+            true => handle.complete().await,
             false => {
-                // TODO: Implement time based reset like 10 mins after channel was empty
-                if seen_queries.len() > SET_RESET_LEN {
-                    seen_queries.clear();
-                }
-                seen_queries.insert(domain.clone());
-                tokio::spawn(qualify_domain(
+                let worker = product_query_receiver.acquire_worker().await;
+                tokio::spawn(worker.run(qualify_domain(
                     sentinel.clone(),
-                    domain,
+                    data,
+                    handle,
                     founder_query_sender.clone(),
                     persistant_data_sender.clone(),
-                ));
+                    pool.clone(),
+                    qualification_ttl,
+                )));
             }
         }
     }
@@ -46,26 +64,243 @@ pub async fn domain_qualifier_handler(
 
 async fn qualify_domain(
     sentinel: Data<Sentinel>,
-    domain: String,
-    founder_query_sender: UnboundedSender<FounderQueryChannelData>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
+    data: DomainQualifierChannelData,
+    handle: JobHandle,
+    founder_query_sender: QueueSender<FounderQueryChannelData>,
+    persistant_data_sender: QueueSender<PersistantData>,
+    pool: PgPool,
+    qualification_ttl: Duration,
 ) {
-    log::info!("Qualifying domain: {}", domain);
+    let DomainQualifierChannelData { domain, run_id } = data;
+
+    let cached = match domain_qualification_db::get_domain_qualification(&pool, &domain).await {
+        Ok(cached) => cached.filter(|q| q.is_fresh(qualification_ttl, Utc::now())),
+        Err(e) => {
+            log::error!(
+                "Domain qualification cache got an Error: {:?} | Source: {:?}",
+                e,
+                e.source(),
+            );
+            None
+        }
+    };
+
+    let is_catch_all = match cached {
+        Some(qualification) => {
+            log::info!(
+                "Domain {} was qualified at {}, catch all: {}",
+                domain,
+                qualification.qualified_at,
+                qualification.is_catch_all
+            );
+            qualification.is_catch_all
+        }
+        None => {
+            log::info!("Qualifying domain: {}", domain);
 
-    let email = format!("kdsjfkljrkvj87@{}", domain);
-    let is_catch_all = sentinel.verify_email_manual(email.as_str()).await;
+            let email = format!("kdsjfkljrkvj87@{}", domain);
+            let verification = sentinel.smtp_verifier().verify(email.as_str()).await;
+            let is_catch_all = verification.verdict == SmtpVerdict::Deliverable;
+            let qualification = DomainQualification {
+                domain: domain.clone(),
+                smtp_reachable: verification.smtp_reachable(),
+                mx_hosts: verification.mx_hosts,
+                is_catch_all,
+                qualified_at: Utc::now(),
+            };
+
+            if let Err(e) = persistant_data_sender
+                .send(PersistantData::DomainQualification(qualification))
+                .await
+            {
+                log::error!(
+                    "Persistant data sender channel got an Error: {:?} | Source: {:?}",
+                    e,
+                    e.source(),
+                );
+            }
+            is_catch_all
+        }
+    };
 
     match is_catch_all {
         false => {
             for query in build_founder_seach_queries(&domain) {
-                founder_query_sender
+                if let Err(e) = founder_query_sender
                     .send(FounderQueryChannelData {
                         query,
                         domain: domain.clone(),
+                        run_id,
                     })
+                    .await
+                {
+                    log::error!(
+                        "Founder query queue got an Error: {:?} | Source: {:?}",
+                        e,
+                        e.source(),
+                    );
+                    handle.retry(&e.to_string()).await;
+                    return;
+                }
+            }
+            record_run_progress(
+                &persistant_data_sender,
+                run_id,
+                RunStage::DomainsQualified,
+                1,
+            )
+            .await;
+        }
+        true => log::info!("Skipping founder search for catch all domain: {}", domain),
+    }
+
+    handle.complete().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use sqlx::PgPool;
+
+    use crate::{
+        configuration::{DataPersistanceSettings, DedupeSettings},
+        dal::domain_qualification_db,
+        domain::{domain_qualification::DomainQualification, job::JobQueue},
+        services::{
+            data_persistance_handler, job_channel,
+            mock_smtp::{mock_sentinel, MockSmtpScenario, MockSmtpServer},
+            Dedupe,
+        },
+    };
+
+    use super::{domain_qualifier_handler, DomainQualifierChannelData};
+
+    const TTL: Duration = Duration::from_secs(60 * 60);
+
+    /// Runs the qualifier over `domain` until its jobs are drained,
+    /// returns the number of founder queries it queued
+    async fn qualify(pool: &PgPool, server: &MockSmtpServer, domain: &str) -> i64 {
+        let sentinel = mock_sentinel(domain, server);
+        let (domain_sender, domain_receiver) = job_channel(pool.clone(), JobQueue::DomainQualifier);
+        let (founder_query_sender, _) = job_channel(pool.clone(), JobQueue::FounderQuery);
+        let (persistant_data_sender, persistant_data_receiver) =
+            job_channel(pool.clone(), JobQueue::PersistantData);
+
+        domain_sender
+            .send(DomainQualifierChannelData {
+                domain: domain.to_string(),
+                run_id: None,
+            })
+            .await
+            .unwrap();
+
+        let handlers = [
+            tokio::spawn(domain_qualifier_handler(
+                sentinel,
+                domain_receiver,
+                Dedupe::new(
+                    JobQueue::DomainQualifier,
+                    &DedupeSettings {
+                        ttl_minutes: 60,
+                        max_entries: 100,
+                        persist: false,
+                    },
+                    pool.clone(),
+                ),
+                founder_query_sender,
+                persistant_data_sender,
+                pool.clone(),
+                TTL,
+            )),
+            tokio::spawn(data_persistance_handler(
+                persistant_data_receiver,
+                pool.clone(),
+                DataPersistanceSettings {
+                    flush_interval_ms: 0,
+                    max_batch_size: 10,
+                },
+            )),
+        ];
+
+        for _ in 0..20 {
+            let pending: i64 =
+                sqlx::query_scalar("select count(*) from job where queue <> 'FOUNDER_QUERY'")
+                    .fetch_one(pool)
+                    .await
                     .unwrap();
+            if pending == 0 {
+                break;
             }
+            tokio::time::sleep(Duration::from_millis(500)).await;
         }
-        true => {}
+        handlers.iter().for_each(|handler| handler.abort());
+
+        sqlx::query_scalar("select count(*) from job where queue = 'FOUNDER_QUERY'")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn catch_all_domain_is_persisted_and_skipped(pool: PgPool) {
+        let server = MockSmtpServer::start(MockSmtpScenario::AcceptAll).await;
+
+        let founder_queries = qualify(&pool, &server, "verywellfit.com").await;
+        assert_eq!(founder_queries, 0);
+
+        let qualification =
+            domain_qualification_db::get_domain_qualification(&pool, "verywellfit.com")
+                .await
+                .unwrap()
+                .expect("Qualification was not persisted");
+        assert!(qualification.is_catch_all);
+        assert!(qualification.smtp_reachable);
+        assert_eq!(qualification.mx_hosts, vec![server.mx_host()]);
+    }
+
+    #[sqlx::test]
+    async fn regular_domain_is_searched_for_founders(pool: PgPool) {
+        let server = MockSmtpServer::start(MockSmtpScenario::RejectUnknown(vec![])).await;
+
+        let founder_queries = qualify(&pool, &server, "verywellfit.com").await;
+        assert!(founder_queries > 0);
+
+        let qualification =
+            domain_qualification_db::get_domain_qualification(&pool, "verywellfit.com")
+                .await
+                .unwrap()
+                .expect("Qualification was not persisted");
+        assert!(!qualification.is_catch_all);
+    }
+
+    #[sqlx::test]
+    async fn fresh_qualification_is_not_probed_again(pool: PgPool) {
+        // Would be qualified as catch-all if it were probed
+        let server = MockSmtpServer::start(MockSmtpScenario::AcceptAll).await;
+        let mut con = pool.acquire().await.unwrap();
+        domain_qualification_db::upsert_domain_qualification(
+            &mut con,
+            &DomainQualification {
+                domain: "verywellfit.com".to_string(),
+                mx_hosts: vec![],
+                is_catch_all: false,
+                smtp_reachable: true,
+                qualified_at: Utc::now(),
+            },
+        )
+        .await
+        .unwrap();
+
+        let founder_queries = qualify(&pool, &server, "verywellfit.com").await;
+        assert!(founder_queries > 0);
+
+        let qualification =
+            domain_qualification_db::get_domain_qualification(&pool, "verywellfit.com")
+                .await
+                .unwrap()
+                .unwrap();
+        assert!(!qualification.is_catch_all);
     }
 }
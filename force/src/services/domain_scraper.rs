@@ -1,68 +1,113 @@
-use std::{collections::HashSet, error::Error};
+use std::error::Error;
 
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use actix_web::web::Data;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
 
-use crate::{domain::html_tag::extract_domain, routes::lead_route::BLACK_LIST_DOMAINS};
+use crate::{
+    dal::google_webpage_db,
+    domain::{google_webpage::DataExtractionIntent, html_tag::extract_domain, run::RunStage},
+    routes::lead_route::BLACK_LIST_DOMAINS,
+};
 
 use super::{
-    extract_data_from_google_search_with_reqwest, DomainData, DomainPageData, GoogleSearchResult,
-    GoogleSearchType, PersistantData,
+    extract_data_from_google_search_with_reqwest, metrics, record_run_progress, Dedupe, DomainData,
+    DomainPageData, DomainQualifierChannelData, GoogleSearchResult, GoogleSearchType, JobHandle,
+    PersistantData, QueueReceiver, QueueSender, SearchEngines,
 };
 
 const PAGE_DEPTH: u8 = 1;
-const SET_RESET_LEN: usize = 10_000;
 
 pub struct ProductQuerySender {
-    pub sender: UnboundedSender<String>,
+    pub sender: QueueSender<ProductQueryChannelData>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProductQueryChannelData {
+    pub query: String,
+    pub run_id: Option<Uuid>,
 }
 
 pub async fn domain_scraper_handler(
-    mut product_query_receiver: UnboundedReceiver<String>,
-    domain_qualifier_sender: UnboundedSender<String>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
+    mut product_query_receiver: QueueReceiver<ProductQueryChannelData>,
+    dedupe: Dedupe,
+    domain_qualifier_sender: QueueSender<DomainQualifierChannelData>,
+    persistant_data_sender: QueueSender<PersistantData>,
+    search_engines: Data<SearchEngines>,
+    pool: PgPool,
 ) {
     log::info!("Started domain scraper");
-    let mut seen_queries = HashSet::new();
 
-    // TODO: Use tokio::select! to check for a signal that asks to move certain tasks from priority queue to backgound
-    while let Some(query) = product_query_receiver.recv().await {
+    while let Some(job) = product_query_receiver.recv().await {
         log::info!(
             "Domain scraper handler has {} elements",
             product_query_receiver.len()
         );
+        let (data, handle) = job.into_parts();
 
-        match seen_queries.contains(&query) {
-            true => {}
+        // Retried jobs were seen before and are back on purpose
+        let is_duplicate =
+            !dedupe.first_seen_in_run(data.run_id, &data.query).await && handle.attempt() == 1;
+        match is_duplicate {
+            true => handle.complete().await,
             false => {
-                // TODO: Implement time based reset like 10 mins after channel was empty
-                if seen_queries.len() > SET_RESET_LEN {
-                    seen_queries.clear();
-                }
-                seen_queries.insert(query.clone());
-                tokio::spawn(scrape_domain_query(
-                    query,
+                let worker = product_query_receiver.acquire_worker().await;
+                tokio::spawn(worker.run(scrape_domain_query(
+                    data,
+                    handle,
                     domain_qualifier_sender.clone(),
                     persistant_data_sender.clone(),
-                ));
+                    search_engines.clone(),
+                    pool.clone(),
+                )));
             }
         }
     }
 }
 
 async fn scrape_domain_query(
-    query: String,
-    founder_qualifier_sender: UnboundedSender<String>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
+    data: ProductQueryChannelData,
+    handle: JobHandle,
+    founder_qualifier_sender: QueueSender<DomainQualifierChannelData>,
+    persistant_data_sender: QueueSender<PersistantData>,
+    search_engines: Data<SearchEngines>,
+    pool: PgPool,
 ) {
+    let ProductQueryChannelData { query, run_id } = data;
+
+    // Queries scraped before, like by another run, hand on the domains found back then
+    match google_webpage_db::get_stored_query_data(&pool, &query, DataExtractionIntent::Domain)
+        .await
+    {
+        Ok(Some(domains)) => {
+            log::info!("Reusing {} stored domains of: {}", domains.len(), query);
+            for domain in domains {
+                qualify_domain(&founder_qualifier_sender, domain, run_id).await;
+            }
+            metrics()
+                .queries_scraped
+                .with_label_values(&["domain", "stored"])
+                .inc();
+            record_run_progress(&persistant_data_sender, run_id, RunStage::QueriesScraped, 1).await;
+            handle.complete().await;
+            return;
+        }
+        Ok(None) => {}
+        Err(e) => log::error!("Error while getting stored domains of {}: {:?}", query, e),
+    }
+
     log::info!("Scraping google for domain: {}", query);
 
     let mut current_url = None;
     let mut not_found = false;
+    let mut captcha_blocked = false;
 
     let mut pages_data: Vec<DomainPageData> = vec![];
 
     for current_page_index in 0..PAGE_DEPTH {
         let google_search_result = extract_data_from_google_search_with_reqwest(
+            &search_engines,
             query.clone(),
             GoogleSearchType::Domain(current_url.clone()),
         )
@@ -84,13 +129,7 @@ async fn scrape_domain_query(
             } => {
                 for domain_url in domain_urls.iter() {
                     if let Some(domain) = extract_domain(domain_url.clone()) {
-                        // Remove blacklisted domains
-                        if !BLACK_LIST_DOMAINS
-                            .iter()
-                            .any(|&blacklist| domain.contains(blacklist))
-                        {
-                            founder_qualifier_sender.send(domain.clone()).unwrap();
-                        }
+                        qualify_domain(&founder_qualifier_sender, domain, run_id).await;
                     }
                 }
 
@@ -112,31 +151,269 @@ async fn scrape_domain_query(
             }
             GoogleSearchResult::CaptchaBlocked => {
                 log::error!("Returning from captcha blocked on url {}", query);
+                captcha_blocked = true;
                 break;
             }
         }
     }
 
+    if pages_data.is_empty() && captcha_blocked {
+        metrics()
+            .queries_scraped
+            .with_label_values(&["domain", "captcha"])
+            .inc();
+        handle.retry("Captcha blocked").await;
+        return;
+    }
+
     not_found = pages_data.is_empty() && not_found;
+    record_run_progress(&persistant_data_sender, run_id, RunStage::QueriesScraped, 1).await;
+    metrics()
+        .queries_scraped
+        .with_label_values(&[
+            "domain",
+            match not_found {
+                true => "not_found",
+                false => "found",
+            },
+        ])
+        .inc();
+
+    let data = match not_found {
+        true => PersistantData::Domain(DomainData::NoResult { query }),
+        false => PersistantData::Domain(DomainData::Result { query, pages_data }),
+    };
 
-    if not_found {
-        if let Err(e) =
-            persistant_data_sender.send(PersistantData::Domain(DomainData::NoResult { query }))
-        {
+    match persistant_data_sender.send(data).await {
+        Ok(_) => handle.complete().await,
+        Err(e) => {
             log::error!(
                 "Persistant data sender channel got an Error: {:?} | Source: {:?}",
                 e,
                 e.source(),
             );
+            handle.retry(&e.to_string()).await;
         }
-    } else {
-        let data = PersistantData::Domain(DomainData::Result { query, pages_data });
-        if let Err(e) = persistant_data_sender.send(data) {
-            log::error!(
-                "Persistant data sender channel got an Error: {:?} | Source: {:?}",
-                e,
-                e.source(),
-            );
+    }
+}
+
+/// Queues the domain for qualification unless it is blacklisted
+async fn qualify_domain(
+    domain_qualifier_sender: &QueueSender<DomainQualifierChannelData>,
+    domain: String,
+    run_id: Option<Uuid>,
+) {
+    if BLACK_LIST_DOMAINS
+        .iter()
+        .any(|&blacklist| domain.contains(blacklist))
+    {
+        return;
+    }
+
+    if let Err(e) = domain_qualifier_sender
+        .send(DomainQualifierChannelData { domain, run_id })
+        .await
+    {
+        log::error!(
+            "Domain qualifier queue got an Error: {:?} | Source: {:?}",
+            e,
+            e.source(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::web::Data;
+    use sqlx::PgPool;
+    use tokio::sync::broadcast;
+
+    use crate::{
+        configuration::{
+            DataPersistanceSettings, DedupeSettings, EmailVerificationSettings,
+            SearchEngineSettings, SerpFixtureSettings,
+        },
+        dal::{google_webpage_db, run_db},
+        domain::{google_webpage::DataExtractionIntent, job::JobQueue},
+        services::{
+            data_persistance_handler, domain_qualifier_handler, email_verified_handler,
+            founder_scraper_handler, job_channel,
+            mock_smtp::{mock_smtp_settings, MockSmtpScenario, MockSmtpServer},
+            Dedupe, EmailVerifierKind, ProxyPool, SearchEngineKind, SearchEngines, Sentinel,
+            SerpFixtureMode, SerpFixtures, SmtpVerifier, StaticMxResolver, VerificationStrategy,
+            VerifiedEmailReceiver,
+        },
+    };
+
+    use super::{domain_scraper_handler, ProductQueryChannelData};
+
+    /// Runs a product query through every stage, searches are served from `fixtures/serp`
+    /// and mailboxes are checked against local smtp servers
+    #[sqlx::test]
+    async fn pipeline_replays_serps_from_query_to_verified_email(pool: PgPool) {
+        let search_engines = Data::new(SearchEngines::new(
+            SearchEngineSettings {
+                domain: vec![SearchEngineKind::Google],
+                founder: vec![SearchEngineKind::Google],
+                company_name: vec![SearchEngineKind::Google],
+                searxng_url: None,
+            },
+            Data::new(ProxyPool::load(pool.clone(), vec![]).await.unwrap()),
+            SerpFixtures::new(SerpFixtureSettings {
+                mode: SerpFixtureMode::Replay,
+                directory: concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/serp").to_string(),
+            }),
+        ));
+
+        // Only organicindia.com tells mailboxes apart, the other domains are catch-all
+        let server = MockSmtpServer::start(MockSmtpScenario::RejectUnknown(vec![
+            "dan.go@organicindia.com".to_string(),
+        ]))
+        .await;
+        let catch_all_server = MockSmtpServer::start(MockSmtpScenario::AcceptAll).await;
+        let resolver = [
+            "znaturalfoods.com",
+            "dallosell.com",
+            "traditionalmedicinals.com",
+        ]
+        .into_iter()
+        .fold(
+            StaticMxResolver::default().with_domain("organicindia.com", vec![server.mx_host()]),
+            |resolver, domain| resolver.with_domain(domain, vec![catch_all_server.mx_host()]),
+        );
+        let sentinel = Data::new(Sentinel::new(
+            EmailVerificationSettings {
+                verifiers: vec![EmailVerifierKind::Smtp],
+                strategy: VerificationStrategy::FirstConclusive,
+            },
+            String::new(),
+            SmtpVerifier::with_resolver(mock_smtp_settings(), resolver),
+        ));
+
+        let (product_query_sender, product_query_receiver) =
+            job_channel(pool.clone(), JobQueue::ProductQuery);
+        let (domain_qualifier_sender, domain_qualifier_receiver) =
+            job_channel(pool.clone(), JobQueue::DomainQualifier);
+        let (founder_query_sender, founder_query_receiver) =
+            job_channel(pool.clone(), JobQueue::FounderQuery);
+        let (email_sender, email_receiver) = job_channel(pool.clone(), JobQueue::EmailVerifier);
+        let (persistant_data_sender, persistant_data_receiver) =
+            job_channel(pool.clone(), JobQueue::PersistantData);
+        let (verified_email_sender, mut verified_email_receiver) = broadcast::channel(10);
+        let dedupe = |queue| {
+            Dedupe::new(
+                queue,
+                &DedupeSettings {
+                    ttl_minutes: 60,
+                    max_entries: 100,
+                    persist: false,
+                },
+                pool.clone(),
+            )
+        };
+
+        let run_id = run_db::insert_run(&pool, "green tea", None, None)
+            .await
+            .unwrap();
+        let query = r#""organic green tea" buy"#;
+        product_query_sender
+            .send(ProductQueryChannelData {
+                query: query.to_string(),
+                run_id: Some(run_id),
+            })
+            .await
+            .unwrap();
+
+        let handlers = [
+            tokio::spawn(domain_scraper_handler(
+                product_query_receiver,
+                dedupe(JobQueue::ProductQuery),
+                domain_qualifier_sender,
+                persistant_data_sender.clone(),
+                search_engines.clone(),
+                pool.clone(),
+            )),
+            tokio::spawn(domain_qualifier_handler(
+                sentinel.clone(),
+                domain_qualifier_receiver,
+                dedupe(JobQueue::DomainQualifier),
+                founder_query_sender,
+                persistant_data_sender.clone(),
+                pool.clone(),
+                Duration::from_secs(60 * 60),
+            )),
+            tokio::spawn(founder_scraper_handler(
+                founder_query_receiver,
+                dedupe(JobQueue::FounderQuery),
+                email_sender.clone(),
+                persistant_data_sender.clone(),
+                search_engines,
+                pool.clone(),
+            )),
+            tokio::spawn(email_verified_handler(
+                sentinel,
+                email_receiver,
+                dedupe(JobQueue::EmailVerifier),
+                persistant_data_sender.clone(),
+                verified_email_sender,
+                email_sender,
+                pool.clone(),
+            )),
+            tokio::spawn(data_persistance_handler(
+                persistant_data_receiver,
+                pool.clone(),
+                DataPersistanceSettings {
+                    flush_interval_ms: 0,
+                    max_batch_size: 10,
+                },
+            )),
+        ];
+
+        let verified = tokio::time::timeout(
+            Duration::from_secs(30),
+            VerifiedEmailReceiver::next_for_run(&mut verified_email_receiver, run_id),
+        )
+        .await
+        .expect("No email was verified");
+        assert_eq!(verified.as_deref(), Some("dan.go@organicindia.com"));
+
+        // Later runs reuse what was stored instead of scraping the queries again
+        let mut stored_domains = None;
+        for _ in 0..20 {
+            stored_domains = google_webpage_db::get_stored_query_data(
+                &pool,
+                query,
+                DataExtractionIntent::Domain,
+            )
+            .await
+            .unwrap();
+            if stored_domains.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
         }
+        let stored_founders = google_webpage_db::get_stored_query_data(
+            &pool,
+            r#"site:linkedin.com "organicindia.com" AND "founder""#,
+            DataExtractionIntent::FounderName,
+        )
+        .await
+        .unwrap();
+        handlers.iter().for_each(|handler| handler.abort());
+
+        let mut stored_domains = stored_domains.expect("Scraped page was not persisted");
+        stored_domains.sort();
+        assert_eq!(
+            stored_domains,
+            vec![
+                "dallosell.com",
+                "organicindia.com",
+                "traditionalmedicinals.com",
+                "znaturalfoods.com",
+            ]
+        );
+        assert_eq!(stored_founders, Some(vec!["dan go".to_string()]));
     }
 }
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use sqlx::{PgConnection, PgPool};
+
+use crate::{dal::email_pattern_db, domain::email_pattern::EmailPattern};
+
+/// Learns the email pattern of the domain from a freshly verified email
+pub async fn learn_email_pattern(
+    con: &mut PgConnection,
+    email_address: &str,
+) -> Result<Option<EmailPattern>, sqlx::Error> {
+    let verified_emails =
+        email_pattern_db::get_unambiguous_verified_emails(con, Some(email_address)).await?;
+
+    let mut learned_pattern = None;
+    for em in verified_emails {
+        if let Some(pattern) = EmailPattern::infer(&em.founder_name, &em.email_address) {
+            email_pattern_db::upsert_email_pattern(con, &em.domain, pattern).await?;
+            learned_pattern = Some(pattern);
+        }
+    }
+
+    Ok(learned_pattern)
+}
+
+/// Recomputes the pattern of every domain from all emails verified so far,
+/// the most common pattern at a domain wins
+pub async fn learn_email_patterns_from_verified(pool: PgPool) {
+    let result = async {
+        let mut tx = pool.begin().await?;
+
+        let verified_emails =
+            email_pattern_db::get_unambiguous_verified_emails(&mut tx, None).await?;
+        let mut domain_patterns: HashMap<String, HashMap<EmailPattern, i32>> = HashMap::new();
+        for em in verified_emails {
+            if let Some(pattern) = EmailPattern::infer(&em.founder_name, &em.email_address) {
+                *domain_patterns
+                    .entry(em.domain)
+                    .or_default()
+                    .entry(pattern)
+                    .or_default() += 1;
+            }
+        }
+
+        for (domain, patterns) in domain_patterns.iter() {
+            if let Some((pattern, count)) = patterns.iter().max_by_key(|(_, count)| **count) {
+                email_pattern_db::set_email_pattern(&mut tx, domain, *pattern, *count).await?;
+            }
+        }
+
+        tx.commit().await?;
+        Ok::<usize, sqlx::Error>(domain_patterns.len())
+    }
+    .await;
+
+    match result {
+        Ok(domains) => log::info!("Learned email patterns of {} domains", domains),
+        Err(e) => log::error!("Error while learning email patterns: {:?}", e),
+    }
+}
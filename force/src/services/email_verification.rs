@@ -0,0 +1,633 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use check_if_email_exists::{check_email, CheckEmailInput, Reachable};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::email::{Reachability, VerificationStatus};
+
+use super::{SmtpVerdict, SmtpVerification, SmtpVerifier};
+
+const BULK_EMAIL_CHECKER_URL: &str = "https://api.bulkemailchecker.com/real-time";
+const ROLE_LOCAL_PARTS: [&str; 16] = [
+    "admin",
+    "contact",
+    "hello",
+    "help",
+    "hi",
+    "info",
+    "marketing",
+    "office",
+    "press",
+    "sales",
+    "support",
+    "team",
+    "careers",
+    "jobs",
+    "billing",
+    "noreply",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailVerifierKind {
+    /// Our own smtp conversation with the domain's mx hosts
+    Smtp,
+    /// The check-if-email-exists crate
+    CheckEmail,
+    /// Paid api at bulkemailchecker.com
+    BulkEmailChecker,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationStrategy {
+    /// Ask verifiers in order until one is sure about the email
+    FirstConclusive,
+    /// Ask every verifier and go with the status most of them agree on
+    Consensus,
+}
+
+/// Everything a verifier could tell about an email
+#[derive(Debug)]
+pub struct VerificationOutcome {
+    pub verifier: EmailVerifierKind,
+    /// Pending when the verifier could not tell
+    pub status: VerificationStatus,
+    pub reachability: Reachability,
+    pub is_catch_all: bool,
+    pub is_disposable: bool,
+    pub is_role_account: bool,
+    /// Mailbox provider like google or microsoft, guessed from the mx hosts
+    pub provider: Option<String>,
+    /// Raw answer of the verifier, like the smtp reply or the api status
+    pub detail: String,
+    /// The verifier was told to come back later, like a greylisted email
+    pub retry_after: Option<Duration>,
+    /// Kept to persist the transcripts of smtp verification
+    pub smtp: Option<SmtpVerification>,
+}
+
+impl VerificationOutcome {
+    fn unknown(verifier: EmailVerifierKind, email: &str, detail: String) -> Self {
+        VerificationOutcome {
+            verifier,
+            status: VerificationStatus::Pending,
+            reachability: Reachability::Unknown,
+            is_catch_all: false,
+            is_disposable: false,
+            is_role_account: is_role_account(email),
+            provider: None,
+            detail,
+            retry_after: None,
+            smtp: None,
+        }
+    }
+
+    pub fn is_conclusive(&self) -> bool {
+        self.status != VerificationStatus::Pending
+    }
+}
+
+#[async_trait]
+pub trait EmailVerifier: Send + Sync {
+    fn kind(&self) -> EmailVerifierKind;
+
+    async fn verify(&self, email: &str) -> VerificationOutcome;
+
+    /// Outcomes in the order of `emails`, verifiers that can check emails together override this
+    async fn verify_many(&self, emails: &[String]) -> Vec<VerificationOutcome> {
+        let mut outcomes = vec![];
+        for email in emails {
+            outcomes.push(self.verify(email).await);
+        }
+        outcomes
+    }
+}
+
+pub struct SmtpEmailVerifier {
+    smtp_verifier: Arc<SmtpVerifier>,
+}
+
+impl SmtpEmailVerifier {
+    pub fn new(smtp_verifier: Arc<SmtpVerifier>) -> Self {
+        SmtpEmailVerifier { smtp_verifier }
+    }
+}
+
+#[async_trait]
+impl EmailVerifier for SmtpEmailVerifier {
+    fn kind(&self) -> EmailVerifierKind {
+        EmailVerifierKind::Smtp
+    }
+
+    async fn verify(&self, email: &str) -> VerificationOutcome {
+        let verification = self.smtp_verifier.verify(email).await;
+        self.outcome(verification)
+    }
+
+    async fn verify_many(&self, emails: &[String]) -> Vec<VerificationOutcome> {
+        self.smtp_verifier
+            .verify_many(emails)
+            .await
+            .into_iter()
+            .map(|verification| self.outcome(verification))
+            .collect()
+    }
+}
+
+impl SmtpEmailVerifier {
+    fn outcome(&self, verification: SmtpVerification) -> VerificationOutcome {
+        let email = verification.email.as_str();
+        let (status, reachability) = match verification.verdict {
+            SmtpVerdict::Deliverable => (VerificationStatus::Verified, Reachability::Safe),
+            SmtpVerdict::Undeliverable => (VerificationStatus::Invalid, Reachability::Invalid),
+            SmtpVerdict::Greylisted | SmtpVerdict::Unknown => {
+                (VerificationStatus::Pending, Reachability::Unknown)
+            }
+        };
+        let detail = verification
+            .attempts
+            .last()
+            .and_then(|attempt| attempt.transcript.last())
+            .cloned()
+            .unwrap_or_default();
+
+        VerificationOutcome {
+            verifier: self.kind(),
+            status,
+            reachability,
+            is_catch_all: false,
+            is_disposable: false,
+            is_role_account: is_role_account(email),
+            provider: verification.mx_hosts.first().and_then(|h| mail_provider(h)),
+            detail,
+            retry_after: match verification.verdict {
+                SmtpVerdict::Greylisted => Some(self.smtp_verifier.greylist_retry()),
+                _ => None,
+            },
+            smtp: Some(verification),
+        }
+    }
+}
+
+pub struct CheckEmailVerifier {
+    from_email: String,
+    hello_name: String,
+}
+
+impl CheckEmailVerifier {
+    pub fn new(from_email: String, hello_name: String) -> Self {
+        CheckEmailVerifier {
+            from_email,
+            hello_name,
+        }
+    }
+}
+
+#[async_trait]
+impl EmailVerifier for CheckEmailVerifier {
+    fn kind(&self) -> EmailVerifierKind {
+        EmailVerifierKind::CheckEmail
+    }
+
+    async fn verify(&self, email: &str) -> VerificationOutcome {
+        let mut input = CheckEmailInput::new(email.to_string());
+        input
+            .set_from_email(self.from_email.clone())
+            .set_hello_name(self.hello_name.clone());
+
+        let result = check_email(&input).await;
+
+        let (is_disposable, is_role_account) = match result.misc {
+            Ok(ref misc) => (misc.is_disposable, misc.is_role_account),
+            Err(_) => (false, is_role_account(email)),
+        };
+        let is_catch_all = result.smtp.as_ref().is_ok_and(|smtp| smtp.is_catch_all);
+        let provider = result.mx.as_ref().ok().and_then(|mx| {
+            let lookup = mx.lookup.as_ref().ok()?;
+            let host = lookup.iter().next()?.exchange().to_string();
+            mail_provider(&host)
+        });
+        let status = match (is_catch_all, &result.is_reachable) {
+            (true, _) => VerificationStatus::CatchAll,
+            (false, Reachable::Safe) => VerificationStatus::Verified,
+            (false, Reachable::Invalid) => VerificationStatus::Invalid,
+            (false, Reachable::Risky | Reachable::Unknown) => VerificationStatus::Pending,
+        };
+
+        VerificationOutcome {
+            verifier: self.kind(),
+            status,
+            detail: format!("{:?}", result.is_reachable),
+            reachability: result.is_reachable.into(),
+            is_catch_all,
+            is_disposable,
+            is_role_account,
+            provider,
+            retry_after: None,
+            smtp: None,
+        }
+    }
+}
+
+pub struct BulkEmailChecker {
+    client: Client,
+    api_key: String,
+}
+
+#[derive(Serialize)]
+struct BulkEmailCheckerQuery<'a> {
+    key: &'a str,
+    email: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BulkEmailCheckerResponse {
+    status: String,
+    #[serde(default)]
+    event: String,
+    #[serde(default)]
+    is_disposable: bool,
+    #[serde(default)]
+    is_role_account: bool,
+}
+
+impl BulkEmailChecker {
+    pub fn new(api_key: String) -> Self {
+        BulkEmailChecker {
+            client: Client::new(),
+            api_key,
+        }
+    }
+}
+
+#[async_trait]
+impl EmailVerifier for BulkEmailChecker {
+    fn kind(&self) -> EmailVerifierKind {
+        EmailVerifierKind::BulkEmailChecker
+    }
+
+    async fn verify(&self, email: &str) -> VerificationOutcome {
+        let response = self
+            .client
+            .get(BULK_EMAIL_CHECKER_URL)
+            .query(&BulkEmailCheckerQuery {
+                key: &self.api_key,
+                email,
+            })
+            .send()
+            .await;
+
+        let json = match response {
+            Ok(res) => match res.json::<BulkEmailCheckerResponse>().await {
+                Ok(json) => json,
+                Err(e) => {
+                    log::error!("Error when deserializing to json: {:?}", e);
+                    return VerificationOutcome::unknown(self.kind(), email, e.to_string());
+                }
+            },
+            Err(e) => {
+                log::error!("Got error from bulk email check api: {:?}", e);
+                return VerificationOutcome::unknown(self.kind(), email, e.to_string());
+            }
+        };
+
+        let is_catch_all = json.event == "is_catchall";
+        let (status, reachability) = match (json.status.as_str(), is_catch_all) {
+            (_, true) => (VerificationStatus::CatchAll, Reachability::Risky),
+            ("passed", false) => (VerificationStatus::Verified, Reachability::Safe),
+            ("failed", false) => (VerificationStatus::Invalid, Reachability::Invalid),
+            _ => (VerificationStatus::Pending, Reachability::Unknown),
+        };
+
+        VerificationOutcome {
+            verifier: self.kind(),
+            status,
+            reachability,
+            is_catch_all,
+            is_disposable: json.is_disposable,
+            is_role_account: json.is_role_account,
+            provider: None,
+            detail: format!("{} {}", json.status, json.event),
+            retry_after: None,
+            smtp: None,
+        }
+    }
+}
+
+/// Runs an email through the configured verifiers
+pub struct VerificationChain {
+    verifiers: Vec<Box<dyn EmailVerifier>>,
+    strategy: VerificationStrategy,
+}
+
+impl VerificationChain {
+    pub fn new(verifiers: Vec<Box<dyn EmailVerifier>>, strategy: VerificationStrategy) -> Self {
+        VerificationChain {
+            verifiers,
+            strategy,
+        }
+    }
+
+    pub async fn verify(&self, email: &str) -> VerificationOutcome {
+        let mut outcomes = self.verify_many(&[email.to_string()]).await;
+        outcomes.remove(0)
+    }
+
+    /// Every verifier gets the emails still undecided as one batch, outcomes come back
+    /// in the order of `emails`
+    pub async fn verify_many(&self, emails: &[String]) -> Vec<VerificationOutcome> {
+        let mut outcomes: Vec<Vec<VerificationOutcome>> = emails.iter().map(|_| vec![]).collect();
+        let mut pending: Vec<usize> = (0..emails.len()).collect();
+
+        for verifier in self.verifiers.iter() {
+            if pending.is_empty() {
+                break;
+            }
+            let batch: Vec<String> = pending.iter().map(|i| emails[*i].clone()).collect();
+
+            for (i, outcome) in pending
+                .clone()
+                .into_iter()
+                .zip(verifier.verify_many(&batch).await)
+            {
+                log::info!(
+                    "Email: {}; Verifier: {:?}; Status: {:?}; Detail: {}",
+                    emails[i],
+                    outcome.verifier,
+                    outcome.status,
+                    outcome.detail,
+                );
+
+                let is_done = outcome.is_conclusive() || outcome.retry_after.is_some();
+                outcomes[i].push(outcome);
+                if self.strategy == VerificationStrategy::FirstConclusive && is_done {
+                    pending.retain(|p| *p != i);
+                }
+            }
+        }
+
+        emails
+            .iter()
+            .zip(outcomes)
+            .map(|(email, outcomes)| match self.strategy {
+                VerificationStrategy::FirstConclusive => first_conclusive(email, outcomes),
+                VerificationStrategy::Consensus => consensus(email, outcomes),
+            })
+            .collect()
+    }
+}
+
+/// The first outcome that is sure or asks for a retry, the first unsure one otherwise
+fn first_conclusive(email: &str, outcomes: Vec<VerificationOutcome>) -> VerificationOutcome {
+    let index = outcomes
+        .iter()
+        .position(|o| o.is_conclusive() || o.retry_after.is_some())
+        .unwrap_or(0);
+
+    outcomes.into_iter().nth(index).unwrap_or_else(|| {
+        VerificationOutcome::unknown(
+            EmailVerifierKind::Smtp,
+            email,
+            "No verifiers configured".to_string(),
+        )
+    })
+}
+
+/// Status with the most votes among conclusive outcomes, a tie stays pending.
+/// Flags are raised when any verifier raised them.
+fn consensus(email: &str, outcomes: Vec<VerificationOutcome>) -> VerificationOutcome {
+    let mut votes: HashMap<VerificationStatus, usize> = HashMap::new();
+    for outcome in outcomes.iter().filter(|o| o.is_conclusive()) {
+        *votes.entry(outcome.status).or_default() += 1;
+    }
+    let max_votes = votes.values().copied().max().unwrap_or(0);
+    let winners: Vec<VerificationStatus> = votes
+        .into_iter()
+        .filter(|(_, count)| *count == max_votes)
+        .map(|(status, _)| status)
+        .collect();
+
+    let is_catch_all = outcomes.iter().any(|o| o.is_catch_all);
+    let is_disposable = outcomes.iter().any(|o| o.is_disposable);
+    let is_role_account = outcomes.iter().any(|o| o.is_role_account);
+    let provider = outcomes.iter().find_map(|o| o.provider.clone());
+    let detail = outcomes
+        .iter()
+        .map(|o| format!("{:?}: {}", o.verifier, o.detail))
+        .collect::<Vec<String>>()
+        .join("; ");
+
+    let mut outcome = match winners.as_slice() {
+        [status] => {
+            let index = outcomes
+                .iter()
+                .position(|o| o.status == *status)
+                .unwrap_or(0);
+            outcomes.into_iter().nth(index)
+        }
+        _ => {
+            let retry_after = outcomes.iter().find_map(|o| o.retry_after);
+            let smtp = outcomes.into_iter().find_map(|o| o.smtp);
+
+            Some(VerificationOutcome {
+                retry_after,
+                smtp,
+                ..VerificationOutcome::unknown(
+                    EmailVerifierKind::Smtp,
+                    email,
+                    "No consensus".to_string(),
+                )
+            })
+        }
+    }
+    .unwrap_or_else(|| {
+        VerificationOutcome::unknown(
+            EmailVerifierKind::Smtp,
+            email,
+            "No verifiers configured".to_string(),
+        )
+    });
+
+    outcome.is_catch_all |= is_catch_all;
+    outcome.is_disposable |= is_disposable;
+    outcome.is_role_account |= is_role_account;
+    outcome.provider = outcome.provider.or(provider);
+    outcome.detail = detail;
+    outcome
+}
+
+/// Shared mailboxes like info@ or sales@ that don't belong to a person
+pub fn is_role_account(email: &str) -> bool {
+    let local_part = email.split('@').next().unwrap_or_default().to_lowercase();
+    ROLE_LOCAL_PARTS.contains(&local_part.as_str())
+}
+
+/// Well known mailbox providers by their mx hosts
+pub fn mail_provider(mx_host: &str) -> Option<String> {
+    let mx_host = mx_host.trim_end_matches('.').to_lowercase();
+
+    [
+        ("google.com", "google"),
+        ("googlemail.com", "google"),
+        ("outlook.com", "microsoft"),
+        ("yahoodns.net", "yahoo"),
+        ("zoho", "zoho"),
+        ("pphosted.com", "proofpoint"),
+        ("mimecast", "mimecast"),
+        ("secureserver.net", "godaddy"),
+    ]
+    .into_iter()
+    .find(|(pattern, _)| mx_host.contains(pattern))
+    .map(|(_, provider)| provider.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use crate::domain::email::{Reachability, VerificationStatus};
+
+    use super::{
+        is_role_account, mail_provider, EmailVerifier, EmailVerifierKind, VerificationChain,
+        VerificationOutcome, VerificationStrategy,
+    };
+
+    struct FixedVerifier(EmailVerifierKind, VerificationStatus);
+
+    #[async_trait]
+    impl EmailVerifier for FixedVerifier {
+        fn kind(&self) -> EmailVerifierKind {
+            self.0
+        }
+
+        async fn verify(&self, email: &str) -> VerificationOutcome {
+            VerificationOutcome {
+                status: self.1,
+                reachability: Reachability::Unknown,
+                ..VerificationOutcome::unknown(self.0, email, format!("{:?}", self.1))
+            }
+        }
+    }
+
+    fn chain(
+        statuses: &[(EmailVerifierKind, VerificationStatus)],
+        strategy: VerificationStrategy,
+    ) -> VerificationChain {
+        VerificationChain::new(
+            statuses
+                .iter()
+                .map(|(kind, status)| {
+                    Box::new(FixedVerifier(*kind, *status)) as Box<dyn EmailVerifier>
+                })
+                .collect(),
+            strategy,
+        )
+    }
+
+    #[tokio::test]
+    async fn first_conclusive_falls_through_unknowns() {
+        let outcome = chain(
+            &[
+                (EmailVerifierKind::Smtp, VerificationStatus::Pending),
+                (
+                    EmailVerifierKind::BulkEmailChecker,
+                    VerificationStatus::Invalid,
+                ),
+                (EmailVerifierKind::CheckEmail, VerificationStatus::Verified),
+            ],
+            VerificationStrategy::FirstConclusive,
+        )
+        .verify("dan@verywellfit.com")
+        .await;
+
+        assert_eq!(outcome.verifier, EmailVerifierKind::BulkEmailChecker);
+        assert_eq!(outcome.status, VerificationStatus::Invalid);
+
+        let outcome = chain(
+            &[(EmailVerifierKind::Smtp, VerificationStatus::Pending)],
+            VerificationStrategy::FirstConclusive,
+        )
+        .verify("dan@verywellfit.com")
+        .await;
+        assert_eq!(outcome.status, VerificationStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn consensus_majority_wins() {
+        let outcome = chain(
+            &[
+                (EmailVerifierKind::Smtp, VerificationStatus::Verified),
+                (EmailVerifierKind::CheckEmail, VerificationStatus::Invalid),
+                (
+                    EmailVerifierKind::BulkEmailChecker,
+                    VerificationStatus::Verified,
+                ),
+            ],
+            VerificationStrategy::Consensus,
+        )
+        .verify("info@verywellfit.com")
+        .await;
+
+        assert_eq!(outcome.status, VerificationStatus::Verified);
+        assert!(outcome.is_role_account);
+
+        let outcome = chain(
+            &[
+                (EmailVerifierKind::Smtp, VerificationStatus::Verified),
+                (EmailVerifierKind::CheckEmail, VerificationStatus::Invalid),
+                (
+                    EmailVerifierKind::BulkEmailChecker,
+                    VerificationStatus::Pending,
+                ),
+            ],
+            VerificationStrategy::Consensus,
+        )
+        .verify("dan@verywellfit.com")
+        .await;
+        assert_eq!(outcome.status, VerificationStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn verify_many_keeps_email_order() {
+        let emails = vec![
+            "dan@verywellfit.com".to_string(),
+            "info@verywellfit.com".to_string(),
+        ];
+        let outcomes = chain(
+            &[
+                (EmailVerifierKind::Smtp, VerificationStatus::Pending),
+                (EmailVerifierKind::CheckEmail, VerificationStatus::Verified),
+            ],
+            VerificationStrategy::FirstConclusive,
+        )
+        .verify_many(&emails)
+        .await;
+
+        assert_eq!(outcomes.len(), 2);
+        assert!(!outcomes[0].is_role_account);
+        assert!(outcomes[1].is_role_account);
+        assert!(outcomes
+            .iter()
+            .all(|o| o.verifier == EmailVerifierKind::CheckEmail));
+    }
+
+    #[test]
+    fn email_traits_valid() {
+        assert!(is_role_account("Sales@verywellfit.com"));
+        assert!(!is_role_account("dan.go@verywellfit.com"));
+
+        assert_eq!(
+            mail_provider("ALT1.ASPMX.L.GOOGLE.COM."),
+            Some("google".to_string())
+        );
+        assert_eq!(
+            mail_provider("verywellfit-com.mail.protection.outlook.com"),
+            Some("microsoft".to_string())
+        );
+        assert_eq!(mail_provider("mx.verywellfit.com"), None);
+    }
+}
@@ -1,74 +1,247 @@
-use std::{collections::HashSet, error::Error};
+use std::{collections::HashMap, error::Error};
 
 use actix_web::web::Data;
 use check_if_email_exists::Reachable;
-use tokio::sync::{
-    broadcast,
-    mpsc::{UnboundedReceiver, UnboundedSender},
+use sqlx::PgPool;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::{
+    dal::email_db,
+    domain::{
+        email::{FounderDomainEmail, VerificationStatus},
+        run::RunStage,
+    },
 };
 
-use crate::domain::email::FounderDomainEmail;
+use super::{
+    metrics, record_run_lead, record_run_progress, Dedupe, JobHandle, PersistantData,
+    QueueReceiver, QueueSender, Sentinel, VerificationOutcome,
+};
 
-use super::{PersistantData, Sentinel};
+pub struct VerifiedEmailReceiver {
+    pub sender: broadcast::Sender<VerifiedEmail>,
+}
 
-const SET_RESET_LEN: usize = 10_000;
+impl VerifiedEmailReceiver {
+    /// Waits for the next email verified for the run, None once the verifier shut down
+    pub async fn next_for_run(
+        receiver: &mut broadcast::Receiver<VerifiedEmail>,
+        run_id: Uuid,
+    ) -> Option<String> {
+        loop {
+            match receiver.recv().await {
+                Ok(em) if em.run_id == Some(run_id) => return Some(em.email),
+                Ok(_) => {}
+                // Emails of other runs share this channel, skip over the ones we missed
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    log::warn!("Verified emails of run {} lagged by {}", run_id, n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
 
-pub struct VerifiedEmailReceiver {
-    pub sender: broadcast::Sender<String>,
+#[derive(Debug, Clone)]
+pub struct VerifiedEmail {
+    pub email: String,
+    pub run_id: Option<Uuid>,
 }
 pub struct EmailVerifierSender {
-    pub sender: UnboundedSender<FounderDomainEmail>,
+    pub sender: QueueSender<FounderDomainEmail>,
 }
 
 pub async fn email_verified_handler(
     sentinel: Data<Sentinel>,
-    mut email_receiver: UnboundedReceiver<FounderDomainEmail>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
-    verified_email_sender: broadcast::Sender<String>,
+    mut email_receiver: QueueReceiver<FounderDomainEmail>,
+    dedupe: Dedupe,
+    persistant_data_sender: QueueSender<PersistantData>,
+    verified_email_sender: broadcast::Sender<VerifiedEmail>,
+    email_sender: QueueSender<FounderDomainEmail>,
+    pool: PgPool,
 ) {
     log::info!("Started email verifier handler");
-    let mut seen_emails = HashSet::new();
 
-    while let Some(email) = email_receiver.recv().await {
+    while let Some(job) = email_receiver.recv().await {
         log::info!(
             "Email verifier handler has {} elements",
             email_receiver.len()
         );
 
-        match seen_emails.contains(&email.email) {
-            true => {}
-            false => {
-                // TODO: Implement time based reset like 10 mins after channel was empty
-                if seen_emails.len() > SET_RESET_LEN {
-                    seen_emails.clear();
+        // Emails of a domain are verified together so that they share smtp sessions
+        let mut jobs = vec![job];
+        while !email_receiver.is_empty() {
+            match email_receiver.recv().await {
+                Some(job) => jobs.push(job),
+                None => break,
+            }
+        }
+
+        let mut domains: HashMap<String, Vec<(FounderDomainEmail, JobHandle)>> = HashMap::new();
+        for job in jobs {
+            let (email, handle) = job.into_parts();
+
+            // Retried jobs were seen before and are back on purpose, like greylisted emails
+            let is_duplicate = !dedupe.first_seen_in_run(email.run_id, &email.email).await
+                && handle.attempt() == 1;
+            match is_duplicate {
+                true => handle.complete().await,
+                false => {
+                    domains
+                        .entry(email.domain.clone())
+                        .or_default()
+                        .push((email, handle));
                 }
-                seen_emails.insert(email.email.clone());
-                tokio::spawn(verify_email(
-                    sentinel.clone(),
-                    persistant_data_sender.clone(),
-                    verified_email_sender.clone(),
-                    email,
-                ));
             }
         }
+
+        for (_, emails) in domains {
+            let worker = email_receiver.acquire_worker().await;
+            tokio::spawn(worker.run(verify_domain_emails(
+                sentinel.clone(),
+                persistant_data_sender.clone(),
+                verified_email_sender.clone(),
+                email_sender.clone(),
+                pool.clone(),
+                emails,
+            )));
+        }
     }
 }
 
-async fn verify_email(
+async fn verify_domain_emails(
     sentinel: Data<Sentinel>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
-    verified_email_sender: broadcast::Sender<String>,
+    persistant_data_sender: QueueSender<PersistantData>,
+    verified_email_sender: broadcast::Sender<VerifiedEmail>,
+    email_sender: QueueSender<FounderDomainEmail>,
+    pool: PgPool,
+    emails: Vec<(FounderDomainEmail, JobHandle)>,
+) {
+    let addresses: Vec<String> = emails.iter().map(|(e, _)| e.email.clone()).collect();
+    let settled: HashMap<String, VerificationStatus> =
+        match email_db::get_settled_emails(&pool, &addresses).await {
+            Ok(settled) => settled.into_iter().collect(),
+            Err(e) => {
+                log::error!("Error while getting settled emails: {:?}", e);
+                HashMap::new()
+            }
+        };
+
+    // Emails verified before, like for another run, keep their status
+    let mut unsettled = vec![];
+    for (email, handle) in emails {
+        match settled.get(&email.email) {
+            Some(VerificationStatus::Verified) => {
+                announce_verified(&persistant_data_sender, &verified_email_sender, &email).await;
+                handle.complete().await;
+            }
+            Some(VerificationStatus::Invalid) => {
+                queue_fallback_emails(&persistant_data_sender, &email_sender, &email).await;
+                handle.complete().await;
+            }
+            Some(_) => handle.complete().await,
+            None => unsettled.push((email, handle)),
+        }
+    }
+    if unsettled.is_empty() {
+        return;
+    }
+    let emails = unsettled;
+
+    let addresses: Vec<String> = emails.iter().map(|(e, _)| e.email.clone()).collect();
+    log::info!("Verifying emails: {:?}", addresses);
+
+    let outcomes = sentinel.verify_many(&addresses).await;
+
+    for ((email, handle), outcome) in emails.into_iter().zip(outcomes) {
+        handle_outcome(
+            &persistant_data_sender,
+            &verified_email_sender,
+            &email_sender,
+            email,
+            handle,
+            outcome,
+        )
+        .await;
+    }
+}
+
+async fn handle_outcome(
+    persistant_data_sender: &QueueSender<PersistantData>,
+    verified_email_sender: &broadcast::Sender<VerifiedEmail>,
+    email_sender: &QueueSender<FounderDomainEmail>,
     email: FounderDomainEmail, // TODO: Use only email
+    handle: JobHandle,
+    outcome: VerificationOutcome,
 ) {
-    log::info!("Verifying email: {}", email.email);
+    let label = match (outcome.retry_after, outcome.status) {
+        (Some(_), _) => "greylisted",
+        (None, VerificationStatus::Verified) => "verified",
+        (None, VerificationStatus::CatchAll) => "catch_all",
+        (None, VerificationStatus::Invalid) => "invalid",
+        (None, VerificationStatus::Pending) => "unknown",
+    };
+    metrics().verifications.with_label_values(&[label]).inc();
 
-    let valid = sentinel.verify_email_manual(&email.email).await;
-    if valid {
-        // Errors if there is no route thread listening for verified emails
-        _ = verified_email_sender.send(email.email.clone());
+    if let Some(verification) = outcome.smtp {
+        if let Err(e) = persistant_data_sender
+            .send(PersistantData::SmtpVerification(verification))
+            .await
+        {
+            log::error!(
+                "Persistant data sender channel got an Error: {:?} | Source: {:?}",
+                e,
+                e.source(),
+            );
+        }
+    }
+
+    if let Some(delay) = outcome.retry_after {
+        log::info!(
+            "Email {} was greylisted, retrying in {:?}",
+            email.email,
+            delay
+        );
+
+        handle
+            .retry_after(&format!("Greylisted: {}", email.email), delay)
+            .await;
+        return;
+    }
+
+    // Timeouts, refused connections or verifiers that can't agree say nothing about the
+    // mailbox, so the email stays pending and is asked about again later
+    if outcome.status == VerificationStatus::Pending {
+        log::info!(
+            "Email {} could not be verified, retrying later: {}",
+            email.email,
+            outcome.detail
+        );
+
+        handle
+            .retry(&format!("Inconclusive: {}", outcome.detail))
+            .await;
+        return;
+    }
 
-        if let Err(e) =
-            persistant_data_sender.send(PersistantData::UpdateEmailVerified(email.email))
+    if outcome.status == VerificationStatus::CatchAll {
+        if let Err(e) = persistant_data_sender
+            .send(PersistantData::UpdateEmailCatchAll(email.email))
+            .await
+        {
+            log::error!(
+                "Persistant data sender channel got an Error: {:?} | Source: {:?}",
+                e,
+                e.source(),
+            );
+        }
+    } else if outcome.status == VerificationStatus::Verified {
+        announce_verified(persistant_data_sender, verified_email_sender, &email).await;
+
+        if let Err(e) = persistant_data_sender
+            .send(PersistantData::UpdateEmailVerified(email.email))
+            .await
         {
             log::error!(
                 "Persistant data sender channel got an Error: {:?} | Source: {:?}",
@@ -77,8 +250,9 @@ async fn verify_email(
             );
         }
     } else {
-        if let Err(e) =
-            persistant_data_sender.send(PersistantData::UpdateEmailUnverified(email.email))
+        if let Err(e) = persistant_data_sender
+            .send(PersistantData::UpdateEmailUnverified(email.email.clone()))
+            .await
         {
             log::error!(
                 "Persistant data sender channel got an Error: {:?} | Source: {:?}",
@@ -86,8 +260,12 @@ async fn verify_email(
                 e.source(),
             );
         }
+
+        queue_fallback_emails(persistant_data_sender, email_sender, &email).await;
     }
 
+    handle.complete().await;
+
     // let reachable = sentinel.get_email_verification_status(&email.email).await;
     // match reachable {
     //     Reachable::Safe => {
@@ -107,3 +285,328 @@ async fn verify_email(
     //     _ => {}
     // };
 }
+
+/// Counts the email towards its run and hands it to the routes waiting on the run
+async fn announce_verified(
+    persistant_data_sender: &QueueSender<PersistantData>,
+    verified_email_sender: &broadcast::Sender<VerifiedEmail>,
+    email: &FounderDomainEmail,
+) {
+    record_run_progress(
+        persistant_data_sender,
+        email.run_id,
+        RunStage::EmailsVerified,
+        1,
+    )
+    .await;
+    record_run_lead(persistant_data_sender, email.run_id, &email.email).await;
+
+    // Errors if there is no route thread listening for verified emails
+    _ = verified_email_sender.send(VerifiedEmail {
+        email: email.email.clone(),
+        run_id: email.run_id,
+    });
+}
+
+/// Learned pattern was wrong for this founder, try the other permutations
+async fn queue_fallback_emails(
+    persistant_data_sender: &QueueSender<PersistantData>,
+    email_sender: &QueueSender<FounderDomainEmail>,
+    email: &FounderDomainEmail,
+) {
+    for fallback_email in email.fallback_emails.iter() {
+        let em = FounderDomainEmail {
+            email: fallback_email.to_string(),
+            fallback_emails: vec![],
+            ..email.clone()
+        };
+
+        if let Err(e) = persistant_data_sender
+            .send(PersistantData::Email(em.clone()))
+            .await
+        {
+            log::error!(
+                "Persistant data sender channel got an Error: {:?} | Source: {:?}",
+                e,
+                e.source(),
+            );
+        }
+        if let Err(e) = email_sender.send_unbounded(em).await {
+            log::error!(
+                "Email verifier queue got an Error: {:?} | Source: {:?}",
+                e,
+                e.source(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::PgPool;
+    use tokio::sync::broadcast;
+
+    use crate::{
+        configuration::{DataPersistanceSettings, DedupeSettings},
+        dal::{email_db, run_db},
+        domain::{
+            email::{
+                construct_email_permutations, Email, FounderDomainEmail, Reachability,
+                VerificationStatus,
+            },
+            job::JobQueue,
+        },
+        services::{
+            data_persistance_handler, job_channel,
+            mock_smtp::{mock_sentinel, MockSmtpScenario, MockSmtpServer},
+            Dedupe,
+        },
+    };
+
+    use super::email_verified_handler;
+
+    #[sqlx::test]
+    async fn email_verified_handler_with_mock_server(pool: PgPool) {
+        let server = MockSmtpServer::start(MockSmtpScenario::RejectUnknown(vec![
+            "dan.go@verywellfit.com".to_string(),
+        ]))
+        .await;
+        let sentinel = mock_sentinel("verywellfit.com", &server);
+
+        let (email_sender, email_receiver) = job_channel(pool.clone(), JobQueue::EmailVerifier);
+        let (persistant_data_sender, persistant_data_receiver) =
+            job_channel(pool.clone(), JobQueue::PersistantData);
+        let (verified_email_sender, mut verified_email_receiver) = broadcast::channel(10);
+
+        let mut con = pool.acquire().await.unwrap();
+        for em in construct_email_permutations("Dan Go", "verywellfit.com") {
+            let email = Email {
+                email_address: em.email.clone(),
+                founder_name: em.founder_name.clone(),
+                domain: em.domain.clone(),
+                verification_status: VerificationStatus::Pending,
+                reachability: Reachability::Unknown,
+                run_id: None,
+            };
+            email_db::insert_email(&mut con, email).await.unwrap();
+            email_sender.send(em).await.unwrap();
+        }
+
+        let handlers = [
+            tokio::spawn(email_verified_handler(
+                sentinel,
+                email_receiver,
+                Dedupe::new(
+                    JobQueue::EmailVerifier,
+                    &DedupeSettings {
+                        ttl_minutes: 60,
+                        max_entries: 100,
+                        persist: false,
+                    },
+                    pool.clone(),
+                ),
+                persistant_data_sender,
+                verified_email_sender,
+                email_sender,
+                pool.clone(),
+            )),
+            tokio::spawn(data_persistance_handler(
+                persistant_data_receiver,
+                pool.clone(),
+                DataPersistanceSettings {
+                    flush_interval_ms: 0,
+                    max_batch_size: 10,
+                },
+            )),
+        ];
+
+        let verified =
+            tokio::time::timeout(Duration::from_secs(10), verified_email_receiver.recv())
+                .await
+                .expect("No email was verified")
+                .unwrap();
+        assert_eq!(verified.email, "dan.go@verywellfit.com");
+
+        let mut statuses = vec![];
+        for _ in 0..20 {
+            statuses = sqlx::query_as::<_, (String, VerificationStatus)>(
+                "select email_address, verification_status from email order by email_address",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+
+            if statuses
+                .iter()
+                .all(|(_, status)| *status != VerificationStatus::Pending)
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        handlers.iter().for_each(|handler| handler.abort());
+
+        for (email, status) in statuses {
+            match email.as_str() {
+                "dan.go@verywellfit.com" => assert_eq!(status, VerificationStatus::Verified),
+                _ => assert_eq!(status, VerificationStatus::Invalid, "{}", email),
+            }
+        }
+
+        let transcripts: i64 = sqlx::query_scalar("select count(*) from smtp_attempt")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(transcripts, 6);
+
+        // All permutations were claimed together and checked in one session
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[sqlx::test]
+    async fn emails_verified_before_are_announced_to_new_runs(pool: PgPool) {
+        let server = MockSmtpServer::start(MockSmtpScenario::AcceptAll).await;
+        let sentinel = mock_sentinel("verywellfit.com", &server);
+
+        let (email_sender, email_receiver) = job_channel(pool.clone(), JobQueue::EmailVerifier);
+        let (persistant_data_sender, _) = job_channel(pool.clone(), JobQueue::PersistantData);
+        let (verified_email_sender, mut verified_email_receiver) = broadcast::channel(10);
+
+        let em = construct_email_permutations("Dan Go", "verywellfit.com")
+            .into_iter()
+            .find(|em| em.email == "dan.go@verywellfit.com")
+            .unwrap();
+        let mut con = pool.acquire().await.unwrap();
+        email_db::insert_email(
+            &mut con,
+            Email {
+                email_address: em.email.clone(),
+                founder_name: em.founder_name.clone(),
+                domain: em.domain.clone(),
+                verification_status: VerificationStatus::Pending,
+                reachability: Reachability::Unknown,
+                run_id: None,
+            },
+        )
+        .await
+        .unwrap();
+        email_db::update_email_verified(&mut con, em.email.clone())
+            .await
+            .unwrap();
+
+        let run_id = Some(
+            run_db::insert_run(&pool, "fitness", None, None)
+                .await
+                .unwrap(),
+        );
+        email_sender
+            .send(FounderDomainEmail { run_id, ..em })
+            .await
+            .unwrap();
+
+        let handler = tokio::spawn(email_verified_handler(
+            sentinel,
+            email_receiver,
+            Dedupe::new(
+                JobQueue::EmailVerifier,
+                &DedupeSettings {
+                    ttl_minutes: 60,
+                    max_entries: 100,
+                    persist: false,
+                },
+                pool.clone(),
+            ),
+            persistant_data_sender,
+            verified_email_sender,
+            email_sender,
+            pool.clone(),
+        ));
+
+        let verified =
+            tokio::time::timeout(Duration::from_secs(10), verified_email_receiver.recv())
+                .await
+                .expect("No email was verified")
+                .unwrap();
+        handler.abort();
+
+        assert_eq!(verified.email, "dan.go@verywellfit.com");
+        assert_eq!(verified.run_id, run_id);
+        assert_eq!(server.connections(), 0);
+    }
+
+    #[sqlx::test]
+    async fn inconclusive_emails_stay_pending_and_are_retried(pool: PgPool) {
+        let server = MockSmtpServer::start(MockSmtpScenario::Disconnect).await;
+        let sentinel = mock_sentinel("verywellfit.com", &server);
+
+        let (email_sender, email_receiver) = job_channel(pool.clone(), JobQueue::EmailVerifier);
+        let (persistant_data_sender, _) = job_channel(pool.clone(), JobQueue::PersistantData);
+        let (verified_email_sender, _) = broadcast::channel(10);
+
+        let em = construct_email_permutations("Dan Go", "verywellfit.com")
+            .into_iter()
+            .find(|em| em.email == "dan.go@verywellfit.com")
+            .unwrap();
+        email_sender
+            .send(FounderDomainEmail {
+                fallback_emails: vec!["d.go@verywellfit.com".to_string()],
+                ..em
+            })
+            .await
+            .unwrap();
+
+        let handler = tokio::spawn(email_verified_handler(
+            sentinel,
+            email_receiver,
+            Dedupe::new(
+                JobQueue::EmailVerifier,
+                &DedupeSettings {
+                    ttl_minutes: 60,
+                    max_entries: 100,
+                    persist: false,
+                },
+                pool.clone(),
+            ),
+            persistant_data_sender,
+            verified_email_sender,
+            email_sender,
+            pool.clone(),
+        ));
+
+        let mut last_error: Option<String> = None;
+        for _ in 0..40 {
+            last_error =
+                sqlx::query_scalar("select last_error from job where payload->>'email' = $1")
+                    .bind("dan.go@verywellfit.com")
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+            if last_error.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+        handler.abort();
+
+        assert!(last_error.unwrap().starts_with("Inconclusive"));
+        let (status, run_after_later): (String, bool) = sqlx::query_as(
+            "select status::text, run_after > now() from job where payload->>'email' = $1",
+        )
+        .bind("dan.go@verywellfit.com")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!((status.as_str(), run_after_later), ("PENDING", true));
+
+        // Not written off as invalid, so its fallbacks are not tried
+        let written_off: i64 = sqlx::query_scalar(
+            "select count(*) from job where payload ? 'UpdateEmailUnverified' or payload->>'email' = 'd.go@verywellfit.com'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(written_off, 0);
+    }
+}
@@ -1,51 +1,65 @@
-use std::{collections::HashSet, error::Error};
+use std::error::Error;
 
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use actix_web::web::Data;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
 
-use crate::domain::{
-    email::{construct_email_permutations, FounderDomainEmail},
-    html_tag::extract_founder_name,
+use crate::{
+    dal::{email_pattern_db, google_webpage_db},
+    domain::{
+        email::{construct_emails_with_pattern, FounderDomainEmail},
+        google_webpage::DataExtractionIntent,
+        html_tag::extract_founder_name,
+        run::RunStage,
+    },
 };
 
 use super::{
-    extract_data_from_google_search_with_reqwest, FounderData, FounderPageData, GoogleSearchResult,
-    GoogleSearchType, PersistantData,
+    extract_data_from_google_search_with_reqwest, metrics, record_run_progress, Dedupe,
+    FounderData, FounderPageData, GoogleSearchResult, GoogleSearchType, JobHandle, PersistantData,
+    QueueReceiver, QueueSender, SearchEngines,
 };
 
-const SET_RESET_LEN: usize = 10_000;
-
+#[derive(Serialize, Deserialize)]
 pub struct FounderQueryChannelData {
     pub query: String,
     pub domain: String,
+    pub run_id: Option<Uuid>,
 }
 
 pub async fn founder_scraper_handler(
-    mut founder_query_receiver: UnboundedReceiver<FounderQueryChannelData>,
-    email_sender: UnboundedSender<FounderDomainEmail>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
+    mut founder_query_receiver: QueueReceiver<FounderQueryChannelData>,
+    dedupe: Dedupe,
+    email_sender: QueueSender<FounderDomainEmail>,
+    persistant_data_sender: QueueSender<PersistantData>,
+    search_engines: Data<SearchEngines>,
+    pool: PgPool,
 ) {
     log::info!("Started founder scraper");
-    let mut seen_queries = HashSet::new();
 
-    while let Some(data) = founder_query_receiver.recv().await {
+    while let Some(job) = founder_query_receiver.recv().await {
         log::info!(
             "Founder scraper handler has {} elements",
             founder_query_receiver.len()
         );
+        let (data, handle) = job.into_parts();
 
-        match seen_queries.contains(&data.query) {
-            true => {}
+        // Retried jobs were seen before and are back on purpose
+        let is_duplicate =
+            !dedupe.first_seen_in_run(data.run_id, &data.query).await && handle.attempt() == 1;
+        match is_duplicate {
+            true => handle.complete().await,
             false => {
-                // TODO: Implement time based reset like 10 mins after channel was empty
-                if seen_queries.len() > SET_RESET_LEN {
-                    seen_queries.clear();
-                }
-                seen_queries.insert(data.query.clone());
-                tokio::spawn(scrape_founder_query(
+                let worker = founder_query_receiver.acquire_worker().await;
+                tokio::spawn(worker.run(scrape_founder_query(
                     data,
+                    handle,
                     email_sender.clone(),
                     persistant_data_sender.clone(),
-                ));
+                    search_engines.clone(),
+                    pool.clone(),
+                )));
             }
         }
     }
@@ -53,23 +67,82 @@ pub async fn founder_scraper_handler(
 
 async fn scrape_founder_query(
     data: FounderQueryChannelData,
-    email_sender: UnboundedSender<FounderDomainEmail>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
+    handle: JobHandle,
+    email_sender: QueueSender<FounderDomainEmail>,
+    persistant_data_sender: QueueSender<PersistantData>,
+    search_engines: Data<SearchEngines>,
+    pool: PgPool,
 ) {
+    // Queries scraped before, like by another run, hand on the founders found back then
+    match google_webpage_db::get_stored_query_data(
+        &pool,
+        &data.query,
+        DataExtractionIntent::FounderName,
+    )
+    .await
+    {
+        Ok(Some(founder_names)) => {
+            log::info!(
+                "Reusing {} stored founders of: {}",
+                founder_names.len(),
+                data.query
+            );
+            metrics()
+                .queries_scraped
+                .with_label_values(&["founder", "stored"])
+                .inc();
+            record_run_progress(
+                &persistant_data_sender,
+                data.run_id,
+                RunStage::FoundersFound,
+                founder_names.len(),
+            )
+            .await;
+            queue_founder_emails(
+                &email_sender,
+                &persistant_data_sender,
+                &pool,
+                &founder_names,
+                &data,
+            )
+            .await;
+            handle.complete().await;
+            return;
+        }
+        Ok(None) => {}
+        Err(e) => log::error!(
+            "Error while getting stored founders of {}: {:?}",
+            data.query,
+            e
+        ),
+    }
+
     log::info!("Scraping google for founder: {}", data.query);
 
     let google_search_result = extract_data_from_google_search_with_reqwest(
+        &search_engines,
         data.query.clone(),
         GoogleSearchType::Founder(data.domain.clone()),
     )
     .await;
 
+    let result = match &google_search_result {
+        GoogleSearchResult::NotFound => "not_found",
+        GoogleSearchResult::CaptchaBlocked => "captcha",
+        _ => "found",
+    };
+    metrics()
+        .queries_scraped
+        .with_label_values(&["founder", result])
+        .inc();
+
     match google_search_result {
         GoogleSearchResult::NotFound => {
-            if let Err(e) =
-                persistant_data_sender.send(PersistantData::Founder(FounderData::NoResult {
+            if let Err(e) = persistant_data_sender
+                .send(PersistantData::Founder(FounderData::NoResult {
                     query: data.query,
                 }))
+                .await
             {
                 log::error!(
                     "Persistant data sender channel got an Error: {:?} | Source: {:?}",
@@ -87,27 +160,20 @@ async fn scrape_founder_query(
                 .iter()
                 .map(|ele| extract_founder_name(ele.clone()))
                 .collect();
+            let founders_found = founder_names.iter().flatten().count();
+            metrics().founders_extracted.inc_by(founders_found as u64);
+            record_run_progress(
+                &persistant_data_sender,
+                data.run_id,
+                RunStage::FoundersFound,
+                founders_found,
+            )
+            .await;
 
-            let emails: Vec<FounderDomainEmail> = founder_names
-                .clone()
-                .into_iter()
-                .filter_map(|name| {
-                    name.map(|name| construct_email_permutations(&name, &data.domain))
-                })
-                .flatten()
-                .collect();
-
-            for em in emails {
-                email_sender.send(em.clone()).unwrap();
+            let found: Vec<String> = founder_names.iter().flatten().cloned().collect();
+            queue_founder_emails(&email_sender, &persistant_data_sender, &pool, &found, &data)
+                .await;
 
-                if let Err(e) = persistant_data_sender.send(PersistantData::Email(em)) {
-                    log::error!(
-                        "Persistant data sender channel got an Error: {:?} | Source: {:?}",
-                        e,
-                        e.source(),
-                    );
-                }
-            }
             let page_data = FounderPageData {
                 page_source: page_source.clone(),
                 page_number: 1,
@@ -115,11 +181,12 @@ async fn scrape_founder_query(
                 founder_names: founder_names.clone(),
             };
 
-            if let Err(e) =
-                persistant_data_sender.send(PersistantData::Founder(FounderData::Result {
+            if let Err(e) = persistant_data_sender
+                .send(PersistantData::Founder(FounderData::Result {
                     query: data.query,
                     page_data,
                 }))
+                .await
             {
                 log::error!(
                     "Persistant data sender channel got an Error: {:?} | Source: {:?}",
@@ -130,6 +197,56 @@ async fn scrape_founder_query(
         }
         GoogleSearchResult::CaptchaBlocked => {
             log::error!("Returning from captcha blocked on url {}", data.query);
+            handle.retry("Captcha blocked").await;
+            return;
+        }
+    };
+
+    handle.complete().await;
+}
+
+/// Queues the emails of the founders for verification, built with the domain's learned
+/// pattern when there is one
+async fn queue_founder_emails(
+    email_sender: &QueueSender<FounderDomainEmail>,
+    persistant_data_sender: &QueueSender<PersistantData>,
+    pool: &PgPool,
+    founder_names: &[String],
+    data: &FounderQueryChannelData,
+) {
+    let pattern = match email_pattern_db::get_email_pattern(pool, &data.domain).await {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            log::error!("Error fetching email pattern of {}: {:?}", data.domain, e);
+            None
         }
     };
+
+    let emails: Vec<FounderDomainEmail> = founder_names
+        .iter()
+        .flat_map(|name| construct_emails_with_pattern(name, &data.domain, pattern))
+        .map(|em| FounderDomainEmail {
+            run_id: data.run_id,
+            ..em
+        })
+        .collect();
+    metrics().emails_generated.inc_by(emails.len() as u64);
+
+    for em in emails {
+        if let Err(e) = email_sender.send(em.clone()).await {
+            log::error!(
+                "Email verifier queue got an Error: {:?} | Source: {:?}",
+                e,
+                e.source(),
+            );
+        }
+
+        if let Err(e) = persistant_data_sender.send(PersistantData::Email(em)).await {
+            log::error!(
+                "Persistant data sender channel got an Error: {:?} | Source: {:?}",
+                e,
+                e.source(),
+            );
+        }
+    }
 }
@@ -1,25 +1,34 @@
 use std::time::Duration;
 
-use scraper::{Html, Selector};
-use serde::Serialize;
+use url::Url;
 
-use crate::{
-    domain::html_tag::HtmlTag, routes::lead_route::FounderTagCandidate, services::get_random_proxy,
+use crate::{domain::html_tag::HtmlTag, routes::lead_route::FounderTagCandidate};
+
+use super::{
+    ProxyOutcome, SearchEngine, SearchEngineKind, SearchEngines, Serp, SerpFixtureMode,
+    SerpResponse,
 };
 
 const NUM_CAPTCHA_RETRIES: u8 = 10; // Should be > 0
 
 pub enum GoogleSearchType {
-    Domain(Option<String>),
+    Domain(Option<NextPage>),
     Founder(String),
     CompanyName,
 }
 
+/// Next page of a search, only valid on the engine that served the previous page
+#[derive(Debug, Clone)]
+pub struct NextPage {
+    pub search_engine: SearchEngineKind,
+    pub url: String,
+}
+
 pub enum GoogleSearchResult {
     NotFound,
     Domains {
         domain_urls: Vec<HtmlTag>,
-        next_page_url: Option<String>,
+        next_page_url: Option<NextPage>,
         page_source: String,
     },
     Founders(FounderTagCandidate, String),
@@ -30,143 +39,209 @@ pub enum GoogleSearchResult {
     CaptchaBlocked,
 }
 
-#[derive(Serialize)]
-struct GoogleQuery {
-    q: String,
-}
-
 pub async fn extract_data_from_google_search_with_reqwest(
+    search_engines: &SearchEngines,
     query: String,
     search_type: GoogleSearchType,
 ) -> GoogleSearchResult {
-    const GOOGLE_URL: &str = "https://www.google.com/search";
-    let a_tag_selector = Selector::parse("a").unwrap();
-    let footer_selector = Selector::parse("footer").unwrap();
-    let h3_selector = Selector::parse("h3").unwrap();
+    let engines: Vec<&dyn SearchEngine> = match search_type {
+        GoogleSearchType::Domain(Some(ref next_page)) => search_engines
+            .get(next_page.search_engine)
+            .into_iter()
+            .collect(),
+        _ => search_engines.for_search_type(&search_type),
+    };
+
+    for engine in engines {
+        let url = match search_type {
+            GoogleSearchType::Domain(Some(ref next_page)) => match Url::parse(&next_page.url) {
+                Ok(url) => url,
+                Err(e) => {
+                    log::error!("Invalid next page url {}: {:?}", next_page.url, e);
+                    return GoogleSearchResult::CaptchaBlocked;
+                }
+            },
+            _ => engine.search_url(&query),
+        };
+
+        let serp_fixtures = search_engines.serp_fixtures();
+        let response = match serp_fixtures.mode() {
+            SerpFixtureMode::Replay => serp_fixtures
+                .load(engine.kind(), &query, &url)
+                .await
+                .map(|page_source| engine.parse(&url, &page_source)),
+            SerpFixtureMode::Live | SerpFixtureMode::Record => {
+                search_with_engine(search_engines, engine, &url, &query).await
+            }
+        };
+
+        match response {
+            Some(SerpResponse::Page(serp)) => {
+                log::info!("{:?} answered query: {}", engine.kind(), query);
+                return build_search_result(engine.kind(), serp, &search_type);
+            }
+            Some(SerpResponse::NotFound) => {
+                log::error!("Found no results on query: {}", query);
+                return GoogleSearchResult::NotFound;
+            }
+            Some(SerpResponse::Blocked) | None => {
+                log::error!(
+                    "{:?} kept blocking query: {}, failing over",
+                    engine.kind(),
+                    query
+                );
+            }
+        }
+    }
+
+    GoogleSearchResult::CaptchaBlocked
+}
 
+/// Every retry goes through a fresh proxy from the pool, the outcome is reported back so
+/// that captcha'd and unreachable proxies cool down. Returns None when the engine could
+/// not be reached on any retry.
+async fn search_with_engine(
+    search_engines: &SearchEngines,
+    engine: &dyn SearchEngine,
+    url: &Url,
+    query: &str,
+) -> Option<SerpResponse> {
+    let proxy_pool = search_engines.proxy_pool();
     let mut retry_count = 0;
+    let mut response = None;
 
     while retry_count < NUM_CAPTCHA_RETRIES {
-        let proxy = get_random_proxy();
-        let http_proxy = reqwest::Proxy::http(proxy.clone()).unwrap();
-        let https_proxy = reqwest::Proxy::https(proxy.clone()).unwrap();
-
-        let client = reqwest::Client::builder()
-            .proxy(http_proxy)
-            .proxy(https_proxy)
-            .read_timeout(Duration::from_secs(30))
-            .build()
-            .unwrap();
-        let query = GoogleQuery { q: query.clone() };
-
-        let req = match search_type {
-            GoogleSearchType::Domain(Some(ref next_page_url)) => {
-                let url = format!("https://www.google.com{}", next_page_url);
-                client.get(url)
+        let mut client_builder = reqwest::Client::builder().read_timeout(Duration::from_secs(30));
+        let proxy = match engine.use_proxy() {
+            true => match proxy_pool.acquire().await {
+                Some(proxy) => match proxy.to_reqwest_proxy() {
+                    Ok(reqwest_proxy) => {
+                        client_builder = client_builder.proxy(reqwest_proxy);
+                        Some(proxy)
+                    }
+                    Err(e) => {
+                        log::error!("Invalid proxy {}: {:?}", proxy.id, e);
+                        proxy_pool.record(&proxy, ProxyOutcome::Timeout);
+                        retry_count += 1;
+                        continue;
+                    }
+                },
+                None => {
+                    log::error!("No proxies configured for query: {}", query);
+                    return response;
+                }
+            },
+            false => None,
+        };
+        let client = client_builder.build().unwrap();
+        let record = |outcome| {
+            if let Some(ref proxy) = proxy {
+                proxy_pool.record(proxy, outcome);
             }
-            _ => client.get(GOOGLE_URL).query(&query),
         };
 
-        // TODO: Missleading info log because of next page
-        log::info!("Sending reqwest for query: {}", query.q);
+        log::info!("Sending reqwest to {:?} for url: {}", engine.kind(), url);
 
-        match req.send().await {
+        match client.get(url.clone()).send().await {
             Ok(res) => {
-                let html_content_result = res.text().await;
-                if let Err(ref e) = html_content_result {
-                    log::error!("Failed to parse text from html_content. Error: {:?}", e);
-                    retry_count += 1;
-                    continue;
-                }
-                let html_content = html_content_result.unwrap();
-                let html_document = Html::parse_document(&html_content);
-
-                let headings: Vec<String> = html_document
-                    .select(&h3_selector)
-                    .map(|tag| tag.text().collect())
-                    .collect();
-
-                match headings.is_empty() {
-                    true => match html_content.contains("did not match any documents") {
-                        true => {
-                            log::error!("Found no results on query: {}", query.q);
-                            return GoogleSearchResult::NotFound;
-                        }
-                        false => {
-                            log::info!("Got html response: {:?}", html_content);
-                            log::error!("Blocked by captcha on query: {}", query.q);
-                            retry_count += 1;
+                let html_content = match res.text().await {
+                    Ok(html_content) => html_content,
+                    Err(e) => {
+                        log::error!("Failed to parse text from html_content. Error: {:?}", e);
+                        record(ProxyOutcome::Timeout);
+                        retry_count += 1;
+                        continue;
+                    }
+                };
+
+                match engine.parse(url, &html_content) {
+                    SerpResponse::Blocked => {
+                        log::info!("Got html response: {:?}", html_content);
+                        log::error!("Blocked by captcha on query: {}", query);
+                        record(ProxyOutcome::Captcha);
+                        response = Some(SerpResponse::Blocked);
+                        retry_count += 1;
+                    }
+                    serp_response => {
+                        record(ProxyOutcome::Success);
+                        let serp_fixtures = search_engines.serp_fixtures();
+                        if serp_fixtures.mode() == SerpFixtureMode::Record {
+                            serp_fixtures
+                                .save(engine.kind(), query, url, &html_content)
+                                .await;
                         }
-                    },
-                    false => match search_type {
-                        GoogleSearchType::Domain(_) => {
-                            let links: Vec<String> = html_document
-                                .select(&a_tag_selector)
-                                .filter_map(|tag| {
-                                    tag.value().attr("href").map(|url| url.to_string())
-                                })
-                                .collect();
-
-                            let next_page_url = html_document
-                                .select(&footer_selector)
-                                .next()
-                                .and_then(|footer| {
-                                    footer.select(&a_tag_selector).next().and_then(
-                                        |next_page_a_tag| {
-                                            next_page_a_tag.attr("href").map(|url| url.to_string())
-                                        },
-                                    )
-                                });
-
-                            log::info!(
-                                "Found {} urls with next page? {} | Potential domains",
-                                links.len(),
-                                next_page_url.is_some()
-                            );
-
-                            return GoogleSearchResult::Domains {
-                                domain_urls: links.into_iter().map(HtmlTag::ATag).collect(),
-                                next_page_url,
-                                page_source: html_content,
-                            };
-                        }
-                        GoogleSearchType::Founder(ref domain) => {
-                            log::info!("Found {} h3_tags| Potential founder names", headings.len(),);
-
-                            let elements = headings.into_iter().map(HtmlTag::H3Tag).collect();
-
-                            return GoogleSearchResult::Founders(
-                                FounderTagCandidate {
-                                    elements,
-                                    domain: domain.to_string(),
-                                },
-                                html_content,
-                            );
-                        }
-                        GoogleSearchType::CompanyName => {
-                            log::info!("Found {} a_tags| Potential company names", headings.len(),);
-
-                            let links: Vec<String> = html_document
-                                .select(&a_tag_selector)
-                                .filter_map(|tag| {
-                                    tag.value().attr("href").map(|url| url.to_string())
-                                })
-                                .collect();
-
-                            return GoogleSearchResult::CompanyNames {
-                                name_candidates: links.into_iter().map(HtmlTag::ATag).collect(),
-                                page_source: html_content,
-                            };
-                        }
-                    },
+                        return Some(serp_response);
+                    }
                 }
             }
             Err(e) => {
                 log::error!("No response from reqwest, error: {:?}", e);
+                record(ProxyOutcome::Timeout);
                 retry_count += 1;
             }
         }
     }
 
-    GoogleSearchResult::CaptchaBlocked
+    response
+}
+
+fn build_search_result(
+    search_engine: SearchEngineKind,
+    serp: Serp,
+    search_type: &GoogleSearchType,
+) -> GoogleSearchResult {
+    match search_type {
+        GoogleSearchType::Domain(_) => {
+            log::info!(
+                "Found {} urls with next page? {} | Potential domains",
+                serp.results.len(),
+                serp.next_page_url.is_some()
+            );
+
+            GoogleSearchResult::Domains {
+                domain_urls: serp
+                    .results
+                    .into_iter()
+                    .map(|result| HtmlTag::ATag(result.url))
+                    .collect(),
+                next_page_url: serp
+                    .next_page_url
+                    .map(|url| NextPage { search_engine, url }),
+                page_source: serp.page_source,
+            }
+        }
+        GoogleSearchType::Founder(domain) => {
+            log::info!(
+                "Found {} h3_tags| Potential founder names",
+                serp.results.len()
+            );
+
+            GoogleSearchResult::Founders(
+                FounderTagCandidate {
+                    elements: serp
+                        .results
+                        .into_iter()
+                        .map(|result| HtmlTag::H3Tag(result.title))
+                        .collect(),
+                    domain: domain.to_string(),
+                },
+                serp.page_source,
+            )
+        }
+        GoogleSearchType::CompanyName => {
+            log::info!(
+                "Found {} a_tags| Potential company names",
+                serp.results.len()
+            );
+
+            GoogleSearchResult::CompanyNames {
+                name_candidates: serp
+                    .results
+                    .into_iter()
+                    .map(|result| HtmlTag::ATag(result.url))
+                    .collect(),
+                page_source: serp.page_source,
+            }
+        }
+    }
 }
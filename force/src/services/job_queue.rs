@@ -0,0 +1,707 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    future::Future,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{types::JsonValue, PgPool};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::{
+    dal::{config_db, job_db, run_db},
+    domain::job::{JobLane, JobQueue},
+};
+
+use super::ShutdownSignal;
+
+const MAX_ATTEMPTS: i32 = 5;
+const CLAIM_BATCH_SIZE: i64 = 100;
+const LEASE_SECS: f64 = 10.0 * 60.0; // 10 minutes
+const RETRY_BACKOFF_SECS: f64 = 30.0;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const LIMITS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Durable replacement of an mpsc channel, every message is a row in the job table
+/// so that queued work survives restarts and deploys.
+pub fn job_channel<T>(pool: PgPool, queue: JobQueue) -> (QueueSender<T>, QueueReceiver<T>) {
+    let limits = Arc::new(QueueLimits::new(pool.clone(), queue));
+
+    (
+        QueueSender {
+            pool: pool.clone(),
+            queue,
+            lane: JobLane::Scheduled,
+            limits: limits.clone(),
+            data: PhantomData,
+        },
+        QueueReceiver {
+            pool,
+            queue,
+            buffer: VecDeque::new(),
+            limits,
+            workers: Arc::new(AtomicUsize::new(0)),
+            worker_done: Arc::new(Notify::new()),
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+            shutdown: None,
+        },
+    )
+}
+
+/// Capacity and worker concurrency of a queue. They are read from the configuration table
+/// every `LIMITS_REFRESH_INTERVAL` so that throughput can be tuned while running.
+struct QueueLimits {
+    pool: PgPool,
+    queue: JobQueue,
+    cached: Mutex<Option<(Instant, usize, usize)>>,
+}
+
+impl QueueLimits {
+    fn new(pool: PgPool, queue: JobQueue) -> Self {
+        QueueLimits {
+            pool,
+            queue,
+            cached: Mutex::new(None),
+        }
+    }
+
+    async fn capacity(&self) -> usize {
+        self.current().await.0
+    }
+
+    async fn concurrency(&self) -> usize {
+        self.current().await.1
+    }
+
+    async fn current(&self) -> (usize, usize) {
+        if let Some((read_at, capacity, concurrency)) = *self.cached.lock().unwrap() {
+            if read_at.elapsed() < LIMITS_REFRESH_INTERVAL {
+                return (capacity, concurrency);
+            }
+        }
+
+        let capacity = self
+            .read(&format!("queue-capacity-{}", self.queue.config_name()))
+            .await
+            .unwrap_or(self.queue.default_capacity());
+        let concurrency = self
+            .read(&format!("worker-concurrency-{}", self.queue.config_name()))
+            .await
+            .unwrap_or(self.queue.default_concurrency());
+
+        *self.cached.lock().unwrap() = Some((Instant::now(), capacity, concurrency));
+        (capacity, concurrency)
+    }
+
+    async fn read(&self, key: &str) -> Option<usize> {
+        match config_db::get_queue_limit(&self.pool, key).await {
+            Ok(value) => value
+                .and_then(|v| v.parse().ok())
+                .filter(|limit: &usize| *limit > 0),
+            Err(e) => {
+                log::error!("Error while reading {} from configuration: {:?}", key, e);
+                None
+            }
+        }
+    }
+}
+
+pub struct QueueSender<T> {
+    pool: PgPool,
+    queue: JobQueue,
+    lane: JobLane,
+    limits: Arc<QueueLimits>,
+    data: PhantomData<fn(T)>,
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        QueueSender {
+            pool: self.pool.clone(),
+            queue: self.queue,
+            lane: self.lane,
+            limits: self.limits.clone(),
+            data: PhantomData,
+        }
+    }
+}
+
+impl<T> QueueSender<T> {
+    /// Lane of jobs that don't belong to a run, jobs of a run follow the run's lane
+    pub fn with_lane(mut self, lane: JobLane) -> Self {
+        self.lane = lane;
+        self
+    }
+}
+
+impl<T: Serialize> QueueSender<T> {
+    /// Waits while the queue is at capacity, so that a stage can't run ahead of the next one.
+    /// Only jobs in the same or earlier lanes count, background work never holds up a run.
+    pub async fn send(&self, data: T) -> Result<i64, sqlx::Error> {
+        let (payload, run_id, lane) = self.prepare(data).await?;
+
+        loop {
+            let capacity = self.limits.capacity().await;
+            match job_db::count_pending_jobs(&self.pool, self.queue, lane).await {
+                Ok(pending) if pending as usize >= capacity => {
+                    log::info!(
+                        "Queue {:?} is at capacity ({}), waiting to send",
+                        self.queue,
+                        capacity
+                    );
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+                Ok(_) => break,
+                // Sending anyway, a failed count shouldn't stop the pipeline
+                Err(e) => {
+                    log::error!("Error while counting jobs in {:?}: {:?}", self.queue, e);
+                    break;
+                }
+            }
+        }
+
+        job_db::insert_job(&self.pool, self.queue, payload, MAX_ATTEMPTS, run_id, lane).await
+    }
+
+    /// Sends without waiting for capacity. For a stage putting work back on its own queue,
+    /// waiting there would block the workers that drain it.
+    pub async fn send_unbounded(&self, data: T) -> Result<i64, sqlx::Error> {
+        let (payload, run_id, lane) = self.prepare(data).await?;
+
+        job_db::insert_job(&self.pool, self.queue, payload, MAX_ATTEMPTS, run_id, lane).await
+    }
+
+    async fn prepare(&self, data: T) -> Result<(JsonValue, Option<Uuid>, JobLane), sqlx::Error> {
+        let payload = serde_json::to_value(data).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+
+        // Every stage passes the run id of the work along in its channel data
+        let run_id = payload
+            .get("run_id")
+            .and_then(|run_id| run_id.as_str())
+            .and_then(|run_id| Uuid::parse_str(run_id).ok());
+        let lane = match run_id {
+            Some(run_id) => run_db::get_run_lane(&self.pool, run_id)
+                .await?
+                .unwrap_or(self.lane),
+            None => self.lane,
+        };
+
+        Ok((payload, run_id, lane))
+    }
+}
+
+/// Moves a run's waiting work behind other runs, for when the route already has its results.
+/// Work the run creates from here on follows it to the new lane.
+pub async fn demote_run(pool: &PgPool, run_id: Uuid) -> Result<(), sqlx::Error> {
+    run_db::set_run_lane(pool, run_id, JobLane::Backfill).await?;
+    job_db::set_run_jobs_lane(pool, run_id, JobLane::Backfill).await?;
+
+    Ok(())
+}
+
+pub struct QueueReceiver<T> {
+    pool: PgPool,
+    queue: JobQueue,
+    buffer: VecDeque<Job<T>>,
+    limits: Arc<QueueLimits>,
+    workers: Arc<AtomicUsize>,
+    worker_done: Arc<Notify>,
+    /// Claimed jobs that were not completed or retried yet
+    in_flight: Arc<Mutex<HashSet<i64>>>,
+    shutdown: Option<(ShutdownSignal, ShutdownMode)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ShutdownMode {
+    Stop,
+    Drain,
+}
+
+impl<T: DeserializeOwned> QueueReceiver<T> {
+    /// Waits until a job is available in the queue. Jobs are leased in batches,
+    /// a lease that runs out before the job is completed puts it back in the queue.
+    /// Returns `None` once the receiver is shut down.
+    pub async fn recv(&mut self) -> Option<Job<T>> {
+        loop {
+            if self.shutdown_mode() == Some(ShutdownMode::Stop) {
+                self.release_buffer().await;
+                return None;
+            }
+            if let Some(job) = self.buffer.pop_front() {
+                return Some(job);
+            }
+
+            match self.claim().await {
+                Ok(0) => {
+                    if self.shutdown_mode() == Some(ShutdownMode::Drain) {
+                        return None;
+                    }
+                    self.wait_to_poll().await;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("Error while claiming jobs from {:?}: {:?}", self.queue, e);
+                    self.wait_to_poll().await;
+                }
+            }
+        }
+    }
+
+    /// Hands out a claimed job or claims once without waiting, for gathering what is already queued
+    pub async fn try_recv(&mut self) -> Option<Job<T>> {
+        if self.shutdown_mode() == Some(ShutdownMode::Stop) {
+            self.release_buffer().await;
+            return None;
+        }
+        if self.buffer.is_empty() {
+            if let Err(e) = self.claim().await {
+                log::error!("Error while claiming jobs from {:?}: {:?}", self.queue, e);
+            }
+        }
+
+        self.buffer.pop_front()
+    }
+
+    /// Claims a batch of jobs into the buffer, returns how many rows were claimed
+    async fn claim(&mut self) -> Result<usize, sqlx::Error> {
+        // Claiming more than the workers can take would only run down the leases
+        let batch_size = (self.limits.concurrency().await as i64).min(CLAIM_BATCH_SIZE);
+        let rows = job_db::claim_jobs(&self.pool, self.queue, batch_size, LEASE_SECS).await?;
+        let claimed = rows.len();
+
+        for row in rows {
+            match serde_json::from_value(row.payload) {
+                Ok(data) => self.buffer.push_back(Job {
+                    data,
+                    handle: JobHandle::new(
+                        row.id,
+                        row.attempts,
+                        self.pool.clone(),
+                        self.in_flight.clone(),
+                    ),
+                }),
+                Err(e) => {
+                    log::error!("Dead lettering job {} in {:?}: {:?}", row.id, self.queue, e);
+                    if let Err(e) =
+                        job_db::dead_letter_job(&self.pool, row.id, &e.to_string()).await
+                    {
+                        log::error!("Error while dead lettering job: {:?}", e);
+                    }
+                }
+            }
+        }
+
+        Ok(claimed)
+    }
+
+    /// Number of claimed jobs waiting to be handed out by this receiver
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    /// Stops handing out jobs once shutdown is triggered, claimed jobs go back to the queue
+    pub fn stop_on(mut self, signal: ShutdownSignal) -> Self {
+        self.shutdown = Some((signal, ShutdownMode::Stop));
+        self
+    }
+
+    /// Keeps handing out jobs after shutdown is triggered, until the queue is empty
+    pub fn drain_on(mut self, signal: ShutdownSignal) -> Self {
+        self.shutdown = Some((signal, ShutdownMode::Drain));
+        self
+    }
+
+    /// Receiver sharing this one's worker pool, in-flight jobs and shutdown, for restarting
+    /// a crashed handler. Jobs claimed by a receiver that was dropped come back once their
+    /// lease runs out.
+    pub fn resubscribe(&self) -> Self {
+        QueueReceiver {
+            pool: self.pool.clone(),
+            queue: self.queue,
+            buffer: VecDeque::new(),
+            limits: self.limits.clone(),
+            workers: self.workers.clone(),
+            worker_done: self.worker_done.clone(),
+            in_flight: self.in_flight.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// Handle to wait for the queue's workers after the receiver moved into its handler
+    pub fn monitor(&self) -> QueueMonitor {
+        QueueMonitor {
+            pool: self.pool.clone(),
+            queue: self.queue,
+            workers: self.workers.clone(),
+            worker_done: self.worker_done.clone(),
+            in_flight: self.in_flight.clone(),
+        }
+    }
+
+    fn shutdown_mode(&self) -> Option<ShutdownMode> {
+        match &self.shutdown {
+            Some((signal, mode)) if signal.is_triggered() => Some(*mode),
+            _ => None,
+        }
+    }
+
+    /// Sleeps until the next poll, cut short when shutdown is triggered
+    async fn wait_to_poll(&mut self) {
+        match &mut self.shutdown {
+            Some((signal, _)) if !signal.is_triggered() => {
+                tokio::select! {
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                    _ = signal.triggered() => {}
+                }
+            }
+            _ => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+
+    async fn release_buffer(&mut self) {
+        let ids: Vec<i64> = self.buffer.iter().map(|job| job.handle.id).collect();
+        if ids.is_empty() {
+            return;
+        }
+
+        log::info!(
+            "Putting {} claimed jobs back in {:?}",
+            ids.len(),
+            self.queue
+        );
+        if let Err(e) = job_db::release_jobs(&self.pool, &ids).await {
+            log::error!("Error while releasing jobs of {:?}: {:?}", self.queue, e);
+        }
+        self.buffer.clear();
+    }
+
+    /// Waits until fewer jobs than the configured concurrency are being worked on
+    pub async fn acquire_worker(&self) -> Worker {
+        loop {
+            let concurrency = self.limits.concurrency().await;
+            let running = self.workers.load(Ordering::SeqCst);
+
+            if running < concurrency
+                && self
+                    .workers
+                    .compare_exchange(running, running + 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+            {
+                return Worker {
+                    workers: self.workers.clone(),
+                    worker_done: self.worker_done.clone(),
+                };
+            }
+
+            // Also wakes up to pick up a changed concurrency
+            _ = tokio::time::timeout(LIMITS_REFRESH_INTERVAL, self.worker_done.notified()).await;
+        }
+    }
+}
+
+/// Slot in a queue's worker pool, freed when dropped
+pub struct Worker {
+    workers: Arc<AtomicUsize>,
+    worker_done: Arc<Notify>,
+}
+
+impl Worker {
+    /// Runs the task and frees the slot once it is done, meant to be spawned
+    pub async fn run<F: Future>(self, task: F) -> F::Output {
+        task.await
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.workers.fetch_sub(1, Ordering::SeqCst);
+        self.worker_done.notify_one();
+    }
+}
+
+pub struct QueueMonitor {
+    pool: PgPool,
+    queue: JobQueue,
+    workers: Arc<AtomicUsize>,
+    worker_done: Arc<Notify>,
+    in_flight: Arc<Mutex<HashSet<i64>>>,
+}
+
+impl QueueMonitor {
+    /// Waits for running workers until the deadline, then puts the jobs that didn't finish
+    /// back in the queue so that the next start picks them up without waiting for their lease
+    pub async fn drain(&self, deadline: Instant) {
+        while self.workers.load(Ordering::SeqCst) > 0 {
+            let notified = self.worker_done.notified();
+            if tokio::time::timeout_at(deadline.into(), notified)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+
+        let abandoned: Vec<i64> = self.in_flight.lock().unwrap().iter().copied().collect();
+        if !abandoned.is_empty() {
+            log::warn!(
+                "Abandoning {} unfinished jobs of {:?}: {:?}",
+                abandoned.len(),
+                self.queue,
+                abandoned
+            );
+            if let Err(e) = job_db::release_jobs(&self.pool, &abandoned).await {
+                log::error!("Error while releasing jobs of {:?}: {:?}", self.queue, e);
+            }
+        }
+
+        match job_db::count_pending_jobs(&self.pool, self.queue, JobLane::Backfill).await {
+            Ok(pending) => log::info!(
+                "{:?} stopped with {} jobs left for the next start",
+                self.queue,
+                pending
+            ),
+            Err(e) => log::error!("Error while counting jobs in {:?}: {:?}", self.queue, e),
+        }
+    }
+}
+
+pub struct Job<T> {
+    pub data: T,
+    pub handle: JobHandle,
+}
+
+impl<T> Job<T> {
+    pub fn into_parts(self) -> (T, JobHandle) {
+        (self.data, self.handle)
+    }
+}
+
+pub struct JobHandle {
+    id: i64,
+    attempt: i32,
+    pool: PgPool,
+    in_flight: Arc<Mutex<HashSet<i64>>>,
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl JobHandle {
+    fn new(id: i64, attempt: i32, pool: PgPool, in_flight: Arc<Mutex<HashSet<i64>>>) -> Self {
+        in_flight.lock().unwrap().insert(id);
+
+        JobHandle {
+            id,
+            attempt,
+            pool,
+            in_flight,
+        }
+    }
+
+    /// 1 on the first run of the job, higher once it was retried
+    pub fn attempt(&self) -> i32 {
+        self.attempt
+    }
+
+    pub async fn complete(self) {
+        if let Err(e) = job_db::complete_job(&self.pool, self.id).await {
+            log::error!("Error while completing job {}: {:?}", self.id, e);
+        }
+    }
+
+    pub async fn retry(self, error: &str) {
+        if let Err(e) = job_db::retry_job(&self.pool, self.id, error, RETRY_BACKOFF_SECS).await {
+            log::error!("Error while retrying job {}: {:?}", self.id, e);
+        }
+    }
+
+    pub async fn retry_after(self, error: &str, delay: Duration) {
+        if let Err(e) =
+            job_db::retry_job_after(&self.pool, self.id, error, delay.as_secs_f64()).await
+        {
+            log::error!("Error while retrying job {}: {:?}", self.id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::PgPool;
+
+    use crate::{
+        dal::{config_db, run_db},
+        domain::job::{JobLane, JobQueue},
+        services::{ProductQueryChannelData, Shutdown},
+    };
+
+    use super::{demote_run, job_channel};
+
+    #[sqlx::test]
+    async fn send_waits_while_queue_is_at_capacity(pool: PgPool) {
+        config_db::set_queue_limit("queue-capacity-founder-query", 2, &pool)
+            .await
+            .unwrap();
+        let (sender, mut receiver) = job_channel::<String>(pool.clone(), JobQueue::FounderQuery);
+
+        sender.send("first".to_string()).await.unwrap();
+        sender.send("second".to_string()).await.unwrap();
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(1500),
+            sender.send("third".to_string()),
+        )
+        .await;
+        assert!(blocked.is_err());
+
+        // Claiming a job makes room again
+        let job = receiver.recv().await.unwrap();
+        assert_eq!(job.data, "first");
+        tokio::time::timeout(Duration::from_secs(3), sender.send("third".to_string()))
+            .await
+            .expect("Send is still blocked")
+            .unwrap();
+
+        // Putting work back on a full queue never waits
+        tokio::time::timeout(
+            Duration::from_millis(500),
+            sender.send_unbounded("fourth".to_string()),
+        )
+        .await
+        .expect("Unbounded send was blocked")
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn workers_are_limited_by_concurrency(pool: PgPool) {
+        config_db::set_queue_limit("worker-concurrency-email-verifier", 2, &pool)
+            .await
+            .unwrap();
+        let (_, receiver) = job_channel::<String>(pool.clone(), JobQueue::EmailVerifier);
+
+        let first = receiver.acquire_worker().await;
+        let _second = receiver.acquire_worker().await;
+        let third =
+            tokio::time::timeout(Duration::from_millis(200), receiver.acquire_worker()).await;
+        assert!(third.is_err());
+
+        tokio::spawn(first.run(tokio::time::sleep(Duration::from_millis(100))));
+        tokio::time::timeout(Duration::from_secs(1), receiver.acquire_worker())
+            .await
+            .expect("Finished worker didn't free its slot");
+    }
+
+    #[sqlx::test]
+    async fn interactive_runs_are_claimed_first_until_demoted(pool: PgPool) {
+        let (sender, mut receiver) =
+            job_channel::<ProductQueryChannelData>(pool.clone(), JobQueue::ProductQuery);
+        let backfill_sender = sender.clone().with_lane(JobLane::Backfill);
+        let run_id = run_db::insert_run(&pool, "bottles", None, None)
+            .await
+            .unwrap();
+        let query = |query: &str, run_id| ProductQueryChannelData {
+            query: query.to_string(),
+            run_id,
+        };
+
+        backfill_sender
+            .send(query("smart scout", None))
+            .await
+            .unwrap();
+        sender.send(query("manual", None)).await.unwrap();
+        sender.send(query("run", Some(run_id))).await.unwrap();
+
+        let mut claimed = vec![];
+        for _ in 0..3 {
+            let (data, handle) = receiver.recv().await.unwrap().into_parts();
+            claimed.push(data.query);
+            handle.complete().await;
+        }
+        assert_eq!(claimed, vec!["run", "manual", "smart scout"]);
+
+        // Waiting and later work of a demoted run goes behind everything else
+        sender
+            .send(query("run waiting", Some(run_id)))
+            .await
+            .unwrap();
+        demote_run(&pool, run_id).await.unwrap();
+        sender.send(query("run later", Some(run_id))).await.unwrap();
+        backfill_sender
+            .send(query("smart scout", None))
+            .await
+            .unwrap();
+        sender.send(query("manual", None)).await.unwrap();
+
+        let mut claimed = vec![];
+        for _ in 0..4 {
+            let (data, handle) = receiver.recv().await.unwrap().into_parts();
+            claimed.push(data.query);
+            handle.complete().await;
+        }
+        assert_eq!(
+            claimed,
+            vec!["manual", "run waiting", "run later", "smart scout"]
+        );
+    }
+
+    #[sqlx::test]
+    async fn stopped_receiver_puts_unfinished_jobs_back(pool: PgPool) {
+        let shutdown = Shutdown::default();
+        let (sender, receiver) = job_channel::<String>(pool.clone(), JobQueue::FounderQuery);
+        let mut receiver = receiver.stop_on(shutdown.signal());
+        let monitor = receiver.monitor();
+
+        for query in ["dan go", "ceo", "founder"] {
+            sender.send(query.to_string()).await.unwrap();
+        }
+        let (_, _handle) = receiver.recv().await.unwrap().into_parts();
+
+        shutdown.trigger();
+        assert!(receiver.recv().await.is_none());
+        monitor
+            .drain(std::time::Instant::now() + Duration::from_millis(100))
+            .await;
+
+        let jobs = sqlx::query_as::<_, (String, i32)>(
+            "select status::text, attempts from job order by id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(jobs, vec![("PENDING".to_string(), 0); 3]);
+    }
+
+    #[sqlx::test]
+    async fn drained_receiver_empties_the_queue(pool: PgPool) {
+        let shutdown = Shutdown::default();
+        let (sender, receiver) = job_channel::<String>(pool.clone(), JobQueue::PersistantData);
+        let mut receiver = receiver.drain_on(shutdown.signal());
+
+        sender.send("domain".to_string()).await.unwrap();
+        sender.send("email".to_string()).await.unwrap();
+        shutdown.trigger();
+
+        let mut drained = vec![];
+        while let Some(job) = receiver.recv().await {
+            let (data, handle) = job.into_parts();
+            drained.push(data);
+            handle.complete().await;
+        }
+        assert_eq!(drained, vec!["domain", "email"]);
+    }
+}
@@ -0,0 +1,294 @@
+use std::{error::Error, time::Duration};
+
+use actix_web::web::Data;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    dal::{job_db, run_db},
+    domain::run::{RunStage, RunStatus},
+    routes::lead_route::build_seach_query,
+};
+
+use super::{
+    demote_run, save_product_search_queries, OpenaiClient, PersistantData, ProductQueryChannelData,
+    QueueSender,
+};
+
+const RUN_COMPLETION_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize)]
+pub struct RunProgress {
+    pub run_id: Uuid,
+    pub stage: RunStage,
+    pub count: i32,
+}
+
+/// A verified email handed to a run
+#[derive(Serialize, Deserialize)]
+pub struct RunLead {
+    pub run_id: Uuid,
+    pub email: String,
+}
+
+/// Counts work a stage did for a run, work that isn't part of a run is not counted
+pub async fn record_run_progress(
+    persistant_data_sender: &QueueSender<PersistantData>,
+    run_id: Option<Uuid>,
+    stage: RunStage,
+    count: usize,
+) {
+    let Some(run_id) = run_id else {
+        return;
+    };
+    if count == 0 {
+        return;
+    }
+
+    if let Err(e) = persistant_data_sender
+        .send(PersistantData::RunProgress(RunProgress {
+            run_id,
+            stage,
+            count: count as i32,
+        }))
+        .await
+    {
+        log::error!(
+            "Persistant data sender channel got an Error: {:?} | Source: {:?}",
+            e,
+            e.source(),
+        );
+    }
+}
+
+/// Records a verified email as a lead of its run, emails outside of a run are not recorded
+pub async fn record_run_lead(
+    persistant_data_sender: &QueueSender<PersistantData>,
+    run_id: Option<Uuid>,
+    email: &str,
+) {
+    let Some(run_id) = run_id else {
+        return;
+    };
+
+    if let Err(e) = persistant_data_sender
+        .send(PersistantData::RunLead(RunLead {
+            run_id,
+            email: email.to_string(),
+        }))
+        .await
+    {
+        log::error!(
+            "Persistant data sender channel got an Error: {:?} | Source: {:?}",
+            e,
+            e.source(),
+        );
+    }
+}
+
+/// Generates the niche's products and queues their searches, the pipeline takes the run
+/// from there. Meant to be spawned, the route that created the run doesn't wait on it.
+pub async fn start_run(
+    pool: PgPool,
+    openai_client: Data<OpenaiClient>,
+    product_query_sender: QueueSender<ProductQueryChannelData>,
+    run_id: Uuid,
+    niche: String,
+) {
+    // Generating products panics on openai errors, its own task lets the run fail instead
+    let generated = {
+        let (pool, niche) = (pool.clone(), niche.clone());
+        tokio::spawn(
+            async move { save_product_search_queries(&pool, &openai_client, &niche).await },
+        )
+        .await
+    };
+    let products = match generated {
+        Ok(products) if !products.is_empty() => products,
+        Ok(_) => return fail_run(&pool, run_id, "No products were generated").await,
+        Err(e) => {
+            log::error!("Error while generating products of run {}: {:?}", run_id, e);
+            return fail_run(&pool, run_id, "Could not generate products").await;
+        }
+    };
+
+    // Queries scraped before are queued too, the stages reuse what they stored for them
+    for product in products.iter() {
+        let q = build_seach_query(product);
+        if let Err(e) = product_query_sender
+            .send(ProductQueryChannelData {
+                query: q.clone(),
+                run_id: Some(run_id),
+            })
+            .await
+        {
+            log::error!("Error while queueing product query {}: {:?}", q, e);
+        }
+    }
+
+    // Set once the searches are queued, a run with products and no work left is done
+    if let Err(e) = run_db::set_products_generated(&pool, run_id, products.len() as i32).await {
+        log::error!("Error while saving products of run {}: {:?}", run_id, e);
+    }
+}
+
+/// Completes a running run once it verified its target count of emails or has no work left.
+/// Work left over after the target is reached still finishes, behind other runs.
+pub async fn complete_run_if_done(
+    pool: &PgPool,
+    run: &mut run_db::RunRow,
+    pending_jobs: i64,
+) -> Result<(), sqlx::Error> {
+    // Products are still being generated
+    if run.status != RunStatus::Running || run.products_generated == 0 {
+        return Ok(());
+    }
+
+    let target_reached = run
+        .target_count
+        .is_some_and(|target| run.emails_verified >= target);
+    if !target_reached && pending_jobs > 0 {
+        return Ok(());
+    }
+
+    if pending_jobs > 0 {
+        demote_run(pool, run.id).await?;
+    }
+    run_db::finish_run(pool, run.id, RunStatus::Completed, None).await?;
+    run.status = RunStatus::Completed;
+    run.completed_at = Some(Utc::now());
+
+    Ok(())
+}
+
+/// Completes the runs that are done, whether or not anyone is polling them
+pub async fn run_completion_handler(pool: PgPool) {
+    log::info!("Started run completion handler");
+
+    let mut interval = tokio::time::interval(RUN_COMPLETION_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let runs = match run_db::get_running_runs(&pool).await {
+            Ok(runs) => runs,
+            Err(e) => {
+                log::error!("Error while getting running runs: {:?}", e);
+                continue;
+            }
+        };
+
+        for mut run in runs {
+            let pending_jobs: i64 = match job_db::count_run_jobs(&pool, run.id).await {
+                Ok(counts) => counts.into_iter().map(|(_, count)| count).sum(),
+                Err(e) => {
+                    log::error!("Error while counting jobs of run {}: {:?}", run.id, e);
+                    continue;
+                }
+            };
+            if let Err(e) = complete_run_if_done(&pool, &mut run, pending_jobs).await {
+                log::error!("Error while completing run {}: {:?}", run.id, e);
+            }
+        }
+    }
+}
+
+async fn fail_run(pool: &PgPool, run_id: Uuid, error: &str) {
+    log::error!("Run {} failed: {}", run_id, error);
+
+    if let Err(e) = run_db::finish_run(pool, run_id, RunStatus::Failed, Some(error)).await {
+        log::error!("Error while failing run {}: {:?}", run_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::PgPool;
+
+    use crate::{
+        dal::run_db,
+        domain::{
+            job::{JobLane, JobQueue},
+            run::{RunStage, RunStatus},
+        },
+        services::{job_channel, ProductQueryChannelData},
+    };
+
+    use super::{complete_run_if_done, run_completion_handler};
+
+    #[sqlx::test]
+    async fn run_completes_at_its_target_or_when_out_of_work(pool: PgPool) {
+        let (sender, _) = job_channel(pool.clone(), JobQueue::ProductQuery);
+        let target_run = run_db::insert_run(&pool, "bottles", Some(1), None)
+            .await
+            .unwrap();
+        let open_run = run_db::insert_run(&pool, "cups", None, None).await.unwrap();
+        for run_id in [target_run, open_run] {
+            sender
+                .send(ProductQueryChannelData {
+                    query: "water bottles".to_string(),
+                    run_id: Some(run_id),
+                })
+                .await
+                .unwrap();
+            run_db::set_products_generated(&pool, run_id, 10)
+                .await
+                .unwrap();
+        }
+
+        let mut con = pool.acquire().await.unwrap();
+        run_db::add_run_progress(&mut con, target_run, RunStage::EmailsVerified, 1)
+            .await
+            .unwrap();
+
+        // Target reached with work left, the rest of it moves behind other runs
+        let mut run = run_db::get_run(&pool, target_run).await.unwrap().unwrap();
+        assert_eq!(run.emails_verified, 1);
+        complete_run_if_done(&pool, &mut run, 1).await.unwrap();
+        assert_eq!(run.status, RunStatus::Completed);
+        let lane: JobLane = sqlx::query_scalar("select lane from job where run_id = $1")
+            .bind(target_run)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(lane, JobLane::Backfill);
+
+        // Without a target a run goes on until its work is done
+        let mut run = run_db::get_run(&pool, open_run).await.unwrap().unwrap();
+        complete_run_if_done(&pool, &mut run, 1).await.unwrap();
+        assert_eq!(run.status, RunStatus::Running);
+        complete_run_if_done(&pool, &mut run, 0).await.unwrap();
+        let run = run_db::get_run(&pool, open_run).await.unwrap().unwrap();
+        assert_eq!(run.status, RunStatus::Completed);
+        assert!(run.completed_at.is_some());
+    }
+
+    #[sqlx::test]
+    async fn runs_are_completed_without_being_polled(pool: PgPool) {
+        let run_id = run_db::insert_run(&pool, "cups", None, None).await.unwrap();
+        run_db::set_products_generated(&pool, run_id, 10)
+            .await
+            .unwrap();
+
+        let handler = tokio::spawn(run_completion_handler(pool.clone()));
+        let mut status = RunStatus::Running;
+        for _ in 0..20 {
+            status = run_db::get_run(&pool, run_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .status;
+            if status != RunStatus::Running {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        handler.abort();
+
+        assert_eq!(status, RunStatus::Completed);
+    }
+}
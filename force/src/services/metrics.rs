@@ -0,0 +1,205 @@
+use std::sync::OnceLock;
+
+use prometheus::{
+    core::Collector, proto::MetricType, Encoder, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use sqlx::PgPool;
+
+use crate::dal::job_db;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Pipeline counters and histograms, scraped from `/metrics` in the prometheus text format
+pub struct Metrics {
+    registry: Registry,
+    /// Jobs by queue and status, refreshed from the job table on every scrape
+    pub queue_jobs: IntGaugeVec,
+    pub queries_scraped: IntCounterVec,
+    pub captcha_blocks: IntCounterVec,
+    pub founders_extracted: IntCounter,
+    pub emails_generated: IntCounter,
+    pub verifications: IntCounterVec,
+    pub db_write_seconds: HistogramVec,
+    pub openai_tokens: IntCounterVec,
+}
+
+/// One line of the dashboard's metrics table
+pub struct MetricSummary {
+    pub name: String,
+    pub labels: String,
+    pub value: String,
+}
+
+/// Shared by every stage, handlers record into it without having it passed around
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("smmac".to_string()), None).expect("Metrics prefix is valid");
+
+        let metrics = Metrics {
+            queue_jobs: IntGaugeVec::new(
+                Opts::new("queue_jobs", "Jobs in a queue by status"),
+                &["queue", "status"],
+            )
+            .unwrap(),
+            queries_scraped: IntCounterVec::new(
+                Opts::new("queries_scraped_total", "Search queries scraped by a stage"),
+                &["stage", "result"],
+            )
+            .unwrap(),
+            captcha_blocks: IntCounterVec::new(
+                Opts::new("captcha_blocks_total", "Searches blocked by a captcha"),
+                &["proxy"],
+            )
+            .unwrap(),
+            founders_extracted: IntCounter::new(
+                "founders_extracted_total",
+                "Founder names extracted from search results",
+            )
+            .unwrap(),
+            emails_generated: IntCounter::new(
+                "emails_generated_total",
+                "Email candidates generated from founder names",
+            )
+            .unwrap(),
+            verifications: IntCounterVec::new(
+                Opts::new("verifications_total", "Email verifications by outcome"),
+                &["outcome"],
+            )
+            .unwrap(),
+            db_write_seconds: HistogramVec::new(
+                HistogramOpts::new("db_write_seconds", "Time to persist a pipeline message"),
+                &["kind"],
+            )
+            .unwrap(),
+            openai_tokens: IntCounterVec::new(
+                Opts::new("openai_tokens_total", "Tokens used on openai"),
+                &["kind"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: Vec<Box<dyn Collector>> = vec![
+            Box::new(metrics.queue_jobs.clone()),
+            Box::new(metrics.queries_scraped.clone()),
+            Box::new(metrics.captcha_blocks.clone()),
+            Box::new(metrics.founders_extracted.clone()),
+            Box::new(metrics.emails_generated.clone()),
+            Box::new(metrics.verifications.clone()),
+            Box::new(metrics.db_write_seconds.clone()),
+            Box::new(metrics.openai_tokens.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("Metric names are unique");
+        }
+
+        metrics
+    }
+
+    /// Reads the queue depths from the job table, the other metrics are recorded as they happen
+    pub async fn refresh_queue_jobs(&self, pool: &PgPool) {
+        match job_db::count_jobs_by_status(pool).await {
+            Ok(rows) => {
+                // Statuses that emptied out since the last scrape drop back to 0
+                self.queue_jobs.reset();
+                for row in rows {
+                    self.queue_jobs
+                        .with_label_values(&[row.queue.config_name(), &row.status.to_lowercase()])
+                        .set(row.count);
+                }
+            }
+            Err(e) => log::error!("Error while counting jobs for metrics: {:?}", e),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Error while encoding metrics: {:?}", e);
+        }
+
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+
+    /// Every series with its current value, histograms as their count and mean
+    pub fn summary(&self) -> Vec<MetricSummary> {
+        let mut summary = vec![];
+
+        for family in self.registry.gather() {
+            for metric in family.get_metric() {
+                let labels = metric
+                    .get_label()
+                    .iter()
+                    .map(|l| format!("{}={}", l.get_name(), l.get_value()))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+
+                let value = match family.get_field_type() {
+                    MetricType::COUNTER => metric.get_counter().get_value().to_string(),
+                    MetricType::GAUGE => metric.get_gauge().get_value().to_string(),
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        let count = histogram.get_sample_count();
+                        match count {
+                            0 => "0".to_string(),
+                            _ => format!(
+                                "{} in {:.3}s on average",
+                                count,
+                                histogram.get_sample_sum() / count as f64
+                            ),
+                        }
+                    }
+                    _ => continue,
+                };
+
+                summary.push(MetricSummary {
+                    name: family.get_name().to_string(),
+                    labels,
+                    value,
+                });
+            }
+        }
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{domain::job::JobQueue, services::job_channel};
+
+    use super::metrics;
+
+    #[sqlx::test]
+    async fn queue_jobs_and_counters_are_rendered(pool: PgPool) {
+        let (sender, _) = job_channel::<String>(pool.clone(), JobQueue::FounderQuery);
+        sender.send("bottles".to_string()).await.unwrap();
+        sender.send("cups".to_string()).await.unwrap();
+
+        metrics().refresh_queue_jobs(&pool).await;
+        metrics()
+            .queries_scraped
+            .with_label_values(&["founder", "found"])
+            .inc();
+
+        let rendered = metrics().render();
+        assert!(rendered.contains(r#"smmac_queue_jobs{queue="founder-query",status="pending"} 2"#));
+        assert!(rendered.contains("# TYPE smmac_queries_scraped_total counter"));
+
+        let summary = metrics().summary();
+        assert!(summary.iter().any(|s| s.name == "smmac_queue_jobs"
+            && s.labels == "queue=founder-query, status=pending"
+            && s.value == "2"));
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::web::Data;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::configuration::{EmailVerificationSettings, SmtpVerifierSettings};
+
+use super::{EmailVerifierKind, Sentinel, SmtpVerifier, StaticMxResolver, VerificationStrategy};
+
+/// How the mock server answers, every connection follows the same script
+#[derive(Debug, Clone)]
+pub enum MockSmtpScenario {
+    /// Every recipient is accepted, like a catch-all domain
+    AcceptAll,
+    /// Only the listed mailboxes are accepted, others get `550 5.1.1`
+    RejectUnknown(Vec<String>),
+    /// A recipient is refused with `450 4.2.0` the first time it is asked about, then accepted
+    Greylist,
+    /// Waits before greeting, to run into the client's timeouts
+    Slow(Duration),
+    /// Hangs up right after the greeting
+    Disconnect,
+}
+
+/// Scriptable smtp server on a random local port, stopped when dropped
+pub struct MockSmtpServer {
+    address: SocketAddr,
+    connections: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl MockSmtpServer {
+    pub async fn start(scenario: MockSmtpScenario) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind mock smtp server");
+        let address = listener.local_addr().unwrap();
+        let greylisted = Arc::new(Mutex::new(HashSet::new()));
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accepted = connections.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(serve(stream, scenario.clone(), greylisted.clone()));
+            }
+        });
+
+        MockSmtpServer {
+            address,
+            connections,
+            task,
+        }
+    }
+
+    /// Host with port, to hand out from a `StaticMxResolver`
+    pub fn mx_host(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// Connections accepted since the server started
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockSmtpServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Short timeouts so that slow scenarios fail fast
+pub fn mock_smtp_settings() -> SmtpVerifierSettings {
+    SmtpVerifierSettings {
+        hello_name: "verywellfit.com".to_string(),
+        from_email: "random.guy@fit.com".to_string(),
+        port: 25,
+        connect_timeout_secs: 1,
+        command_timeout_secs: 1,
+        max_mx_hosts: 3,
+        max_connections_per_host: 2,
+        max_probes_per_minute_per_host: 60_000,
+        max_recipients_per_session: 20,
+        greylist_retry_secs: 0,
+    }
+}
+
+/// Sentinel that only verifies over smtp, with `domain` pointed at the mock server
+pub fn mock_sentinel(domain: &str, server: &MockSmtpServer) -> Data<Sentinel> {
+    Data::new(Sentinel::new(
+        EmailVerificationSettings {
+            verifiers: vec![EmailVerifierKind::Smtp],
+            strategy: VerificationStrategy::FirstConclusive,
+        },
+        String::new(),
+        SmtpVerifier::with_resolver(
+            mock_smtp_settings(),
+            StaticMxResolver::default().with_domain(domain, vec![server.mx_host()]),
+        ),
+    ))
+}
+
+async fn serve(
+    stream: TcpStream,
+    scenario: MockSmtpScenario,
+    greylisted: Arc<Mutex<HashSet<String>>>,
+) {
+    let mut stream = BufReader::new(stream);
+
+    if let MockSmtpScenario::Slow(delay) = scenario {
+        tokio::time::sleep(delay).await;
+    }
+    if write(&mut stream, "220 mock.smtp ESMTP ready")
+        .await
+        .is_err()
+    {
+        return;
+    }
+    if let MockSmtpScenario::Disconnect = scenario {
+        return;
+    }
+
+    loop {
+        let mut line = String::new();
+        match stream.read_line(&mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        let line = line.trim_end();
+        let command = line.to_uppercase();
+
+        let reply = if command.starts_with("EHLO") {
+            "250-mock.smtp\r\n250 SMTPUTF8".to_string()
+        } else if command.starts_with("HELO") || command.starts_with("MAIL FROM") {
+            "250 2.1.0 OK".to_string()
+        } else if command.starts_with("RCPT TO") {
+            let recipient = line
+                .split_once(':')
+                .map(|(_, r)| r.trim().trim_matches(['<', '>']).to_lowercase())
+                .unwrap_or_default();
+            rcpt_reply(&scenario, &greylisted, recipient)
+        } else if command.starts_with("QUIT") {
+            _ = write(&mut stream, "221 2.0.0 Bye").await;
+            return;
+        } else {
+            "502 5.5.2 Command not recognized".to_string()
+        };
+
+        if write(&mut stream, &reply).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn rcpt_reply(
+    scenario: &MockSmtpScenario,
+    greylisted: &Mutex<HashSet<String>>,
+    recipient: String,
+) -> String {
+    match scenario {
+        MockSmtpScenario::RejectUnknown(mailboxes) if !mailboxes.contains(&recipient) => {
+            format!(
+                "550 5.1.1 <{}>: Recipient address rejected: User unknown",
+                recipient
+            )
+        }
+        MockSmtpScenario::Greylist if greylisted.lock().unwrap().insert(recipient.clone()) => {
+            format!(
+                "450 4.2.0 <{}>: Recipient address rejected: Greylisted",
+                recipient
+            )
+        }
+        _ => "250 2.1.5 OK".to_string(),
+    }
+}
+
+async fn write(stream: &mut BufReader<TcpStream>, reply: &str) -> std::io::Result<()> {
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", reply).as_bytes())
+        .await
+}
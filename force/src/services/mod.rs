@@ -1,21 +1,55 @@
+pub mod api_key;
+pub mod auth;
 pub mod data_persistance;
+pub mod dedupe;
 pub mod domain_qualifier;
 pub mod domain_scraper;
 pub mod droid;
+pub mod email_pattern;
+pub mod email_verification;
 pub mod email_verifier;
 pub mod founder_scraper;
 pub mod google_scraper;
+pub mod job_queue;
+pub mod lead_run;
+pub mod metrics;
+#[cfg(test)]
+pub mod mock_smtp;
+pub mod mx_host_limiter;
 pub mod openai_client;
+pub mod proxy_pool;
+pub mod reextraction;
+pub mod search_engine;
 pub mod sentinel;
+pub mod serp_fixture;
+pub mod shutdown;
 pub mod smart_scout_scraper;
+pub mod smtp_verifier;
+pub mod supervisor;
 
+pub use api_key::*;
+pub use auth::*;
 pub use data_persistance::*;
+pub use dedupe::*;
 pub use domain_qualifier::*;
 pub use domain_scraper::*;
 pub use droid::*;
+pub use email_pattern::*;
+pub use email_verification::*;
 pub use email_verifier::*;
 pub use founder_scraper::*;
 pub use google_scraper::*;
+pub use job_queue::*;
+pub use lead_run::*;
+pub use metrics::*;
+pub use mx_host_limiter::*;
 pub use openai_client::*;
+pub use proxy_pool::*;
+pub use reextraction::*;
+pub use search_engine::*;
 pub use sentinel::*;
+pub use serp_fixture::*;
+pub use shutdown::*;
 pub use smart_scout_scraper::*;
+pub use smtp_verifier::*;
+pub use supervisor::*;
@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+struct HostLimit {
+    connections: Arc<Semaphore>,
+    next_probe_at: Mutex<Instant>,
+}
+
+/// Keeps our smtp traffic to each mx host under a number of open connections and a
+/// probe rate, big providers blocklist ips that open too many sessions at once.
+pub struct MxHostLimiter {
+    max_connections: usize,
+    probe_interval: Duration,
+    hosts: Mutex<HashMap<String, Arc<HostLimit>>>,
+}
+
+impl MxHostLimiter {
+    pub fn new(max_connections_per_host: usize, max_probes_per_minute_per_host: u32) -> Self {
+        MxHostLimiter {
+            max_connections: max_connections_per_host.max(1),
+            probe_interval: Duration::from_secs(60) / max_probes_per_minute_per_host.max(1),
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn host(&self, mx_host: &str) -> Arc<HostLimit> {
+        let mut hosts = self.hosts.lock().unwrap();
+
+        hosts
+            .entry(mx_host.to_lowercase())
+            .or_insert_with(|| {
+                Arc::new(HostLimit {
+                    connections: Arc::new(Semaphore::new(self.max_connections)),
+                    next_probe_at: Mutex::new(Instant::now()),
+                })
+            })
+            .clone()
+    }
+
+    /// Waits for a free connection slot, the slot is given back when the permit is dropped
+    pub async fn acquire_connection(&self, mx_host: &str) -> OwnedSemaphorePermit {
+        self.host(mx_host)
+            .connections
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Mx host semaphore is never closed")
+    }
+
+    /// Waits for the next free probe slot, slots are spread evenly over the minute
+    pub async fn wait_for_probe(&self, mx_host: &str) {
+        let host = self.host(mx_host);
+        let slot = {
+            let mut next_probe_at = host.next_probe_at.lock().unwrap();
+            let slot = (*next_probe_at).max(Instant::now());
+            *next_probe_at = slot + self.probe_interval;
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::time::Instant;
+
+    use super::MxHostLimiter;
+
+    #[tokio::test]
+    async fn connections_are_limited_per_host() {
+        let limiter = Arc::new(MxHostLimiter::new(2, 60_000));
+        let open = Arc::new(AtomicUsize::new(0));
+        let max_open = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..6)
+            .map(|_| {
+                let (limiter, open, max_open) = (limiter.clone(), open.clone(), max_open.clone());
+                tokio::spawn(async move {
+                    let _permit = limiter.acquire_connection("aspmx.l.google.com").await;
+                    let now_open = open.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_open.fetch_max(now_open, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    open.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(max_open.load(Ordering::SeqCst), 2);
+
+        // Other hosts have their own slots
+        let _first = limiter.acquire_connection("aspmx.l.google.com").await;
+        let _second = limiter.acquire_connection("ASPMX.L.GOOGLE.COM").await;
+        let other = tokio::time::timeout(
+            Duration::from_millis(100),
+            limiter.acquire_connection("mx.outlook.com"),
+        )
+        .await;
+        assert!(other.is_ok());
+    }
+
+    #[tokio::test]
+    async fn probes_are_spread_over_the_minute() {
+        // One probe every 100ms
+        let limiter = MxHostLimiter::new(1, 600);
+        let start = Instant::now();
+
+        for _ in 0..4 {
+            limiter.wait_for_probe("aspmx.l.google.com").await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        let start = Instant::now();
+        limiter.wait_for_probe("mx.outlook.com").await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
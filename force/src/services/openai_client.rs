@@ -9,6 +9,8 @@ use sqlx::PgPool;
 
 use crate::dal::{config_db, niche_db};
 
+use super::metrics;
+
 pub const FRESH_RESULTS: bool = true; // Default to false
 
 pub struct OpenaiClient {
@@ -46,6 +48,15 @@ impl OpenaiClient {
 
         let response = self.client.chat().create(request).await?;
         log::info!("Response: {:?}", response);
+        if let Some(usage) = &response.usage {
+            let tokens = &metrics().openai_tokens;
+            tokens
+                .with_label_values(&["prompt"])
+                .inc_by(usage.prompt_tokens as u64);
+            tokens
+                .with_label_values(&["completion"])
+                .inc_by(usage.completion_tokens as u64);
+        }
 
         let first_choice = response
             .choices
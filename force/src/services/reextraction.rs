@@ -0,0 +1,364 @@
+use std::error::Error;
+
+use sqlx::PgPool;
+
+use crate::{
+    dal::{
+        data_extract_db, email_db, email_pattern_db,
+        reextraction_db::{self, ReextractionCounts},
+    },
+    domain::{
+        data_extract::{DataExtract, EXTRACTOR_VERSION},
+        email::{
+            construct_emails_with_pattern, Email, FounderDomainEmail, Reachability,
+            VerificationStatus,
+        },
+        google_webpage::DataExtractionIntent,
+        html_tag::{extract_domain, extract_founder_name},
+    },
+    routes::lead_route::parse_founder_search_query,
+};
+
+use super::QueueSender;
+
+const BATCH_SIZE: i64 = 1000;
+
+/// Replays stored html tags through the current extractors, saving results under
+/// `EXTRACTOR_VERSION` and counting how they differ from the previous version.
+/// Every processed tag is marked, including those the extractor found nothing in, and
+/// marked tags are skipped so a run can be resumed.
+///
+/// Company names are out of scope: they are picked by scoring every domain of a page
+/// against the smart scout company's name, which the stored lowercased query doesn't keep,
+/// so replaying the tags would not reproduce them.
+pub async fn reextract(
+    pool: PgPool,
+    reextraction_id: i64,
+    intent: DataExtractionIntent,
+    regenerate_emails: bool,
+    email_sender: QueueSender<FounderDomainEmail>,
+) {
+    log::info!(
+        "Started re-extraction {} of {:?} with extractor version {}",
+        reextraction_id,
+        intent,
+        EXTRACTOR_VERSION
+    );
+    if intent == DataExtractionIntent::CompanyName {
+        log::error!("Company names can not be re-extracted");
+        if let Err(e) = reextraction_db::finish_reextraction(&pool, reextraction_id).await {
+            log::error!("Error finishing re-extraction: {:?}", e);
+        }
+        return;
+    }
+    let mut after_html_tag_id = 0;
+
+    loop {
+        let candidates = match reextraction_db::get_reextraction_candidates(
+            &pool,
+            intent,
+            EXTRACTOR_VERSION,
+            after_html_tag_id,
+            BATCH_SIZE,
+        )
+        .await
+        {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                log::error!(
+                    "Re-extraction {} got an Error: {:?} | Source: {:?}",
+                    reextraction_id,
+                    e,
+                    e.source(),
+                );
+                return;
+            }
+        };
+        let Some(last) = candidates.last() else {
+            break;
+        };
+        after_html_tag_id = last.html_tag_id;
+
+        let mut counts = ReextractionCounts::default();
+
+        for candidate in candidates {
+            let tag = candidate.html_tag_type.with_content(candidate.text_content);
+
+            let current = match intent {
+                DataExtractionIntent::Domain => extract_domain(tag),
+                DataExtractionIntent::FounderName => extract_founder_name(tag),
+                // Turned away above
+                DataExtractionIntent::CompanyName => None,
+            };
+
+            let mut tag_counts = ReextractionCounts {
+                tags_processed: 1,
+                ..Default::default()
+            };
+            let is_new = match (&candidate.previous_data, &current) {
+                (None, None) => false,
+                (Some(_), None) => {
+                    tag_counts.removed += 1;
+                    false
+                }
+                (None, Some(_)) => {
+                    tag_counts.added += 1;
+                    true
+                }
+                (Some(previous), Some(current)) => match previous == current {
+                    true => {
+                        tag_counts.unchanged += 1;
+                        false
+                    }
+                    false => {
+                        tag_counts.changed += 1;
+                        true
+                    }
+                },
+            };
+
+            let data = current.clone().map(|current| match intent {
+                DataExtractionIntent::Domain => DataExtract::Domain(current),
+                _ => DataExtract::FounderName(current),
+            });
+
+            let mut emails = vec![];
+            if regenerate_emails && is_new && intent == DataExtractionIntent::FounderName {
+                if let (Some(founder_name), Some(domain)) =
+                    (current, parse_founder_search_query(&candidate.search_query))
+                {
+                    let pattern = email_pattern_db::get_email_pattern(&pool, &domain)
+                        .await
+                        .unwrap_or_default();
+                    emails = construct_emails_with_pattern(&founder_name, &domain, pattern);
+                }
+            }
+
+            // A tag that could not be saved stays unmarked, resuming the run picks it up again
+            let emails = match save_reextracted_tag(&pool, candidate.html_tag_id, data, emails)
+                .await
+            {
+                Ok(emails) => emails,
+                Err(e) => {
+                    log::error!(
+                        "Re-extraction {} got an Error: {:?} | Source: {:?}",
+                        reextraction_id,
+                        e,
+                        e.source(),
+                    );
+                    if let Err(e) =
+                        reextraction_db::add_reextraction_counts(&pool, reextraction_id, &counts)
+                            .await
+                    {
+                        log::error!("Error saving re-extraction counts: {:?}", e);
+                    }
+                    return;
+                }
+            };
+            tag_counts.emails_generated = emails.len() as i64;
+            counts.add(&tag_counts);
+
+            for em in emails {
+                if let Err(e) = email_sender.send(em).await {
+                    log::error!(
+                        "Email verifier queue got an Error: {:?} | Source: {:?}",
+                        e,
+                        e.source(),
+                    );
+                }
+            }
+        }
+
+        if let Err(e) =
+            reextraction_db::add_reextraction_counts(&pool, reextraction_id, &counts).await
+        {
+            log::error!("Error saving re-extraction counts: {:?}", e);
+        }
+    }
+
+    if let Err(e) = reextraction_db::finish_reextraction(&pool, reextraction_id).await {
+        log::error!("Error finishing re-extraction: {:?}", e);
+    }
+    log::info!("Finished re-extraction {}", reextraction_id);
+}
+
+/// Saves the tag's data, its marker and the emails generated from it in one transaction,
+/// so a tag is only marked once everything it yields is stored. Returns the emails that
+/// did not exist yet, the others were verified before.
+async fn save_reextracted_tag(
+    pool: &PgPool,
+    html_tag_id: i64,
+    data: Option<DataExtract>,
+    emails: Vec<FounderDomainEmail>,
+) -> Result<Vec<FounderDomainEmail>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    if let Some(data) = data {
+        data_extract_db::insert_data(&mut tx, data, html_tag_id).await?;
+    }
+    data_extract_db::insert_extraction_marker(&mut tx, html_tag_id).await?;
+
+    let mut new_emails = vec![];
+    for em in emails {
+        let email = Email {
+            email_address: em.email.clone(),
+            founder_name: em.founder_name.clone(),
+            domain: em.domain.clone(),
+            verification_status: VerificationStatus::Pending,
+            reachability: Reachability::Unknown,
+            run_id: None,
+        };
+        if email_db::insert_email_if_new(&mut tx, email).await? {
+            new_emails.push(em);
+        }
+    }
+
+    tx.commit().await?;
+    Ok(new_emails)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        dal::{data_extract_db, google_webpage_db, reextraction_db},
+        domain::{
+            data_extract::{DataExtract, EXTRACTOR_VERSION},
+            google_webpage::{DataExtractionIntent, GoogleWebPage},
+            html_tag::HtmlTag,
+            job::JobQueue,
+        },
+        services::job_channel,
+    };
+
+    use super::reextract;
+
+    #[sqlx::test]
+    async fn tags_without_data_are_not_processed_twice(pool: PgPool) {
+        let mut con = pool.acquire().await.unwrap();
+        let web_page_id = google_webpage_db::insert_web_page(
+            &mut con,
+            GoogleWebPage {
+                search_query: "water bottles".to_string(),
+                page_source: "".to_string(),
+                page_number: 1,
+                data_extraction_intent: DataExtractionIntent::Domain,
+                any_result: true,
+            },
+        )
+        .await
+        .unwrap();
+        data_extract_db::insert_html_tags_with_data(
+            &mut con,
+            web_page_id,
+            vec![
+                (
+                    HtmlTag::ATag("https://verywellfit.com".to_string()),
+                    Some(DataExtract::Domain("verywellfit.com".to_string())),
+                ),
+                (HtmlTag::ATag("not a link".to_string()), None),
+            ],
+        )
+        .await
+        .unwrap();
+        // Like pages saved by an older extractor
+        sqlx::query("delete from html_tag_extraction")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let (email_sender, _) = job_channel(pool.clone(), JobQueue::EmailVerifier);
+        let mut tags_processed = vec![];
+        for _ in 0..2 {
+            let reextraction_id = reextraction_db::insert_reextraction(
+                &pool,
+                EXTRACTOR_VERSION,
+                DataExtractionIntent::Domain,
+                false,
+            )
+            .await
+            .unwrap();
+            reextract(
+                pool.clone(),
+                reextraction_id,
+                DataExtractionIntent::Domain,
+                false,
+                email_sender.clone(),
+            )
+            .await;
+
+            let reextraction = reextraction_db::get_reextraction(&pool, reextraction_id)
+                .await
+                .unwrap()
+                .unwrap();
+            tags_processed.push(reextraction.tags_processed);
+        }
+
+        assert_eq!(tags_processed, vec![2, 0]);
+    }
+
+    #[sqlx::test]
+    async fn tags_whose_data_failed_to_save_stay_unmarked(pool: PgPool) {
+        let mut con = pool.acquire().await.unwrap();
+        let web_page_id = google_webpage_db::insert_web_page(
+            &mut con,
+            GoogleWebPage {
+                search_query: "water bottles".to_string(),
+                page_source: "".to_string(),
+                page_number: 1,
+                data_extraction_intent: DataExtractionIntent::Domain,
+                any_result: true,
+            },
+        )
+        .await
+        .unwrap();
+        data_extract_db::insert_html_tags_with_data(
+            &mut con,
+            web_page_id,
+            vec![(HtmlTag::ATag("https://verywellfit.com".to_string()), None)],
+        )
+        .await
+        .unwrap();
+        sqlx::query("delete from html_tag_extraction")
+            .execute(&pool)
+            .await
+            .unwrap();
+        // Every new data extract is rejected from here on
+        sqlx::query("alter table data_extract add constraint reject_all check (false) not valid")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let (email_sender, _) = job_channel(pool.clone(), JobQueue::EmailVerifier);
+        let reextraction_id = reextraction_db::insert_reextraction(
+            &pool,
+            EXTRACTOR_VERSION,
+            DataExtractionIntent::Domain,
+            false,
+        )
+        .await
+        .unwrap();
+        reextract(
+            pool.clone(),
+            reextraction_id,
+            DataExtractionIntent::Domain,
+            false,
+            email_sender,
+        )
+        .await;
+
+        let markers: i64 = sqlx::query_scalar("select count(*) from html_tag_extraction")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(markers, 0);
+
+        let reextraction = reextraction_db::get_reextraction(&pool, reextraction_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reextraction.tags_processed, 0);
+        assert!(reextraction.finished_at.is_none());
+    }
+}
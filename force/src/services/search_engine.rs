@@ -0,0 +1,558 @@
+use actix_web::web::Data;
+use scraper::{ElementRef, Html, Selector};
+use serde::Deserialize;
+use url::Url;
+
+use crate::configuration::SearchEngineSettings;
+
+use super::{GoogleSearchType, ProxyPool, SerpFixtures};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchEngineKind {
+    Google,
+    Bing,
+    DuckDuckGo,
+    Searxng,
+}
+
+impl SearchEngineKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchEngineKind::Google => "google",
+            SearchEngineKind::Bing => "bing",
+            SearchEngineKind::DuckDuckGo => "duckduckgo",
+            SearchEngineKind::Searxng => "searxng",
+        }
+    }
+}
+
+pub struct SerpResult {
+    pub title: String,
+    pub url: String,
+    pub snippet: String,
+}
+
+pub struct Serp {
+    pub results: Vec<SerpResult>,
+    /// Absolute url of the next results page
+    pub next_page_url: Option<String>,
+    pub page_source: String,
+}
+
+pub enum SerpResponse {
+    Page(Serp),
+    NotFound,
+    Blocked,
+}
+
+/// A search engine results page provider. Engines only build urls and parse responses,
+/// sending the request (proxies, retries, failover) is left to the google scraper.
+pub trait SearchEngine: Send + Sync {
+    fn kind(&self) -> SearchEngineKind;
+
+    fn search_url(&self, query: &str) -> Url;
+
+    /// `url` is the url the page was requested from, used to resolve relative links
+    fn parse(&self, url: &Url, page_source: &str) -> SerpResponse;
+
+    fn use_proxy(&self) -> bool {
+        true
+    }
+}
+
+pub struct SearchEngines {
+    engines: Vec<Box<dyn SearchEngine>>,
+    domain: Vec<SearchEngineKind>,
+    founder: Vec<SearchEngineKind>,
+    company_name: Vec<SearchEngineKind>,
+    proxy_pool: Data<ProxyPool>,
+    serp_fixtures: SerpFixtures,
+}
+
+impl SearchEngines {
+    pub fn new(
+        settings: SearchEngineSettings,
+        proxy_pool: Data<ProxyPool>,
+        serp_fixtures: SerpFixtures,
+    ) -> Self {
+        let mut engines: Vec<Box<dyn SearchEngine>> =
+            vec![Box::new(Google), Box::new(Bing), Box::new(DuckDuckGo)];
+
+        match settings.searxng_url {
+            Some(base_url) => match Url::parse(&base_url) {
+                Ok(base_url) => engines.push(Box::new(Searxng { base_url })),
+                Err(e) => log::error!("Invalid searxng url {}: {:?}", base_url, e),
+            },
+            None => {
+                if [&settings.domain, &settings.founder, &settings.company_name]
+                    .iter()
+                    .any(|kinds| kinds.contains(&SearchEngineKind::Searxng))
+                {
+                    log::error!("Searxng is configured as a search engine without a searxng_url");
+                }
+            }
+        }
+
+        SearchEngines {
+            engines,
+            domain: settings.domain,
+            founder: settings.founder,
+            company_name: settings.company_name,
+            proxy_pool,
+            serp_fixtures,
+        }
+    }
+
+    pub fn proxy_pool(&self) -> &ProxyPool {
+        &self.proxy_pool
+    }
+
+    pub fn serp_fixtures(&self) -> &SerpFixtures {
+        &self.serp_fixtures
+    }
+
+    pub fn get(&self, kind: SearchEngineKind) -> Option<&dyn SearchEngine> {
+        self.engines
+            .iter()
+            .find(|engine| engine.kind() == kind)
+            .map(|engine| engine.as_ref())
+    }
+
+    /// Engines to try in order, the next one is used when the previous is blocking us
+    pub fn for_search_type(&self, search_type: &GoogleSearchType) -> Vec<&dyn SearchEngine> {
+        let kinds = match search_type {
+            GoogleSearchType::Domain(_) => &self.domain,
+            GoogleSearchType::Founder(_) => &self.founder,
+            GoogleSearchType::CompanyName => &self.company_name,
+        };
+
+        kinds.iter().filter_map(|kind| self.get(*kind)).collect()
+    }
+}
+
+fn selector(selectors: &str) -> Selector {
+    Selector::parse(selectors).unwrap()
+}
+
+fn element_text(element: ElementRef) -> String {
+    element.text().collect::<String>().trim().to_string()
+}
+
+fn is_absolute_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+pub struct Google;
+
+impl Google {
+    /// Result links look like `/url?q=https://target.com/page&sa=U&ved=...`
+    fn target_url(href: &str) -> Option<String> {
+        match href.strip_prefix("/url?") {
+            Some(query) => url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "q")
+                .map(|(_, value)| value.to_string()),
+            None => match is_absolute_http_url(href) {
+                true => Some(href.to_string()),
+                false => None,
+            },
+        }
+    }
+}
+
+impl SearchEngine for Google {
+    fn kind(&self) -> SearchEngineKind {
+        SearchEngineKind::Google
+    }
+
+    fn search_url(&self, query: &str) -> Url {
+        Url::parse_with_params("https://www.google.com/search", &[("q", query)]).unwrap()
+    }
+
+    fn parse(&self, url: &Url, page_source: &str) -> SerpResponse {
+        let html_document = Html::parse_document(page_source);
+        let a_tag_selector = selector("a");
+        let h3_selector = selector("h3");
+
+        if html_document.select(&h3_selector).next().is_none() {
+            return match page_source.contains("did not match any documents") {
+                true => SerpResponse::NotFound,
+                false => SerpResponse::Blocked,
+            };
+        }
+
+        let results = html_document
+            .select(&a_tag_selector)
+            .filter_map(|a_tag| {
+                let title = element_text(a_tag.select(&h3_selector).next()?);
+                let url = Google::target_url(a_tag.value().attr("href")?)?;
+                Some(SerpResult {
+                    title,
+                    url,
+                    // Snippets of the basic html page have no stable markup
+                    snippet: "".to_string(),
+                })
+            })
+            .collect();
+
+        let next_page_url = html_document
+            .select(&selector("footer"))
+            .next()
+            .and_then(|footer| footer.select(&a_tag_selector).next())
+            .and_then(|next_page_a_tag| next_page_a_tag.value().attr("href"))
+            .and_then(|href| url.join(href).ok())
+            .map(|next_url| next_url.to_string());
+
+        SerpResponse::Page(Serp {
+            results,
+            next_page_url,
+            page_source: page_source.to_string(),
+        })
+    }
+}
+
+pub struct Bing;
+
+impl SearchEngine for Bing {
+    fn kind(&self) -> SearchEngineKind {
+        SearchEngineKind::Bing
+    }
+
+    fn search_url(&self, query: &str) -> Url {
+        Url::parse_with_params("https://www.bing.com/search", &[("q", query)]).unwrap()
+    }
+
+    fn parse(&self, url: &Url, page_source: &str) -> SerpResponse {
+        let html_document = Html::parse_document(page_source);
+        let title_selector = selector("h2 a");
+        let snippet_selector = selector(".b_caption p, p");
+
+        let results: Vec<SerpResult> = html_document
+            .select(&selector("li.b_algo"))
+            .filter_map(|result| {
+                let a_tag = result.select(&title_selector).next()?;
+                let url = a_tag.value().attr("href")?;
+                if !is_absolute_http_url(url) {
+                    return None;
+                }
+
+                Some(SerpResult {
+                    title: element_text(a_tag),
+                    url: url.to_string(),
+                    snippet: result
+                        .select(&snippet_selector)
+                        .next()
+                        .map(element_text)
+                        .unwrap_or_default(),
+                })
+            })
+            .collect();
+
+        if results.is_empty() {
+            return match html_document.select(&selector("li.b_no")).next() {
+                Some(_) => SerpResponse::NotFound,
+                None => SerpResponse::Blocked,
+            };
+        }
+
+        let next_page_url = html_document
+            .select(&selector("a.sb_pagN"))
+            .next()
+            .and_then(|a_tag| a_tag.value().attr("href"))
+            .and_then(|href| url.join(href).ok())
+            .map(|next_url| next_url.to_string());
+
+        SerpResponse::Page(Serp {
+            results,
+            next_page_url,
+            page_source: page_source.to_string(),
+        })
+    }
+}
+
+pub struct DuckDuckGo;
+
+impl DuckDuckGo {
+    /// Result links go through a redirect like `//duckduckgo.com/l/?uddg=https%3A%2F%2Ftarget.com`
+    fn target_url(url: &Url, href: &str) -> Option<String> {
+        let href = url.join(href).ok()?;
+        match href.path() == "/l/" {
+            true => href
+                .query_pairs()
+                .find(|(key, _)| key == "uddg")
+                .map(|(_, value)| value.to_string()),
+            false => Some(href.to_string()),
+        }
+    }
+}
+
+impl SearchEngine for DuckDuckGo {
+    fn kind(&self) -> SearchEngineKind {
+        SearchEngineKind::DuckDuckGo
+    }
+
+    fn search_url(&self, query: &str) -> Url {
+        Url::parse_with_params("https://html.duckduckgo.com/html/", &[("q", query)]).unwrap()
+    }
+
+    fn parse(&self, url: &Url, page_source: &str) -> SerpResponse {
+        let html_document = Html::parse_document(page_source);
+        let title_selector = selector("a.result__a");
+        let snippet_selector = selector(".result__snippet");
+
+        let results: Vec<SerpResult> = html_document
+            .select(&selector("div.result"))
+            .filter_map(|result| {
+                let a_tag = result.select(&title_selector).next()?;
+                let url = DuckDuckGo::target_url(url, a_tag.value().attr("href")?)?;
+
+                Some(SerpResult {
+                    title: element_text(a_tag),
+                    url,
+                    snippet: result
+                        .select(&snippet_selector)
+                        .next()
+                        .map(element_text)
+                        .unwrap_or_default(),
+                })
+            })
+            .collect();
+
+        if results.is_empty() {
+            return match html_document.select(&selector(".no-results")).next() {
+                Some(_) => SerpResponse::NotFound,
+                None => SerpResponse::Blocked,
+            };
+        }
+
+        // The next page is a form post, its hidden inputs work as query params as well
+        let next_page_url = html_document
+            .select(&selector("div.nav-link form"))
+            .next_back()
+            .map(|form| {
+                let params: Vec<(&str, &str)> = form
+                    .select(&selector("input[type=hidden]"))
+                    .filter_map(|input| {
+                        Some((input.value().attr("name")?, input.value().attr("value")?))
+                    })
+                    .collect();
+                let mut next_url = url.clone();
+                next_url.query_pairs_mut().clear().extend_pairs(params);
+                next_url.to_string()
+            });
+
+        SerpResponse::Page(Serp {
+            results,
+            next_page_url,
+            page_source: page_source.to_string(),
+        })
+    }
+}
+
+/// Self hosted metasearch instance, requires the json format to be enabled in its settings
+pub struct Searxng {
+    base_url: Url,
+}
+
+#[derive(Deserialize)]
+struct SearxngResponse {
+    results: Vec<SearxngResult>,
+}
+
+#[derive(Deserialize)]
+struct SearxngResult {
+    title: String,
+    url: String,
+    #[serde(default)]
+    content: String,
+}
+
+impl SearchEngine for Searxng {
+    fn kind(&self) -> SearchEngineKind {
+        SearchEngineKind::Searxng
+    }
+
+    fn search_url(&self, query: &str) -> Url {
+        let mut url = self.base_url.join("search").unwrap();
+        url.query_pairs_mut()
+            .append_pair("q", query)
+            .append_pair("format", "json")
+            .append_pair("pageno", "1");
+        url
+    }
+
+    fn parse(&self, url: &Url, page_source: &str) -> SerpResponse {
+        let response = match serde_json::from_str::<SearxngResponse>(page_source) {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("Error when deserializing searxng response: {:?}", e);
+                return SerpResponse::Blocked;
+            }
+        };
+
+        if response.results.is_empty() {
+            return SerpResponse::NotFound;
+        }
+
+        let page_number: u32 = url
+            .query_pairs()
+            .find(|(key, _)| key == "pageno")
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or(1);
+        let mut next_url = url.clone();
+        let params: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(key, _)| key != "pageno")
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        next_url
+            .query_pairs_mut()
+            .clear()
+            .extend_pairs(params)
+            .append_pair("pageno", &(page_number + 1).to_string());
+
+        SerpResponse::Page(Serp {
+            results: response
+                .results
+                .into_iter()
+                .map(|result| SerpResult {
+                    title: result.title,
+                    url: result.url,
+                    snippet: result.content,
+                })
+                .collect(),
+            next_page_url: Some(next_url.to_string()),
+            page_source: page_source.to_string(),
+        })
+    }
+
+    fn use_proxy(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::{Bing, DuckDuckGo, Google, SearchEngine, Searxng, SerpResponse};
+
+    fn unwrap_page(response: SerpResponse) -> super::Serp {
+        match response {
+            SerpResponse::Page(serp) => serp,
+            SerpResponse::NotFound => panic!("Expected a page, got not found"),
+            SerpResponse::Blocked => panic!("Expected a page, got blocked"),
+        }
+    }
+
+    #[test]
+    fn google_parse_valid() {
+        let url = Google.search_url("green tea");
+        let html = r#"
+            <html><body>
+                <a href="/url?q=https://www.znaturalfoods.com/products/green-tea&sa=U&ved=abc"><h3>Green Tea - Z Natural Foods</h3></a>
+                <a href="/search?q=green+tea&tbm=isch">Images</a>
+                <footer><a href="/search?q=green+tea&start=10">Next &gt;</a></footer>
+            </body></html>
+        "#;
+
+        let serp = unwrap_page(Google.parse(&url, html));
+
+        assert_eq!(serp.results.len(), 1);
+        assert_eq!(serp.results[0].title, "Green Tea - Z Natural Foods");
+        assert_eq!(
+            serp.results[0].url,
+            "https://www.znaturalfoods.com/products/green-tea"
+        );
+        assert_eq!(
+            serp.next_page_url,
+            Some("https://www.google.com/search?q=green+tea&start=10".to_string())
+        );
+    }
+
+    #[test]
+    fn google_parse_not_found_and_blocked() {
+        let url = Google.search_url("green tea");
+
+        let not_found = Google.parse(&url, "<p>Your search did not match any documents.</p>");
+        assert!(matches!(not_found, SerpResponse::NotFound));
+
+        let blocked = Google.parse(&url, "<p>Our systems have detected unusual traffic</p>");
+        assert!(matches!(blocked, SerpResponse::Blocked));
+    }
+
+    #[test]
+    fn bing_parse_valid() {
+        let url = Bing.search_url("green tea");
+        let html = r#"
+            <ol id="b_results">
+                <li class="b_algo">
+                    <h2><a href="https://dallosell.com/product_detail/organic-green-tea-bag">Organic Green Tea Bag</a></h2>
+                    <div class="b_caption"><p>Buy organic green tea bags</p></div>
+                </li>
+                <li class="b_pag"><a class="sb_pagN" href="/search?q=green+tea&first=11">Next</a></li>
+            </ol>
+        "#;
+
+        let serp = unwrap_page(Bing.parse(&url, html));
+
+        assert_eq!(serp.results.len(), 1);
+        assert_eq!(serp.results[0].title, "Organic Green Tea Bag");
+        assert_eq!(serp.results[0].snippet, "Buy organic green tea bags");
+        assert_eq!(
+            serp.next_page_url,
+            Some("https://www.bing.com/search?q=green+tea&first=11".to_string())
+        );
+    }
+
+    #[test]
+    fn duckduckgo_parse_valid() {
+        let url = DuckDuckGo.search_url("green tea");
+        let html = r#"
+            <div class="result">
+                <a class="result__a" href="//duckduckgo.com/l/?uddg=https%3A%2F%2Forganicindia.com%2Fcollections%2Fgreen-tea&rut=abc">Green Tea | Organic India</a>
+                <a class="result__snippet">Shop organic green tea</a>
+            </div>
+            <div class="nav-link">
+                <form action="/html/" method="post">
+                    <input type="submit" value="Next" />
+                    <input type="hidden" name="q" value="green tea" />
+                    <input type="hidden" name="s" value="10" />
+                </form>
+            </div>
+        "#;
+
+        let serp = unwrap_page(DuckDuckGo.parse(&url, html));
+
+        assert_eq!(serp.results.len(), 1);
+        assert_eq!(
+            serp.results[0].url,
+            "https://organicindia.com/collections/green-tea"
+        );
+        assert_eq!(serp.results[0].snippet, "Shop organic green tea");
+        assert_eq!(
+            serp.next_page_url,
+            Some("https://html.duckduckgo.com/html/?q=green+tea&s=10".to_string())
+        );
+    }
+
+    #[test]
+    fn searxng_parse_valid() {
+        let searxng = Searxng {
+            base_url: Url::parse("http://localhost:8888/").unwrap(),
+        };
+        let url = searxng.search_url("green tea");
+        let json = r#"{"query": "green tea", "results": [
+            {"title": "Green Tea Matcha", "url": "https://www.traditionalmedicinals.com/products/green-tea-matcha", "content": "Matcha tea"}
+        ]}"#;
+
+        let serp = unwrap_page(searxng.parse(&url, json));
+
+        assert_eq!(serp.results.len(), 1);
+        assert_eq!(serp.results[0].snippet, "Matcha tea");
+        assert_eq!(
+            serp.next_page_url,
+            Some("http://localhost:8888/search?q=green+tea&format=json&pageno=2".to_string())
+        );
+    }
+}
@@ -1,211 +1,62 @@
-use std::time::Duration;
+use std::sync::Arc;
 
-use async_smtp::{
-    commands::{MailCommand, RcptCommand},
-    SmtpClient, SmtpTransport,
-};
-use check_if_email_exists::{check_email, CheckEmailInput, CheckEmailOutput, Reachable};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use tokio::{
-    io::{AsyncRead, AsyncWrite, BufStream},
-    net::TcpStream,
+use crate::configuration::EmailVerificationSettings;
+
+use super::{
+    BulkEmailChecker, CheckEmailVerifier, EmailVerifier, EmailVerifierKind, SmtpEmailVerifier,
+    SmtpVerifier, VerificationChain, VerificationOutcome,
 };
 
+/// Entry point for email verification, runs emails through the configured chain of verifiers
 pub struct Sentinel {
-    client: Client,
-    api_key: String,
-    url: String,
-}
-
-#[derive(Serialize)]
-struct GetQuery {
-    key: String,
-    email: String,
-}
-
-#[derive(Deserialize)]
-struct ApiResponse {
-    status: String,
+    chain: VerificationChain,
+    smtp_verifier: Arc<SmtpVerifier>,
 }
 
 impl Sentinel {
-    pub fn new(api_key: String) -> Self {
-        let client = reqwest::Client::new();
-
-        Sentinel {
-            client,
-            api_key,
-            url: "https://api.bulkemailchecker.com/real-time".to_string(),
-        }
-    }
-
-    pub async fn verfiy_email_from_bulk_mail_checker(&self, email: String) -> bool {
-        match self
-            .client
-            .get(self.url.clone())
-            .query(&GetQuery {
-                key: self.api_key.clone(),
-                email,
-            })
-            .send()
-            .await
-        {
-            Ok(res) => match res.json::<ApiResponse>().await {
-                Ok(json) => json.status == "passed",
-                Err(e) => {
-                    log::error!("Error when deserializing to json: {:?}", e);
-                    false
+    pub fn new(
+        settings: EmailVerificationSettings,
+        bulk_email_checker_api_key: String,
+        smtp_verifier: SmtpVerifier,
+    ) -> Self {
+        let smtp_verifier = Arc::new(smtp_verifier);
+
+        let verifiers = settings
+            .verifiers
+            .iter()
+            .map(|kind| -> Box<dyn EmailVerifier> {
+                match kind {
+                    EmailVerifierKind::Smtp => {
+                        Box::new(SmtpEmailVerifier::new(smtp_verifier.clone()))
+                    }
+                    EmailVerifierKind::CheckEmail => Box::new(CheckEmailVerifier::new(
+                        smtp_verifier.from_email().to_string(),
+                        smtp_verifier.hello_name().to_string(),
+                    )),
+                    EmailVerifierKind::BulkEmailChecker => {
+                        Box::new(BulkEmailChecker::new(bulk_email_checker_api_key.clone()))
+                    }
                 }
-            },
-            Err(e) => {
-                log::error!("Got error from bulk email check api: {:?}", e);
-                false
-            }
-        }
-    }
-
-    pub async fn verfiy_email(&self, email: String) -> bool {
-        let mut input = CheckEmailInput::new(email);
-        input
-            .set_from_email("random.guy@fit.com".to_string())
-            .set_hello_name("verywellfit.com".to_string());
-
-        // .set_proxy(CheckEmailInputProxy {
-        //     host: "p.webshare.io".to_string(),
-        //     port: 9999,
-        //     username: None,
-        //     password: None,
-        // }); //works
-        // .set_proxy(CheckEmailInputProxy {
-        //     host: "69.12.93.165".to_string(),
-        //     port: 6185,
-        //     username: None,
-        //     password: None,
-        // }); //works
-        // .set_gmail_use_api(true) // SMTP is ok and its API works but invalid email
-        // .set_smtp_port(587);
-
-        // Verify this email, using async/await syntax.
-        let result = check_email(&input).await;
-
-        log::info!("Result from API: {:?}", result);
+            })
+            .collect();
 
-        match result.is_reachable {
-            Reachable::Safe => true,
-            Reachable::Unknown => false,
-            Reachable::Risky => false,
-            Reachable::Invalid => false,
+        Sentinel {
+            chain: VerificationChain::new(verifiers, settings.strategy),
+            smtp_verifier,
         }
     }
 
-    pub async fn get_email_verification_status(&self, email: &str) -> Reachable {
-        let mut input = CheckEmailInput::new(email.to_string());
-        input
-            .set_from_email("random.guy@fit.com".to_string())
-            .set_hello_name("verywellfit.com".to_string());
-
-        // Verify this email, using async/await syntax.
-        let result = check_email(&input).await;
-
-        result.is_reachable
+    pub async fn verify(&self, email: &str) -> VerificationOutcome {
+        self.chain.verify(email).await
     }
 
-    async fn get_email_info(&self, email: &str) -> CheckEmailOutput {
-        let mut input = CheckEmailInput::new(email.to_string());
-        input.set_smtp_timeout(Some(Duration::from_millis(100)));
-
-        check_email(&input).await
+    /// Outcomes in the order of `emails`, emails of a domain share smtp sessions
+    pub async fn verify_many(&self, emails: &[String]) -> Vec<VerificationOutcome> {
+        self.chain.verify_many(emails).await
     }
 
-    pub async fn verify_email_manual(&self, email: &str) -> bool {
-        let dns_info = self.get_email_info(email).await;
-
-        // CheckEmailOutput input: "sulemanmahmood99@gmail.com", is_reachable: Safe, misc: Ok(MiscDetails { is_disposable: false, is_role_account: false, gravatar_url: None, haveibeenpwned: None }),
-        //   mx: Ok(MxDetails { lookup: Ok(MxLookup(Lookup { query: Query { name: Name("gmail.com"), query_type: MX, query_class: IN },
-        //     records: [
-        //       Record { name_labels: Name("gmail.com."), rr_type: MX, dns_class: IN, ttl: 1411, rdata: Some(MX(MX { preference: 30, exchange: Name("alt3.gmail-smtp-in.l.google.com.") })) },
-        //       Record { name_labels: Name("gmail.com."), rr_type: MX, dns_class: IN, ttl: 1411, rdata: Some(MX(MX { preference: 10, exchange: Name("alt1.gmail-smtp-in.l.google.com.") })) },
-        //       Record { name_labels: Name("gmail.com."), rr_type: MX, dns_class: IN, ttl: 1411, rdata: Some(MX(MX { preference: 20, exchange: Name("alt2.gmail-smtp-in.l.google.com.") })) },
-        //       Record { name_labels: Name("gmail.com."), rr_type: MX, dns_class: IN, ttl: 1411, rdata: Some(MX(MX { preference: 40, exchange: Name("alt4.gmail-smtp-in.l.google.com.") })) },
-        //       Record { name_labels: Name("gmail.com."), rr_type: MX, dns_class: IN, ttl: 1411, rdata: Some(MX(MX { preference: 5, exchange: Name("gmail-smtp-in.l.google.com.") })) }
-        //     ],
-        //     valid_until: Instant { tv_sec: 6077130, tv_nsec: 595604176 } })) })
-        let Ok(mx) = dns_info.mx else {
-            return false;
-        };
-        let Ok(lookup) = mx.lookup else {
-            return false;
-        };
-        let exchanges: Vec<String> = lookup
-            .iter()
-            .map(|rdata| rdata.exchange().to_string())
-            .collect();
-
-        let Some(first_exchange) = exchanges.first() else {
-            return false;
-        };
-        let smtp_server = first_exchange;
-        let smtp_server = smtp_server.trim_end_matches(".");
-        let smtp_server_port = format!("{}:25", smtp_server);
-
-        // Define a new trait that combines AsyncRead, AsyncWrite, and Unpin
-        trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
-        impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncReadWrite for T {}
-
-        let Ok(stream) = TcpStream::connect(smtp_server_port.clone()).await else {
-            return false;
-        };
-        let stream = BufStream::new(Box::new(stream) as Box<dyn AsyncReadWrite>);
-        let client = SmtpClient::new();
-        let Ok(mut transport) = SmtpTransport::new(client, stream).await else {
-            return false;
-        };
-
-        if let Err(_) = transport
-            .get_mut()
-            .command(MailCommand::new(
-                Some("random.guy@fit.com".parse().unwrap()),
-                vec![],
-            ))
-            .await
-        {
-            return false;
-        }
-
-        let Ok(response) = transport
-            .get_mut()
-            .command(RcptCommand::new(email.parse().unwrap(), vec![]))
-            .await
-        else {
-            return false;
-        };
-
-        // log::info!("First word: {:?}", response.first_word());
-        log::info!(
-            "Email: {}; Exchange: {}; First line response: {:?}",
-            email,
-            smtp_server_port,
-            response.first_line(),
-        );
-        // log::info!("Response message: {:?}", response.clone().message);
-        // log::info!("Response code: {:?}", response.code);
-        // log::info!("Entire response: {:?}", response);
-
-        if !response.is_positive() {
-            return false;
-        }
-
-        match response.first_word() {
-            Some(first_word) => {
-                let first_word = first_word.to_lowercase();
-                match first_word.as_str() {
-                    "recipient" => false,
-                    "ok" => false,
-                    _ => true,
-                }
-            }
-            None => false,
-        }
+    /// Direct smtp access for probes that are only meaningful over smtp, like catch-all checks
+    pub fn smtp_verifier(&self) -> &SmtpVerifier {
+        &self.smtp_verifier
     }
 }
@@ -0,0 +1,166 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::configuration::SerpFixtureSettings;
+
+use super::SearchEngineKind;
+
+const MAX_QUERY_SLUG_LEN: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SerpFixtureMode {
+    /// Only hit the search engines
+    Live,
+    /// Hit the search engines and save every answered page to disk
+    Record,
+    /// Serve pages from disk only, nothing goes over the network
+    Replay,
+}
+
+/// Raw search engine responses saved on disk, keyed by engine, query and page url
+pub struct SerpFixtures {
+    mode: SerpFixtureMode,
+    directory: PathBuf,
+}
+
+impl SerpFixtures {
+    pub fn new(settings: SerpFixtureSettings) -> Self {
+        SerpFixtures {
+            mode: settings.mode,
+            directory: PathBuf::from(settings.directory),
+        }
+    }
+
+    pub fn mode(&self) -> SerpFixtureMode {
+        self.mode
+    }
+
+    pub async fn load(
+        &self,
+        search_engine: SearchEngineKind,
+        query: &str,
+        url: &Url,
+    ) -> Option<String> {
+        let path = self.path(search_engine, query, url);
+
+        match tokio::fs::read_to_string(&path).await {
+            Ok(page_source) => Some(page_source),
+            Err(e) => {
+                log::error!("No serp fixture at {}: {:?}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    pub async fn save(
+        &self,
+        search_engine: SearchEngineKind,
+        query: &str,
+        url: &Url,
+        page_source: &str,
+    ) {
+        let path = self.path(search_engine, query, url);
+
+        if let Some(parent) = path.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(parent).await {
+                log::error!("Error creating serp fixture directory: {:?}", e);
+                return;
+            }
+        }
+
+        match tokio::fs::write(&path, page_source).await {
+            Ok(_) => log::info!("Recorded serp fixture {}", path.display()),
+            Err(e) => log::error!("Error recording serp fixture {}: {:?}", path.display(), e),
+        }
+    }
+
+    /// `<directory>/<engine>/<query slug>-<url hash>.html`, the url tells pages of a query apart
+    fn path(&self, search_engine: SearchEngineKind, query: &str, url: &Url) -> PathBuf {
+        let query_slug: String = query
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() {
+                true => c.to_ascii_lowercase(),
+                false => '_',
+            })
+            .take(MAX_QUERY_SLUG_LEN)
+            .collect();
+
+        self.directory.join(search_engine.as_str()).join(format!(
+            "{}-{:016x}.html",
+            query_slug,
+            fnv1a_hash(url.as_str())
+        ))
+    }
+}
+
+/// Stable across builds and platforms unlike std's hasher, fixture names must not change
+fn fnv1a_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        configuration::SerpFixtureSettings,
+        domain::html_tag::{extract_domain, HtmlTag},
+        services::{Google, SearchEngine, SearchEngineKind, SerpResponse},
+    };
+
+    use super::{SerpFixtureMode, SerpFixtures};
+
+    fn replay_fixtures() -> SerpFixtures {
+        SerpFixtures::new(SerpFixtureSettings {
+            mode: SerpFixtureMode::Replay,
+            directory: concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/serp").to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn replay_google_domain_search_valid() {
+        let fixtures = replay_fixtures();
+        let query = r#""organic green tea" buy"#;
+        let url = Google.search_url(query);
+
+        let page_source = fixtures
+            .load(SearchEngineKind::Google, query, &url)
+            .await
+            .expect("Fixture for query is missing");
+
+        let SerpResponse::Page(serp) = Google.parse(&url, &page_source) else {
+            panic!("Fixture should parse into a page");
+        };
+        let domains: Vec<String> = serp
+            .results
+            .into_iter()
+            .filter_map(|result| extract_domain(HtmlTag::ATag(result.url)))
+            .collect();
+
+        assert_eq!(
+            domains,
+            vec![
+                "znaturalfoods.com",
+                "dallosell.com",
+                "organicindia.com",
+                "traditionalmedicinals.com",
+            ]
+        );
+        assert!(serp.next_page_url.is_some());
+    }
+
+    #[tokio::test]
+    async fn replay_missing_fixture() {
+        let fixtures = replay_fixtures();
+        let query = "query that was never recorded";
+        let url = Google.search_url(query);
+
+        assert!(fixtures
+            .load(SearchEngineKind::Google, query, &url)
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,41 @@
+use tokio::sync::watch;
+
+/// Tells the pipeline to wind down, every stage holds a `ShutdownSignal` of it
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            sender: watch::channel(false).0,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.sender.subscribe())
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+}
+
+#[derive(Clone)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutdown was triggered, right away if it already was
+    pub async fn triggered(&mut self) {
+        // Only errors when the `Shutdown` is dropped, which never triggers it
+        if self.0.wait_for(|triggered| *triggered).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
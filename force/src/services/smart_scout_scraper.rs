@@ -1,9 +1,11 @@
 use std::{error::Error, time::Duration};
 
-use sqlx::{Acquire, PgPool};
-use tokio::{sync::mpsc::UnboundedSender, time};
+use actix_web::web::Data;
+use sqlx::PgPool;
+use tokio::time;
 
 use crate::{
+    configuration::SmartScoutSettings,
     dal::smart_scout_db,
     domain::{
         html_tag::{extract_company_domain, extract_domain},
@@ -12,53 +14,61 @@ use crate::{
     routes::lead_route::{build_company_name_search_query, build_founder_seach_queries},
     services::{
         extract_data_from_google_search_with_reqwest, CompanyNameData, GoogleSearchResult,
-        GoogleSearchType,
+        GoogleSearchType, SearchEngines,
     },
 };
 
-use super::{FounderQueryChannelData, PersistantData};
-
-const N: i64 = 300;
+use super::{FounderQueryChannelData, PersistantData, QueueSender};
 
 pub async fn smart_scout_scraper_handler(
     pool: PgPool,
-    founder_query_sender: UnboundedSender<FounderQueryChannelData>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
+    founder_query_sender: QueueSender<FounderQueryChannelData>,
+    persistant_data_sender: QueueSender<PersistantData>,
+    search_engines: Data<SearchEngines>,
+    settings: SmartScoutSettings,
 ) {
     log::info!("Started smart scout scraper");
 
-    // Create a 30 min interval
-    let mut interval = time::interval(Duration::from_secs(30 * 60));
+    let mut interval = time::interval(Duration::from_secs(settings.interval_minutes * 60));
 
     loop {
         /*
         1. Tick every 'm' minutes
-        2. Check stop condition: Use a notify channel on route invocation
-        3. Get 'n' (random) unscraped jobs from the smart scout table
-        4. Start scraping them
+        2. Get 'n' (random) unscraped jobs from the smart scout table
+        3. Start scraping them
         */
         interval.tick().await;
-        return;
-
-        // TODO: Stop condition
 
         let pool_con_result = pool.acquire().await;
         let Ok(mut pool_con) = pool_con_result else {
             log::error!("Pool timed out: {:?}", pool_con_result.unwrap_err());
             continue;
         };
-        let con = pool_con.acquire().await.unwrap();
-
-        let ss_companies = smart_scout_db::get_n_unscraped_company_ids(con, N)
-            .await
-            .unwrap();
+        let ss_companies =
+            match smart_scout_db::get_n_unscraped_company_ids(&mut pool_con, settings.batch_size)
+                .await
+            {
+                Ok(ss_companies) => ss_companies,
+                Err(e) => {
+                    log::error!(
+                        "Error while getting unscraped smart scout companies: {:?}",
+                        e
+                    );
+                    continue;
+                }
+            };
 
         for ss in ss_companies {
-            smart_scout_db::start_job(con, ss.id).await.unwrap();
+            // Companies whose job didn't start are picked up again on a later tick
+            if let Err(e) = smart_scout_db::start_job(&mut pool_con, ss.id).await {
+                log::error!("Error while starting smart scout job {}: {:?}", ss.id, e);
+                continue;
+            }
             tokio::spawn(scrape_company_domain_query(
                 ss,
                 founder_query_sender.clone(),
                 persistant_data_sender.clone(),
+                search_engines.clone(),
             ));
         }
     }
@@ -66,8 +76,9 @@ pub async fn smart_scout_scraper_handler(
 
 async fn scrape_company_domain_query(
     ss: SmartScout,
-    founder_query_sender: UnboundedSender<FounderQueryChannelData>,
-    persistant_data_sender: UnboundedSender<PersistantData>,
+    founder_query_sender: QueueSender<FounderQueryChannelData>,
+    persistant_data_sender: QueueSender<PersistantData>,
+    search_engines: Data<SearchEngines>,
 ) {
     log::info!(
         "Scraping google for company domain for company: {}",
@@ -76,9 +87,12 @@ async fn scrape_company_domain_query(
 
     let query = build_company_name_search_query(&ss.name);
 
-    let google_search_result =
-        extract_data_from_google_search_with_reqwest(query.clone(), GoogleSearchType::CompanyName)
-            .await;
+    let google_search_result = extract_data_from_google_search_with_reqwest(
+        &search_engines,
+        query.clone(),
+        GoogleSearchType::CompanyName,
+    )
+    .await;
 
     match google_search_result {
         GoogleSearchResult::Domains { .. } | GoogleSearchResult::Founders(..) => {
@@ -88,9 +102,12 @@ async fn scrape_company_domain_query(
             log::error!("Returning from captcha blocked on url {}", query);
         }
         GoogleSearchResult::NotFound => {
-            if let Err(e) = persistant_data_sender.send(PersistantData::CompanyName(
-                CompanyNameData::NoResult { query },
-            )) {
+            if let Err(e) = persistant_data_sender
+                .send(PersistantData::CompanyName(CompanyNameData::NoResult {
+                    query,
+                }))
+                .await
+            {
                 log::error!(
                     "Persistant data sender channel got an Error: {:?} | Source: {:?}",
                     e,
@@ -105,22 +122,31 @@ async fn scrape_company_domain_query(
             let domains: Vec<String> = name_candidates
                 .clone()
                 .into_iter()
-                .filter_map(|nc| extract_domain(nc))
+                .filter_map(extract_domain)
                 .collect();
 
             let company_name = extract_company_domain(&ss.name, domains.clone());
 
             for query in build_founder_seach_queries(&company_name) {
-                founder_query_sender
+                if let Err(e) = founder_query_sender
                     .send(FounderQueryChannelData {
                         query,
                         domain: company_name.clone(),
+                        run_id: None,
                     })
-                    .unwrap();
+                    .await
+                {
+                    log::error!(
+                        "Founder query queue got an Error: {:?} | Source: {:?}",
+                        e,
+                        e.source(),
+                    );
+                }
             }
 
-            if let Err(e) =
-                persistant_data_sender.send(PersistantData::CompleteSmartScoutJob(ss.id))
+            if let Err(e) = persistant_data_sender
+                .send(PersistantData::CompleteSmartScoutJob(ss.id))
+                .await
             {
                 log::error!(
                     "Persistant data sender channel got an Error: {:?} | Source: {:?}",
@@ -129,14 +155,15 @@ async fn scrape_company_domain_query(
                 );
             }
 
-            if let Err(e) =
-                persistant_data_sender.send(PersistantData::CompanyName(CompanyNameData::Result {
+            if let Err(e) = persistant_data_sender
+                .send(PersistantData::CompanyName(CompanyNameData::Result {
                     query,
                     page_source,
                     page_number: 1,
                     html_tags: name_candidates,
                     company_name,
                 }))
+                .await
             {
                 log::error!(
                     "Persistant data sender channel got an Error: {:?} | Source: {:?}",
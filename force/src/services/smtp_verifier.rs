@@ -0,0 +1,709 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::configuration::SmtpVerifierSettings;
+
+use super::MxHostLimiter;
+
+/// What a mail server said about a mailbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "SmtpVerdict", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SmtpVerdict {
+    Deliverable,
+    Undeliverable,
+    /// Temporarily refused, the same address should be asked again later
+    Greylisted,
+    /// No usable answer, like a timeout or a policy block on our ip
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmtpReply {
+    pub code: u16,
+    /// Like `5.1.1` from RFC 3463, not every server sends one
+    pub enhanced_code: Option<String>,
+    pub lines: Vec<String>,
+}
+
+impl SmtpReply {
+    /// Parses the lines of a possibly multiline reply like `250-mx.google.com` .. `250 SMTPUTF8`
+    pub fn parse(lines: Vec<String>) -> Option<SmtpReply> {
+        let code = lines.first()?.get(0..3)?.parse::<u16>().ok()?;
+        let enhanced_code = lines.first().and_then(|line| {
+            let candidate = line.get(4..)?.split_whitespace().next()?;
+            let parts: Vec<&str> = candidate.split('.').collect();
+
+            match parts.len() == 3
+                && parts[0] == (code / 100).to_string()
+                && parts.iter().all(|p| p.parse::<u16>().is_ok())
+            {
+                true => Some(candidate.to_string()),
+                false => None,
+            }
+        });
+
+        Some(SmtpReply {
+            code,
+            enhanced_code,
+            lines,
+        })
+    }
+
+    pub fn is_positive(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+
+    pub fn is_transient(&self) -> bool {
+        (400..500).contains(&self.code)
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        (500..600).contains(&self.code)
+    }
+
+    pub fn text(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| line.get(4..).unwrap_or_default())
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    /// Verdict on the mailbox from the reply to `RCPT TO`
+    pub fn rcpt_verdict(&self) -> SmtpVerdict {
+        if self.is_positive() {
+            return SmtpVerdict::Deliverable;
+        }
+        if self.is_transient() {
+            return SmtpVerdict::Greylisted;
+        }
+        if !self.is_permanent() {
+            return SmtpVerdict::Unknown;
+        }
+
+        match self.enhanced_code.as_deref() {
+            // Bad destination mailbox address or mailbox disabled
+            Some(code) if code.starts_with("5.1.") || code == "5.2.1" => SmtpVerdict::Undeliverable,
+            Some(_) => SmtpVerdict::Unknown,
+            None => {
+                let text = self.text().to_lowercase();
+                let is_policy_block = [
+                    "block",
+                    "spam",
+                    "blacklist",
+                    "listed",
+                    "policy",
+                    "reputation",
+                ]
+                .iter()
+                .any(|word| text.contains(word));
+
+                match (self.code, is_policy_block) {
+                    (550 | 551 | 553, false) => SmtpVerdict::Undeliverable,
+                    _ => SmtpVerdict::Unknown,
+                }
+            }
+        }
+    }
+}
+
+/// One smtp session with one mx host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmtpAttempt {
+    pub mx_host: String,
+    pub verdict: SmtpVerdict,
+    /// The server greeted us, so it is reachable from here
+    pub connected: bool,
+    pub reply_code: Option<u16>,
+    pub enhanced_code: Option<String>,
+    /// Every line sent and received, prefixed with `C:` and `S:`, local errors with `!:`
+    pub transcript: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmtpVerification {
+    pub email: String,
+    pub mx_hosts: Vec<String>,
+    pub verdict: SmtpVerdict,
+    pub attempts: Vec<SmtpAttempt>,
+}
+
+impl SmtpVerification {
+    /// Some mx host greeted us
+    pub fn smtp_reachable(&self) -> bool {
+        self.attempts.iter().any(|attempt| attempt.connected)
+    }
+}
+
+#[async_trait]
+pub trait MxResolver: Send + Sync {
+    /// Mx hosts of the domain by preference, a host may carry its own port like `127.0.0.1:2525`
+    async fn resolve_mx(&self, domain: &str) -> Vec<String>;
+}
+
+pub struct DnsMxResolver {
+    resolver: Option<TokioAsyncResolver>,
+}
+
+impl DnsMxResolver {
+    pub fn from_system_conf() -> Self {
+        let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(resolver) => Some(resolver),
+            Err(e) => {
+                log::error!("Error creating dns resolver: {:?}", e);
+                None
+            }
+        };
+
+        DnsMxResolver { resolver }
+    }
+}
+
+#[async_trait]
+impl MxResolver for DnsMxResolver {
+    /// A domain without mx records receives mail itself
+    async fn resolve_mx(&self, domain: &str) -> Vec<String> {
+        let Some(ref resolver) = self.resolver else {
+            return vec![];
+        };
+
+        match resolver.mx_lookup(domain).await {
+            Ok(lookup) => {
+                let mut records: Vec<(u16, String)> = lookup
+                    .iter()
+                    .map(|mx| {
+                        (
+                            mx.preference(),
+                            mx.exchange().to_string().trim_end_matches('.').to_string(),
+                        )
+                    })
+                    .filter(|(_, host)| !host.is_empty())
+                    .collect();
+                records.sort();
+
+                records.into_iter().map(|(_, host)| host).collect()
+            }
+            Err(e) => {
+                log::info!("No mx records for {}: {:?}", domain, e);
+                vec![domain.to_string()]
+            }
+        }
+    }
+}
+
+/// Fixed mx hosts per domain, points domains at local servers in tests
+#[derive(Default)]
+pub struct StaticMxResolver {
+    hosts: HashMap<String, Vec<String>>,
+}
+
+impl StaticMxResolver {
+    pub fn with_domain(mut self, domain: &str, mx_hosts: Vec<String>) -> Self {
+        self.hosts.insert(domain.to_string(), mx_hosts);
+        self
+    }
+}
+
+#[async_trait]
+impl MxResolver for StaticMxResolver {
+    async fn resolve_mx(&self, domain: &str) -> Vec<String> {
+        self.hosts.get(domain).cloned().unwrap_or_default()
+    }
+}
+
+/// Verifies mailboxes by walking the domain's mx hosts in priority order and asking
+/// each one about the address with `RCPT TO`, without ever sending any mail.
+pub struct SmtpVerifier {
+    settings: SmtpVerifierSettings,
+    resolver: Box<dyn MxResolver>,
+    limiter: MxHostLimiter,
+}
+
+impl SmtpVerifier {
+    pub fn new(settings: SmtpVerifierSettings) -> Self {
+        Self::with_resolver(settings, DnsMxResolver::from_system_conf())
+    }
+
+    pub fn with_resolver(
+        settings: SmtpVerifierSettings,
+        resolver: impl MxResolver + 'static,
+    ) -> Self {
+        SmtpVerifier {
+            limiter: MxHostLimiter::new(
+                settings.max_connections_per_host,
+                settings.max_probes_per_minute_per_host,
+            ),
+            settings,
+            resolver: Box::new(resolver),
+        }
+    }
+
+    pub fn hello_name(&self) -> &str {
+        &self.settings.hello_name
+    }
+
+    pub fn from_email(&self) -> &str {
+        &self.settings.from_email
+    }
+
+    pub fn greylist_retry(&self) -> Duration {
+        Duration::from_secs(self.settings.greylist_retry_secs)
+    }
+
+    pub async fn verify(&self, email: &str) -> SmtpVerification {
+        let mut verifications = self.verify_many(&[email.to_string()]).await;
+        verifications.remove(0)
+    }
+
+    /// Verifies emails domain by domain, emails of a domain share smtp sessions.
+    /// Verifications come back in the order of `emails`.
+    pub async fn verify_many(&self, emails: &[String]) -> Vec<SmtpVerification> {
+        let mut domains: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, email) in emails.iter().enumerate() {
+            let domain = email.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+            domains.entry(domain).or_default().push(i);
+        }
+
+        let mut verifications: Vec<Option<SmtpVerification>> = vec![None; emails.len()];
+        for (domain, indexes) in domains {
+            let domain_emails: Vec<String> = indexes.iter().map(|i| emails[*i].clone()).collect();
+
+            for (i, verification) in indexes
+                .into_iter()
+                .zip(self.verify_domain(domain, domain_emails).await)
+            {
+                verifications[i] = Some(verification);
+            }
+        }
+
+        verifications.into_iter().flatten().collect()
+    }
+
+    async fn verify_domain(&self, domain: &str, emails: Vec<String>) -> Vec<SmtpVerification> {
+        let mx_hosts = self.resolver.resolve_mx(domain).await;
+        let mut verifications: Vec<SmtpVerification> = emails
+            .into_iter()
+            .map(|email| SmtpVerification {
+                email,
+                mx_hosts: mx_hosts.clone(),
+                verdict: SmtpVerdict::Unknown,
+                attempts: vec![],
+            })
+            .collect();
+
+        for mx_host in mx_hosts.iter().take(self.settings.max_mx_hosts) {
+            // Lower priority hosts are only backups for hosts that can't be talked to
+            let pending: Vec<usize> = (0..verifications.len())
+                .filter(|i| verifications[*i].verdict == SmtpVerdict::Unknown)
+                .collect();
+
+            for chunk in pending.chunks(self.settings.max_recipients_per_session.max(1)) {
+                let chunk_emails: Vec<&str> = chunk
+                    .iter()
+                    .map(|i| verifications[*i].email.as_str())
+                    .collect();
+                let attempts = self.verify_with_host(mx_host, &chunk_emails).await;
+
+                for (i, attempt) in chunk.iter().zip(attempts) {
+                    verifications[*i].verdict = attempt.verdict;
+                    verifications[*i].attempts.push(attempt);
+                }
+            }
+        }
+
+        verifications
+    }
+
+    /// One session with the mx host for all `emails`, every email gets its own attempt
+    /// with the shared part of the transcript followed by its own `RCPT TO`
+    async fn verify_with_host(&self, mx_host: &str, emails: &[&str]) -> Vec<SmtpAttempt> {
+        let mut attempt = SmtpAttempt {
+            mx_host: mx_host.to_string(),
+            verdict: SmtpVerdict::Unknown,
+            connected: false,
+            reply_code: None,
+            enhanced_code: None,
+            transcript: vec![],
+        };
+        let _permit = self.limiter.acquire_connection(mx_host).await;
+
+        // Mx records are names and never carry a port, only hosts from a static resolver do
+        let address = match mx_host.contains(':') {
+            true => mx_host.to_string(),
+            false => format!("{}:{}", mx_host, self.settings.port),
+        };
+        let connect_timeout = Duration::from_secs(self.settings.connect_timeout_secs);
+        let stream = match timeout(connect_timeout, TcpStream::connect(&address)).await {
+            Ok(Ok(stream)) => Some(stream),
+            Ok(Err(e)) => {
+                attempt.transcript.push(format!("!: Connect failed: {}", e));
+                None
+            }
+            Err(_) => {
+                attempt.transcript.push("!: Connect timed out".to_string());
+                None
+            }
+        };
+        let Some(stream) = stream else {
+            return vec![attempt; emails.len()];
+        };
+
+        let mut session = SmtpSession {
+            stream: BufReader::new(stream),
+            command_timeout: Duration::from_secs(self.settings.command_timeout_secs),
+            transcript: vec![],
+        };
+        let mut attempts = vec![];
+
+        if self.open_transaction(&mut session, &mut attempt).await {
+            let prelude = session.transcript.clone();
+
+            for email in emails {
+                self.limiter.wait_for_probe(mx_host).await;
+                session.transcript = prelude.clone();
+
+                let reply = session.command(&format!("RCPT TO:<{}>", email)).await;
+                let mut email_attempt = attempt.clone();
+                if let Some(ref reply) = reply {
+                    email_attempt.record(reply);
+                    email_attempt.verdict = reply.rcpt_verdict();
+                }
+                email_attempt.transcript = session.transcript.clone();
+                attempts.push(email_attempt);
+
+                // The connection is gone, remaining emails are left for the next host
+                if reply.is_none() {
+                    break;
+                }
+            }
+        }
+
+        if attempt.connected {
+            session.command("QUIT").await;
+        }
+        attempt.transcript = session.transcript;
+        attempts.resize(emails.len(), attempt);
+
+        attempts
+    }
+
+    /// Greeting, EHLO (or HELO for old servers) and MAIL FROM, false at the first refusal
+    async fn open_transaction(&self, session: &mut SmtpSession, attempt: &mut SmtpAttempt) -> bool {
+        let Some(greeting) = session.read_reply().await else {
+            return false;
+        };
+        attempt.record(&greeting);
+        if !greeting.is_positive() {
+            return false;
+        }
+        attempt.connected = true;
+
+        let mut hello = session
+            .command(&format!("EHLO {}", self.settings.hello_name))
+            .await;
+        if hello.as_ref().is_some_and(|reply| reply.is_permanent()) {
+            hello = session
+                .command(&format!("HELO {}", self.settings.hello_name))
+                .await;
+        }
+
+        let Some(hello) = hello else {
+            return false;
+        };
+        attempt.record(&hello);
+        if !hello.is_positive() {
+            return false;
+        }
+
+        let Some(reply) = session
+            .command(&format!("MAIL FROM:<{}>", self.settings.from_email))
+            .await
+        else {
+            return false;
+        };
+        attempt.record(&reply);
+
+        reply.is_positive()
+    }
+}
+
+impl SmtpAttempt {
+    fn record(&mut self, reply: &SmtpReply) {
+        self.reply_code = Some(reply.code);
+        self.enhanced_code = reply.enhanced_code.clone();
+    }
+}
+
+struct SmtpSession {
+    stream: BufReader<TcpStream>,
+    command_timeout: Duration,
+    transcript: Vec<String>,
+}
+
+impl SmtpSession {
+    async fn command(&mut self, command: &str) -> Option<SmtpReply> {
+        self.transcript.push(format!("C: {}", command));
+        let line = format!("{}\r\n", command);
+
+        match timeout(
+            self.command_timeout,
+            self.stream.get_mut().write_all(line.as_bytes()),
+        )
+        .await
+        {
+            Ok(Ok(())) => self.read_reply().await,
+            Ok(Err(e)) => {
+                self.transcript.push(format!("!: Write failed: {}", e));
+                None
+            }
+            Err(_) => {
+                self.transcript.push("!: Write timed out".to_string());
+                None
+            }
+        }
+    }
+
+    async fn read_reply(&mut self) -> Option<SmtpReply> {
+        let mut lines = vec![];
+
+        loop {
+            let mut line = String::new();
+            match timeout(self.command_timeout, self.stream.read_line(&mut line)).await {
+                Ok(Ok(0)) => {
+                    self.transcript.push("!: Connection closed".to_string());
+                    return None;
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    self.transcript.push(format!("!: Read failed: {}", e));
+                    return None;
+                }
+                Err(_) => {
+                    self.transcript.push("!: Reply timed out".to_string());
+                    return None;
+                }
+            }
+
+            let line = line.trim_end().to_string();
+            self.transcript.push(format!("S: {}", line));
+            // `250-` continues a multiline reply, `250 ` ends it
+            let is_last = line.as_bytes().get(3) != Some(&b'-');
+            lines.push(line);
+
+            if is_last {
+                break;
+            }
+        }
+
+        let reply = SmtpReply::parse(lines);
+        if reply.is_none() {
+            self.transcript.push("!: Malformed reply".to_string());
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::services::mock_smtp::{mock_smtp_settings, MockSmtpScenario, MockSmtpServer};
+
+    use super::{SmtpReply, SmtpVerdict, SmtpVerifier, StaticMxResolver};
+
+    fn mock_verifier(mx_hosts: Vec<String>) -> SmtpVerifier {
+        SmtpVerifier::with_resolver(
+            mock_smtp_settings(),
+            StaticMxResolver::default().with_domain("verywellfit.com", mx_hosts),
+        )
+    }
+
+    fn reply(lines: &[&str]) -> SmtpReply {
+        SmtpReply::parse(lines.iter().map(|l| l.to_string()).collect()).unwrap()
+    }
+
+    #[test]
+    fn parse_smtp_reply_valid() {
+        let ehlo = reply(&[
+            "250-mx.google.com at your service",
+            "250-SIZE 157286400",
+            "250 SMTPUTF8",
+        ]);
+        assert_eq!(ehlo.code, 250);
+        assert_eq!(ehlo.enhanced_code, None);
+
+        let rejected = reply(&[
+            "550-5.1.1 The email account that you tried to reach does not exist.",
+            "550 5.1.1 https://support.google.com/mail/?p=NoSuchUser",
+        ]);
+        assert_eq!(rejected.code, 550);
+        assert_eq!(rejected.enhanced_code.as_deref(), Some("5.1.1"));
+
+        assert!(SmtpReply::parse(vec!["hello".to_string()]).is_none());
+        assert!(SmtpReply::parse(vec![]).is_none());
+    }
+
+    #[test]
+    fn rcpt_verdict_valid() {
+        let cases = [
+            (vec!["250 2.1.5 OK"], SmtpVerdict::Deliverable),
+            (vec!["250 Accepted"], SmtpVerdict::Deliverable),
+            (
+                vec!["550 5.1.1 <a@b.com>: Recipient address rejected: User unknown"],
+                SmtpVerdict::Undeliverable,
+            ),
+            (vec!["550 No such user here"], SmtpVerdict::Undeliverable),
+            (
+                vec!["550 5.2.1 Mailbox disabled"],
+                SmtpVerdict::Undeliverable,
+            ),
+            (
+                vec!["450 4.2.0 <a@b.com>: Recipient address rejected: Greylisted"],
+                SmtpVerdict::Greylisted,
+            ),
+            (
+                vec!["451 Temporary local problem, try again later"],
+                SmtpVerdict::Greylisted,
+            ),
+            (
+                vec!["550 5.7.1 Service unavailable, client host blocked using Spamhaus"],
+                SmtpVerdict::Unknown,
+            ),
+            (
+                vec!["554 Your ip is listed at zen.spamhaus.org"],
+                SmtpVerdict::Unknown,
+            ),
+            (vec!["550 Rejected by policy"], SmtpVerdict::Unknown),
+        ];
+
+        for (lines, expected) in cases {
+            assert_eq!(reply(&lines).rcpt_verdict(), expected, "{:?}", lines);
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_against_mock_server() {
+        let server = MockSmtpServer::start(MockSmtpScenario::RejectUnknown(vec![
+            "dan@verywellfit.com".to_string(),
+        ]))
+        .await;
+        let verifier = mock_verifier(vec![server.mx_host()]);
+
+        let verification = verifier.verify("dan@verywellfit.com").await;
+        assert_eq!(verification.verdict, SmtpVerdict::Deliverable);
+        assert!(verification.smtp_reachable());
+        assert_eq!(verification.attempts[0].reply_code, Some(250));
+        assert!(verification.attempts[0]
+            .transcript
+            .contains(&"C: RCPT TO:<dan@verywellfit.com>".to_string()));
+
+        let verification = verifier.verify("go@verywellfit.com").await;
+        assert_eq!(verification.verdict, SmtpVerdict::Undeliverable);
+        assert_eq!(
+            verification.attempts[0].enhanced_code.as_deref(),
+            Some("5.1.1")
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_many_shares_one_session_per_domain() {
+        let server = MockSmtpServer::start(MockSmtpScenario::RejectUnknown(vec![
+            "dan.go@verywellfit.com".to_string(),
+        ]))
+        .await;
+        let verifier = mock_verifier(vec![server.mx_host()]);
+        let emails: Vec<String> = ["dan", "dan.go", "dango", "d.go"]
+            .iter()
+            .map(|local| format!("{}@verywellfit.com", local))
+            .collect();
+
+        let verifications = verifier.verify_many(&emails).await;
+        assert_eq!(server.connections(), 1);
+        assert_eq!(
+            verifications
+                .iter()
+                .map(|v| (v.email.as_str(), v.verdict))
+                .collect::<Vec<_>>(),
+            vec![
+                ("dan@verywellfit.com", SmtpVerdict::Undeliverable),
+                ("dan.go@verywellfit.com", SmtpVerdict::Deliverable),
+                ("dango@verywellfit.com", SmtpVerdict::Undeliverable),
+                ("d.go@verywellfit.com", SmtpVerdict::Undeliverable),
+            ]
+        );
+
+        // Every attempt has the shared greeting and only its own recipient
+        for verification in &verifications {
+            let transcript = &verification.attempts[0].transcript;
+            assert!(transcript[0].starts_with("S: 220"));
+            assert_eq!(
+                transcript
+                    .iter()
+                    .filter(|l| l.starts_with("C: RCPT TO"))
+                    .collect::<Vec<_>>(),
+                vec![&format!("C: RCPT TO:<{}>", verification.email)]
+            );
+        }
+
+        // Sessions are capped at max_recipients_per_session
+        let mut settings = mock_smtp_settings();
+        settings.max_recipients_per_session = 3;
+        let verifier = SmtpVerifier::with_resolver(
+            settings,
+            StaticMxResolver::default().with_domain("verywellfit.com", vec![server.mx_host()]),
+        );
+        verifier.verify_many(&emails).await;
+        assert_eq!(server.connections(), 3);
+    }
+
+    #[tokio::test]
+    async fn verify_greylisted_then_accepted() {
+        let server = MockSmtpServer::start(MockSmtpScenario::Greylist).await;
+        let verifier = mock_verifier(vec![server.mx_host()]);
+
+        let first = verifier.verify("dan@verywellfit.com").await;
+        assert_eq!(first.verdict, SmtpVerdict::Greylisted);
+
+        let second = verifier.verify("dan@verywellfit.com").await;
+        assert_eq!(second.verdict, SmtpVerdict::Deliverable);
+    }
+
+    #[tokio::test]
+    async fn verify_falls_back_to_next_mx_host() {
+        let slow = MockSmtpServer::start(MockSmtpScenario::Slow(Duration::from_secs(3))).await;
+        let disconnect = MockSmtpServer::start(MockSmtpScenario::Disconnect).await;
+        let accept_all = MockSmtpServer::start(MockSmtpScenario::AcceptAll).await;
+        let verifier = mock_verifier(vec![
+            slow.mx_host(),
+            disconnect.mx_host(),
+            accept_all.mx_host(),
+        ]);
+
+        let verification = verifier.verify("dan@verywellfit.com").await;
+        assert_eq!(verification.verdict, SmtpVerdict::Deliverable);
+        assert_eq!(verification.attempts.len(), 3);
+        assert!(!verification.attempts[0].connected);
+        assert!(verification.attempts[0]
+            .transcript
+            .contains(&"!: Reply timed out".to_string()));
+        assert_eq!(verification.attempts[1].verdict, SmtpVerdict::Unknown);
+        assert!(verification.attempts[1]
+            .transcript
+            .contains(&"!: Connection closed".to_string()));
+
+        let verification = mock_verifier(vec![]).verify("dan@verywellfit.com").await;
+        assert_eq!(verification.verdict, SmtpVerdict::Unknown);
+        assert!(verification.attempts.is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    any::Any,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::configuration::SupervisorSettings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum StageStatus {
+    Running,
+    /// Crashed or exited on its own, waiting out the backoff before starting again
+    Restarting,
+    /// Exited after the supervisor was stopped
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageHealth {
+    pub name: &'static str,
+    pub status: StageStatus,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    pub last_restart_at: Option<DateTime<Utc>>,
+}
+
+/// Owns the pipeline's background handlers. A handler that panics or returns while the
+/// app is running is started again with exponential backoff, so a stage never stays dead
+/// while the server keeps taking requests for it.
+#[derive(Clone)]
+pub struct Supervisor {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    stopping: Arc<AtomicBool>,
+    stages: Arc<Mutex<Vec<Arc<Mutex<StageHealth>>>>>,
+}
+
+impl Supervisor {
+    pub fn new(settings: &SupervisorSettings) -> Self {
+        Supervisor {
+            initial_backoff: Duration::from_millis(settings.initial_backoff_ms),
+            max_backoff: Duration::from_millis(settings.max_backoff_ms),
+            stopping: Arc::new(AtomicBool::new(false)),
+            stages: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Spawns the handler `start` creates and keeps it running, `start` is called again
+    /// for every restart. The returned task finishes once the handler exits after `stop`.
+    pub fn spawn<F, Fut>(&self, name: &'static str, mut start: F) -> JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let stage = Arc::new(Mutex::new(StageHealth {
+            name,
+            status: StageStatus::Running,
+            restarts: 0,
+            last_error: None,
+            last_restart_at: None,
+        }));
+        self.stages.lock().unwrap().push(stage.clone());
+
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = supervisor.initial_backoff;
+
+            loop {
+                let started_at = Instant::now();
+                let result = tokio::spawn(start()).await;
+
+                if supervisor.stopping.load(Ordering::SeqCst) {
+                    stage.lock().unwrap().status = StageStatus::Stopped;
+                    log::info!("Stage {} stopped", name);
+                    return;
+                }
+
+                let error = match result {
+                    Ok(()) => "Exited while the app is running".to_string(),
+                    Err(e) if e.is_panic() => panic_message(e.into_panic()),
+                    Err(e) => e.to_string(),
+                };
+
+                // A stage that ran for a while crashed on something new, not in a loop
+                if started_at.elapsed() > supervisor.max_backoff {
+                    backoff = supervisor.initial_backoff;
+                }
+                log::error!(
+                    "Stage {} crashed, restarting in {:?}: {}",
+                    name,
+                    backoff,
+                    error
+                );
+                {
+                    let mut stage = stage.lock().unwrap();
+                    stage.status = StageStatus::Restarting;
+                    stage.last_error = Some(error);
+                }
+
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(supervisor.max_backoff);
+
+                let mut stage = stage.lock().unwrap();
+                stage.status = StageStatus::Running;
+                stage.restarts += 1;
+                stage.last_restart_at = Some(Utc::now());
+            }
+        })
+    }
+
+    /// Handlers that exit from here on are not restarted, called before shutting them down
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn health(&self) -> Vec<StageHealth> {
+        self.stages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|stage| stage.lock().unwrap().clone())
+            .collect()
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "Panicked".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::configuration::SupervisorSettings;
+
+    use super::{StageStatus, Supervisor};
+
+    #[tokio::test]
+    async fn crashed_stage_is_restarted_until_stopped() {
+        let supervisor = Supervisor::new(&SupervisorSettings {
+            initial_backoff_ms: 10,
+            max_backoff_ms: 100,
+        });
+        let starts = Arc::new(AtomicU32::new(0));
+
+        let starts_clone = starts.clone();
+        let task = supervisor.spawn("flaky", move || {
+            let starts = starts_clone.clone();
+            async move {
+                match starts.fetch_add(1, Ordering::SeqCst) {
+                    0 => panic!("Channel closed"),
+                    1 => {}
+                    _ => tokio::time::sleep(Duration::from_millis(500)).await,
+                }
+            }
+        });
+
+        // Printing the panic can take a while, so wait on the starts rather than a fixed time
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while starts.load(Ordering::SeqCst) < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Stage was not restarted");
+        let health = supervisor.health();
+        assert_eq!(health[0].status, StageStatus::Running);
+        assert_eq!(health[0].restarts, 2);
+        assert_eq!(
+            health[0].last_error.as_deref(),
+            Some("Exited while the app is running")
+        );
+
+        supervisor.stop();
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("Stopped stage was restarted")
+            .unwrap();
+        assert_eq!(supervisor.health()[0].status, StageStatus::Stopped);
+    }
+}
@@ -3,52 +3,100 @@ use std::net::TcpListener;
 use actix_files::Files;
 use actix_web::{
     dev::Server,
-    middleware::Logger,
+    middleware::{from_fn, Logger},
     web::{self, Data},
     App, HttpServer,
 };
 use sqlx::PgPool;
 
 use crate::{
+    configuration::AuthSettings,
     routes::{
         dashboard_route, default_route, domain_route, email_route, exp_route, founder_route,
-        lead_route, lightning_route, login_route, product_route, verified_email_route,
+        health_route, lead_route, leads_route, lightning_route, login_route, metrics_route,
+        product_route, runs_route, verified_email_route,
     },
     services::{
-        EmailVerifierSender, OpenaiClient, ProductQuerySender, Sentinel, VerifiedEmailReceiver,
+        require_api_key, require_api_key_or_login, require_login, ApiKeyLimiter,
+        EmailVerifierSender, OpenaiClient, ProductQuerySender, ProxyPool, SearchEngines, Sentinel,
+        Supervisor, VerifiedEmailReceiver,
     },
 };
 
-pub fn run(
-    listener: TcpListener,
-    db_pool: PgPool,
-    openai_client: OpenaiClient,
-    sentinel: Data<Sentinel>,
-    product_query_sender: ProductQuerySender,
-    verified_email_receiver: VerifiedEmailReceiver,
-    email_verifier_sender: EmailVerifierSender,
-) -> Result<Server, std::io::Error> {
+/// Everything the routes share, each field is registered as its own app data
+pub struct AppState {
+    pub db_pool: PgPool,
+    pub openai_client: OpenaiClient,
+    pub sentinel: Data<Sentinel>,
+    pub product_query_sender: ProductQuerySender,
+    pub verified_email_receiver: VerifiedEmailReceiver,
+    pub email_verifier_sender: EmailVerifierSender,
+    pub search_engines: Data<SearchEngines>,
+    pub proxy_pool: Data<ProxyPool>,
+    pub supervisor: Supervisor,
+    pub auth_settings: AuthSettings,
+}
+
+pub fn run(listener: TcpListener, state: AppState) -> Result<Server, std::io::Error> {
+    let AppState {
+        db_pool,
+        openai_client,
+        sentinel,
+        product_query_sender,
+        verified_email_receiver,
+        email_verifier_sender,
+        search_engines,
+        proxy_pool,
+        supervisor,
+        auth_settings,
+    } = state;
+
     let db_pool = web::Data::new(db_pool);
     let openai_client = web::Data::new(openai_client);
     let product_query_sender = web::Data::new(product_query_sender);
     let verified_email_receiver = web::Data::new(verified_email_receiver);
     let email_verifier_sender = web::Data::new(email_verifier_sender);
+    let supervisor = web::Data::new(supervisor);
+    let auth_settings = web::Data::new(auth_settings);
+    let api_key_limiter = web::Data::new(ApiKeyLimiter::default());
 
     let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .service(Files::new("/static", "./templates/static").prefer_utf8(true))
             .service(default_route::default)
-            .service(web::scope("/lead").service(lead_route::get_leads_from_niche))
-            .service(web::scope("/lightning").service(lightning_route::get_lightning_leads))
+            .service(health_route::health)
+            .service(metrics_route::prometheus_metrics)
+            .service(
+                web::scope("/lead")
+                    .wrap(from_fn(require_api_key_or_login))
+                    .service(lead_route::get_leads_from_niche),
+            )
+            .service(
+                web::scope("/lightning")
+                    .wrap(from_fn(require_api_key_or_login))
+                    .service(lightning_route::get_lightning_leads),
+            )
+            .service(
+                web::scope("/api/v1")
+                    .wrap(from_fn(require_api_key))
+                    .service(leads_route::get_leads)
+                    .service(runs_route::create_run)
+                    .service(runs_route::get_run)
+                    .service(runs_route::stream_run_events),
+            )
             .service(
                 web::scope("/exp")
+                    .wrap(from_fn(require_login))
                     .service(exp_route::check_channel_works)
                     .service(exp_route::check_proxy_works)
                     .service(exp_route::verify_emails_custom)
                     .service(exp_route::verify_emails_hardcoded)
                     .service(exp_route::verify_emails_smart_scout)
-                    .service(exp_route::verify_emails),
+                    .service(exp_route::verify_emails)
+                    .service(exp_route::start_reextraction)
+                    .service(exp_route::get_reextraction)
+                    .service(exp_route::get_smtp_attempts),
             )
             // .service(
             //     web::scope("/exp")
@@ -71,13 +119,22 @@ pub fn run(
             .service(
                 web::scope("/app")
                     .service(login_route::login)
-                    .service(domain_route::domain)
-                    .service(founder_route::founder)
-                    .service(email_route::email)
-                    .service(product_route::product)
-                    .service(verified_email_route::verified_email)
-                    .service(dashboard_route::dashboard)
-                    .service(dashboard_route::set_config),
+                    .service(login_route::authenticate_user)
+                    .service(login_route::logout)
+                    .service(
+                        web::scope("")
+                            .wrap(from_fn(require_login))
+                            .service(domain_route::domain)
+                            .service(founder_route::founder)
+                            .service(email_route::email)
+                            .service(product_route::product)
+                            .service(verified_email_route::verified_email)
+                            .service(dashboard_route::dashboard)
+                            .service(dashboard_route::set_config)
+                            .service(dashboard_route::add_user)
+                            .service(dashboard_route::add_api_key)
+                            .service(dashboard_route::revoke_api_key),
+                    ),
             )
             .app_data(db_pool.clone())
             .app_data(openai_client.clone())
@@ -85,6 +142,11 @@ pub fn run(
             .app_data(product_query_sender.clone())
             .app_data(verified_email_receiver.clone())
             .app_data(email_verifier_sender.clone())
+            .app_data(search_engines.clone())
+            .app_data(proxy_pool.clone())
+            .app_data(supervisor.clone())
+            .app_data(auth_settings.clone())
+            .app_data(api_key_limiter.clone())
     })
     .listen(listener)?
     .run();